@@ -0,0 +1,399 @@
+use rand::{seq::SliceRandom, Rng};
+use sha2::{Digest, Sha256};
+use std::sync::OnceLock;
+use totp_rs::{Algorithm, Secret, TOTP};
+
+use crate::{
+    error::{AppError, AppResult},
+    models::{Account, SessionUser},
+    password::hash_password,
+};
+
+/// Session ids of built-in accounts are this followed by the account id.
+pub const ACCOUNT_ID_PREFIX: &str = "local:";
+pub const INVITE_TTL_SECS: i64 = 60 * 60 * 24 * 7;
+pub const RESET_TTL_SECS: i64 = 60 * 60 * 24;
+
+const MIN_USERNAME_LENGTH: usize = 3;
+const MAX_USERNAME_LENGTH: usize = 32;
+const MIN_PASSWORD_LENGTH: usize = 8;
+// Hashing is slow on purpose; don't let a request make it slower
+const MAX_PASSWORD_LENGTH: usize = 1024;
+
+const TOTP_ISSUER: &str = "meoShortURL";
+const TOTP_STEP_SECS: u64 = 30;
+const TOTP_DIGITS: usize = 6;
+const RECOVERY_CODE_COUNT: usize = 10;
+// Lowercase letters and digits without lookalikes (0/o, 1/l/i)
+const RECOVERY_CODE_ALPHABET: &[u8] = b"abcdefghjkmnpqrstuvwxyz23456789";
+
+/// The account id in a session user id, for users signed in with a built-in account.
+pub fn account_id(user_id: &str) -> Option<i64> {
+    user_id.strip_prefix(ACCOUNT_ID_PREFIX)?.parse().ok()
+}
+
+pub fn session_user(account: &Account) -> SessionUser {
+    SessionUser {
+        id: format!("{}{}", ACCOUNT_ID_PREFIX, account.id),
+        username: account.username.clone(),
+        avatar: None,
+        role: account.role,
+    }
+}
+
+/// Whether a session issued at `issued_at` (Unix time in milliseconds) still
+/// signs in to the account: it must be enabled, and the session newer than its
+/// last password reset.
+pub fn accepts_session(account: &Account, issued_at: i64) -> bool {
+    !account.disabled && issued_at > account.sessions_valid_after
+}
+
+/// Trims a username and checks it is 3 to 32 letters, digits, `_`, `.` or `-`.
+pub fn normalize_username(username: &str) -> AppResult<String> {
+    let username = username.trim();
+    let length = username.chars().count();
+    if !(MIN_USERNAME_LENGTH..=MAX_USERNAME_LENGTH).contains(&length) {
+        return Err(AppError::bad_request(
+            "invalid_username",
+            format!(
+                "Username must be {} to {} characters",
+                MIN_USERNAME_LENGTH, MAX_USERNAME_LENGTH
+            ),
+        ));
+    }
+    if !username
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '.' | '-'))
+    {
+        return Err(AppError::bad_request(
+            "invalid_username",
+            "Username may only contain letters, digits, _, . and -",
+        ));
+    }
+    Ok(username.to_string())
+}
+
+pub fn validate_password(password: &str) -> AppResult<()> {
+    let length = password.chars().count();
+    if length < MIN_PASSWORD_LENGTH {
+        return Err(AppError::bad_request(
+            "weak_password",
+            format!(
+                "Password must be at least {} characters",
+                MIN_PASSWORD_LENGTH
+            ),
+        ));
+    }
+    if password.len() > MAX_PASSWORD_LENGTH {
+        return Err(AppError::bad_request(
+            "invalid_password",
+            "Password is too long",
+        ));
+    }
+    Ok(())
+}
+
+/// A hash to check passwords against when the username doesn't exist, so a
+/// failed login takes as long either way. Slow the first time; call from
+/// `spawn_blocking`.
+pub fn dummy_password_hash() -> String {
+    static HASH: OnceLock<String> = OnceLock::new();
+    HASH.get_or_init(|| {
+        let password: [u8; 16] = rand::thread_rng().gen();
+        hash_password(&hex(&password)).unwrap_or_default()
+    })
+    .clone()
+}
+
+/// A random single-use invite or reset token.
+pub fn new_token() -> String {
+    let bytes: [u8; 32] = rand::thread_rng().gen();
+    hex(&bytes)
+}
+
+/// Tokens and recovery codes are random enough that a fast hash is safe, and
+/// lets them be looked up by hash.
+pub fn hash_token(token: &str) -> String {
+    hex(&Sha256::digest(token.trim().as_bytes()))
+}
+
+/// Fresh recovery codes, formatted `xxxxx-xxxxx`.
+pub fn new_recovery_codes() -> Vec<String> {
+    let mut rng = rand::thread_rng();
+    (0..RECOVERY_CODE_COUNT)
+        .map(|_| {
+            let chars: String = (0..10)
+                .map(|_| *RECOVERY_CODE_ALPHABET.choose(&mut rng).unwrap() as char)
+                .collect();
+            format!("{}-{}", &chars[..5], &chars[5..])
+        })
+        .collect()
+}
+
+/// Hashes a recovery code as typed, ignoring case, spaces and dashes.
+pub fn hash_recovery_code(code: &str) -> String {
+    let code: String = code
+        .chars()
+        .filter(|c| !c.is_whitespace() && *c != '-')
+        .collect();
+    hash_token(&code.to_ascii_lowercase())
+}
+
+/// A new base32 TOTP secret.
+pub fn new_totp_secret() -> String {
+    Secret::generate_secret().to_encoded().to_string()
+}
+
+fn totp(secret: &str, username: &str) -> Option<TOTP> {
+    let secret = Secret::Encoded(secret.to_string()).to_bytes().ok()?;
+    TOTP::new(
+        Algorithm::SHA1,
+        TOTP_DIGITS,
+        1,
+        TOTP_STEP_SECS,
+        secret,
+        Some(TOTP_ISSUER.to_string()),
+        username.to_string(),
+    )
+    .ok()
+}
+
+/// The `otpauth://` URL authenticator apps scan as a QR code.
+pub fn totp_url(secret: &str, username: &str) -> Option<String> {
+    totp(secret, username).map(|totp| totp.get_url())
+}
+
+/// Checks a TOTP code against the current time step and one step either side,
+/// for clock drift. Returns the matching step, so callers can refuse codes
+/// that were already used.
+pub fn verify_totp(secret: &str, code: &str) -> Option<i64> {
+    verify_totp_at(secret, code, chrono::Utc::now().timestamp() as u64)
+}
+
+fn verify_totp_at(secret: &str, code: &str, unix_secs: u64) -> Option<i64> {
+    let code = code.trim().replace(' ', "");
+    if code.len() != TOTP_DIGITS || !code.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    let totp = totp(secret, "check")?;
+    let now = unix_secs / TOTP_STEP_SECS;
+    (now - 1..=now + 1)
+        .find(|step| {
+            let expected = totp.generate(step * TOTP_STEP_SECS);
+            constant_time_eq(expected.as_bytes(), code.as_bytes())
+        })
+        .map(|step| step as i64)
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        auth::Role,
+        db::testing::TempDb,
+        session::{encode_session, session_issued_at},
+        signing::Signer,
+    };
+
+    #[test]
+    fn usernames() {
+        assert_eq!(normalize_username("  alice.b-c_1 ").unwrap(), "alice.b-c_1");
+        for invalid in ["ab", "a b c", "alice!", "ålice", &"a".repeat(33)] {
+            assert!(normalize_username(invalid).is_err(), "{:?}", invalid);
+        }
+        assert!(normalize_username(&"a".repeat(32)).is_ok());
+
+        assert!(validate_password("short").is_err());
+        assert!(validate_password("long enough").is_ok());
+        assert!(validate_password(&"x".repeat(MAX_PASSWORD_LENGTH + 1)).is_err());
+    }
+
+    #[test]
+    fn totp_accepts_one_step_either_side() {
+        let secret = new_totp_secret();
+        let totp = totp(&secret, "check").unwrap();
+        let now = 1_800_000_000 / TOTP_STEP_SECS * TOTP_STEP_SECS + 10;
+        let step = (now / TOTP_STEP_SECS) as i64;
+
+        let code_at = |offset: i64| totp.generate((now as i64 + offset) as u64);
+        assert_eq!(verify_totp_at(&secret, &code_at(0), now), Some(step));
+        assert_eq!(verify_totp_at(&secret, &code_at(-30), now), Some(step - 1));
+        assert_eq!(verify_totp_at(&secret, &code_at(30), now), Some(step + 1));
+        assert_eq!(verify_totp_at(&secret, &code_at(-60), now), None);
+        assert_eq!(verify_totp_at(&secret, &code_at(90), now), None);
+
+        // Spaces are ignored; anything but six digits is refused
+        let code = code_at(0);
+        let spaced = format!("{} {}", &code[..3], &code[3..]);
+        assert_eq!(verify_totp_at(&secret, &spaced, now), Some(step));
+        assert_eq!(verify_totp_at(&secret, "12345a", now), None);
+    }
+
+    #[test]
+    fn recovery_codes_are_normalised() {
+        let codes = new_recovery_codes();
+        assert_eq!(codes.len(), RECOVERY_CODE_COUNT);
+        assert!(codes
+            .iter()
+            .all(|code| code.len() == 11 && &code[5..6] == "-"));
+
+        let hash = hash_recovery_code("abcde-fghjk");
+        assert_eq!(hash_recovery_code(" ABCDE FGHJK "), hash);
+        assert_eq!(hash_recovery_code("abcdefghjk"), hash);
+        assert_ne!(hash_recovery_code("abcde-fghjm"), hash);
+    }
+
+    async fn account(db: &TempDb) -> Account {
+        let id = db
+            .insert_account("alice", "hash", Role::User, "test")
+            .await
+            .unwrap();
+        db.get_account(id).await.unwrap().unwrap()
+    }
+
+    #[tokio::test]
+    async fn second_factor_codes_are_single_use() {
+        let db = TempDb::new().await;
+        let account = account(&db).await;
+        let codes = ["aaaaa-bbbbb".to_string(), "ccccc-ddddd".to_string()];
+        let hashes: Vec<String> = codes.iter().map(|c| hash_recovery_code(c)).collect();
+        db.set_pending_totp(account.id, &new_totp_secret())
+            .await
+            .unwrap();
+        db.enable_totp(account.id, 100, &hashes).await.unwrap();
+
+        // Only steps after the last accepted one
+        assert!(!db.accept_totp_step(account.id, 100).await.unwrap());
+        assert!(!db.accept_totp_step(account.id, 99).await.unwrap());
+        assert!(db.accept_totp_step(account.id, 101).await.unwrap());
+        assert!(!db.accept_totp_step(account.id, 101).await.unwrap());
+
+        let code = hash_recovery_code("AAAAA BBBBB");
+        assert!(db.use_recovery_code(account.id, &code).await.unwrap());
+        assert!(!db.use_recovery_code(account.id, &code).await.unwrap());
+        assert!(!db
+            .use_recovery_code(account.id, &hash_recovery_code("zzzzz-zzzzz"))
+            .await
+            .unwrap());
+
+        // Turning TOTP off drops the remaining codes
+        db.disable_totp(account.id).await.unwrap();
+        assert!(!db.use_recovery_code(account.id, &hashes[1]).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn invites_are_single_use_and_expire() {
+        let db = TempDb::new().await;
+        let (invite, expired) = (new_token(), new_token());
+        db.insert_account_token(
+            &hash_token(&invite),
+            "invite",
+            None,
+            Some(Role::Admin),
+            "test",
+            60,
+        )
+        .await
+        .unwrap();
+        db.insert_account_token(
+            &hash_token(&expired),
+            "invite",
+            None,
+            Some(Role::User),
+            "test",
+            0,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(
+            db.redeem_invite(&hash_token(&expired), "bob", "hash")
+                .await
+                .unwrap(),
+            None
+        );
+        let (id, role) = db
+            .redeem_invite(&hash_token(&invite), "bob", "hash")
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(role, Role::Admin);
+        assert_eq!(db.get_account(id).await.unwrap().unwrap().username, "bob");
+        assert_eq!(
+            db.redeem_invite(&hash_token(&invite), "carol", "hash")
+                .await
+                .unwrap(),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn password_resets_end_earlier_sessions() {
+        let db = TempDb::new().await;
+        let account = account(&db).await;
+        let signer = Signer::from_env();
+        let before = encode_session(&session_user(&account), &signer);
+        let before = session_issued_at(&before).unwrap();
+        assert!(accepts_session(&account, before));
+
+        let (reset, expired) = (new_token(), new_token());
+        for (token, ttl) in [(&reset, RESET_TTL_SECS), (&expired, 0)] {
+            db.insert_account_token(
+                &hash_token(token),
+                "reset",
+                Some(account.id),
+                None,
+                "test",
+                ttl,
+            )
+            .await
+            .unwrap();
+        }
+        assert_eq!(
+            db.redeem_password_reset(&hash_token(&expired), "new")
+                .await
+                .unwrap(),
+            None
+        );
+        assert_eq!(
+            db.redeem_password_reset(&hash_token(&reset), "new")
+                .await
+                .unwrap(),
+            Some(account.id)
+        );
+        assert_eq!(
+            db.redeem_password_reset(&hash_token(&reset), "newer")
+                .await
+                .unwrap(),
+            None
+        );
+
+        let account = db.get_account(account.id).await.unwrap().unwrap();
+        assert_eq!(account.password_hash, "new");
+        assert!(!accepts_session(&account, before));
+        // A session started after the reset is fine
+        tokio::time::sleep(std::time::Duration::from_millis(5)).await;
+        let after = encode_session(&session_user(&account), &signer);
+        assert!(accepts_session(
+            &account,
+            session_issued_at(&after).unwrap()
+        ));
+
+        db.update_account(account.id, None, Some(true))
+            .await
+            .unwrap();
+        let account = db.get_account(account.id).await.unwrap().unwrap();
+        assert!(!accepts_session(
+            &account,
+            session_issued_at(&after).unwrap()
+        ));
+    }
+}
@@ -0,0 +1,140 @@
+use axum::{async_trait, extract::FromRequestParts, http::request::Parts};
+use serde::{Deserialize, Serialize};
+use std::{convert::Infallible, marker::PhantomData, sync::Arc};
+
+use crate::{
+    accounts,
+    error::{AppError, AppResult},
+    models::SessionUser,
+    session::{decode_session, session_issued_at, session_token},
+    AppState,
+};
+
+/// What a signed-in user may do. Roles are ordered, so an admin can do
+/// everything a user can.
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize, sqlx::Type,
+)]
+#[serde(rename_all = "lowercase")]
+#[sqlx(type_name = "TEXT", rename_all = "lowercase")]
+pub enum Role {
+    /// Manages their own links, and uses and creates tags and folders
+    User,
+    /// Also manages every link, renames and deletes tags and folders, and
+    /// manages instance settings: domain policy, threat feeds, jobs and imports
+    Admin,
+}
+
+impl Role {
+    /// The role for a user signing in with a login provider. `ADMIN_USERS`
+    /// lists the ids of admins, comma-separated; everyone else is a user.
+    pub fn for_user(id: &str) -> Self {
+        let admins = std::env::var("ADMIN_USERS").unwrap_or_default();
+        if admins.split(',').any(|admin| admin.trim() == id) {
+            Self::Admin
+        } else {
+            Self::User
+        }
+    }
+}
+
+/// The user a valid session token belongs to. Built-in accounts are looked up,
+/// so disabling one or resetting its password ends its sessions, and a role
+/// change applies straight away.
+async fn signed_in_user(parts: &Parts, state: &AppState) -> AppResult<Option<SessionUser>> {
+    let Some(token) = session_token(&parts.headers) else {
+        return Ok(None);
+    };
+    let Some(user) = decode_session(&token, &state.signer) else {
+        return Ok(None);
+    };
+    let Some(id) = accounts::account_id(&user.id) else {
+        return Ok(Some(user));
+    };
+
+    let issued_at = session_issued_at(&token).unwrap_or(0);
+    Ok(state
+        .db
+        .get_account(id)
+        .await?
+        .filter(|account| accounts::accepts_session(account, issued_at))
+        .map(|account| accounts::session_user(&account)))
+}
+
+/// The signed-in user, from the session cookie or a bearer token. Rejects the
+/// request with 401 otherwise.
+pub struct CurrentUser(pub SessionUser);
+
+#[async_trait]
+impl FromRequestParts<Arc<AppState>> for CurrentUser {
+    type Rejection = AppError;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &Arc<AppState>,
+    ) -> Result<Self, Self::Rejection> {
+        signed_in_user(parts, state)
+            .await?
+            .map(Self)
+            .ok_or_else(AppError::unauthorized)
+    }
+}
+
+/// The signed-in user if there is one, for routes that also serve visitors.
+pub struct OptionalUser(pub Option<SessionUser>);
+
+#[async_trait]
+impl FromRequestParts<Arc<AppState>> for OptionalUser {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &Arc<AppState>,
+    ) -> Result<Self, Self::Rejection> {
+        match signed_in_user(parts, state).await {
+            Ok(user) => Ok(Self(user)),
+            Err(e) => {
+                tracing::error!("Failed to check session: {:?}", e);
+                Ok(Self(None))
+            }
+        }
+    }
+}
+
+/// A role as a type, for `RequireRole`.
+pub trait RoleMarker {
+    const ROLE: Role;
+}
+
+pub struct Admin;
+
+impl RoleMarker for Admin {
+    const ROLE: Role = Role::Admin;
+}
+
+/// The signed-in user, who must have at least role `R`: 401 without a
+/// session, 403 with a lesser role.
+pub struct RequireRole<R: RoleMarker>(pub SessionUser, pub PhantomData<R>);
+
+#[async_trait]
+impl<R: RoleMarker> FromRequestParts<Arc<AppState>> for RequireRole<R> {
+    type Rejection = AppError;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &Arc<AppState>,
+    ) -> Result<Self, Self::Rejection> {
+        let CurrentUser(user) = CurrentUser::from_request_parts(parts, state).await?;
+        require_role(&user, R::ROLE)?;
+        Ok(Self(user, PhantomData))
+    }
+}
+
+/// For handlers where only some inputs need a higher role: 403 unless `user`
+/// has at least `role`.
+pub fn require_role(user: &SessionUser, role: Role) -> AppResult<()> {
+    if user.role < role {
+        return Err(AppError::forbidden("You don't have permission to do this"));
+    }
+    Ok(())
+}
@@ -0,0 +1,262 @@
+use std::collections::{HashMap, HashSet};
+use tokio::io::AsyncWriteExt;
+
+use crate::{
+    accounts::{hash_token, new_token, normalize_username, validate_password, RESET_TTL_SECS},
+    auth::Role,
+    db::Database,
+    domain_policy::DomainPolicy,
+    export::{spawn_export, ExportFormat, ExportKind},
+    import::{import, ImportSource},
+    models::UrlFilter,
+    password::hash_password_async,
+    threat_feed::ThreatFeeds,
+};
+
+const USAGE: &str = "\
+Usage: meoshorturl [COMMAND]
+
+Without a command the HTTP server is started.
+
+Commands:
+  export <links|clicks> [--format csv|json|ndjson] [--output PATH]
+                        [--q TEXT] [--created-after DATE] [--created-before DATE]
+                        [--tag TAG[,TAG...]] [--folder NAME] [--broken]
+  import <yourls|shlink|kutt|bitly> <PATH> [--dry-run]
+  create-account <USERNAME> [--role user|admin]
+                        Creates a built-in account; the password is read from stdin
+  reset-account <USERNAME> [--reset-totp]
+                        Prints a password reset token for a built-in account";
+
+// Parsed command line: positional arguments, `--flag value` pairs and bare `--switch`es
+struct Args {
+    positional: Vec<String>,
+    flags: HashMap<String, String>,
+    switches: HashSet<String>,
+}
+
+impl Args {
+    fn parse(args: &[String], switches: &[&str]) -> Result<Self, String> {
+        let mut parsed = Args {
+            positional: Vec::new(),
+            flags: HashMap::new(),
+            switches: HashSet::new(),
+        };
+
+        let mut iter = args.iter();
+        while let Some(arg) = iter.next() {
+            let Some(name) = arg.strip_prefix("--") else {
+                parsed.positional.push(arg.clone());
+                continue;
+            };
+
+            if let Some((name, value)) = name.split_once('=') {
+                parsed.flags.insert(name.to_string(), value.to_string());
+            } else if switches.contains(&name) {
+                parsed.switches.insert(name.to_string());
+            } else {
+                let value = iter
+                    .next()
+                    .ok_or_else(|| format!("Missing value for --{}", name))?;
+                parsed.flags.insert(name.to_string(), value.clone());
+            }
+        }
+
+        Ok(parsed)
+    }
+
+    fn flag(&self, name: &str) -> Option<String> {
+        self.flags.get(name).cloned()
+    }
+
+    fn switch(&self, name: &str) -> bool {
+        self.switches.contains(name)
+    }
+
+    fn url_filter(&self) -> UrlFilter {
+        UrlFilter {
+            q: self.flag("q"),
+            created_after: self.flag("created-after"),
+            created_before: self.flag("created-before"),
+            tag: self.flag("tag"),
+            folder: self.flag("folder"),
+            broken: self.switch("broken"),
+            owner_id: None,
+        }
+    }
+}
+
+/// Runs a one-off command instead of the server. Returns an error message to print on failure.
+pub async fn run(db: Database, args: &[String]) -> Result<(), String> {
+    match args.first().map(String::as_str) {
+        Some("export") => export(db, &args[1..]).await,
+        Some("import") => import_file(db, &args[1..]).await,
+        Some("create-account") => create_account(db, &args[1..]).await,
+        Some("reset-account") => reset_account(db, &args[1..]).await,
+        Some("help" | "--help" | "-h") => {
+            println!("{}", USAGE);
+            Ok(())
+        }
+        _ => Err(USAGE.to_string()),
+    }
+}
+
+async fn export(db: Database, args: &[String]) -> Result<(), String> {
+    let args = Args::parse(args, &["broken"])?;
+
+    let kind = args
+        .positional
+        .first()
+        .and_then(|kind| ExportKind::parse(kind))
+        .ok_or_else(|| USAGE.to_string())?;
+    let format = match args.flag("format") {
+        None => ExportFormat::Csv,
+        Some(value) => {
+            ExportFormat::parse(&value).ok_or_else(|| format!("Unknown format: {}", value))?
+        }
+    };
+
+    let mut out: Box<dyn tokio::io::AsyncWrite + Unpin> = match args.flag("output") {
+        Some(path) => Box::new(
+            tokio::fs::File::create(&path)
+                .await
+                .map_err(|e| format!("Failed to create {}: {}", path, e))?,
+        ),
+        None => Box::new(tokio::io::stdout()),
+    };
+
+    let mut rx = spawn_export(db, kind, format, args.url_filter());
+    while let Some(chunk) = rx.recv().await {
+        let chunk = chunk.map_err(|e| format!("Export failed: {}", e))?;
+        out.write_all(&chunk)
+            .await
+            .map_err(|e| format!("Write failed: {}", e))?;
+    }
+    out.flush()
+        .await
+        .map_err(|e| format!("Write failed: {}", e))
+}
+
+async fn import_file(db: Database, args: &[String]) -> Result<(), String> {
+    let args = Args::parse(args, &["dry-run"])?;
+
+    let (Some(source), Some(path)) = (args.positional.first(), args.positional.get(1)) else {
+        return Err(USAGE.to_string());
+    };
+    let source =
+        ImportSource::parse(source).ok_or_else(|| format!("Unknown import format: {}", source))?;
+    let input = tokio::fs::read_to_string(path)
+        .await
+        .map_err(|e| format!("Failed to read {}: {}", path, e))?;
+
+    let report = import(
+        &db,
+        &DomainPolicy::from_env(),
+        &ThreatFeeds::from_env(),
+        source,
+        &input,
+        args.switch("dry-run"),
+    )
+    .await
+    .map_err(|e| e.to_string())?;
+
+    for error in &report.errors {
+        eprintln!("row {}: {}", error.row, error.error);
+    }
+    for rejected in &report.rejected {
+        eprintln!("row {}: rejected: {}", rejected.row, rejected.error);
+    }
+    for slug in &report.conflicts {
+        eprintln!("conflict: slug '{}' already exists", slug);
+    }
+    println!(
+        "{} {} link(s), {} conflict(s), {} rejected, {} error(s)",
+        if report.dry_run {
+            "Would import"
+        } else {
+            "Imported"
+        },
+        report.imported,
+        report.conflicts.len(),
+        report.rejected.len(),
+        report.errors.len()
+    );
+    Ok(())
+}
+
+// Recorded as the creator of accounts and tokens made from the command line
+const CLI_ACTOR: &str = "cli";
+
+/// Bootstraps the first admin on instances that can't use an external login.
+async fn create_account(db: Database, args: &[String]) -> Result<(), String> {
+    let args = Args::parse(args, &[])?;
+
+    let username = args.positional.first().ok_or_else(|| USAGE.to_string())?;
+    let username = normalize_username(username).map_err(|e| e.to_string())?;
+    let role = match args.flag("role").as_deref() {
+        None | Some("user") => Role::User,
+        Some("admin") => Role::Admin,
+        Some(other) => return Err(format!("Unknown role: {}", other)),
+    };
+
+    let mut password = String::new();
+    std::io::stdin()
+        .read_line(&mut password)
+        .map_err(|e| format!("Failed to read password: {}", e))?;
+    let password = password.trim_end_matches(['\r', '\n']).to_string();
+    validate_password(&password).map_err(|e| e.to_string())?;
+    let password_hash = hash_password_async(password)
+        .await
+        .ok_or("Failed to hash password")?;
+
+    let id = db
+        .insert_account(&username, &password_hash, role, CLI_ACTOR)
+        .await
+        .map_err(|e| match &e {
+            sqlx::Error::Database(db_err) if db_err.is_unique_violation() => {
+                format!("Username {} is taken", username)
+            }
+            _ => format!("Failed to create account: {}", e),
+        })?;
+    println!("Created account {} ({:?}) with id {}", username, role, id);
+    Ok(())
+}
+
+/// Issues a password reset token, e.g. when the only admin is locked out.
+async fn reset_account(db: Database, args: &[String]) -> Result<(), String> {
+    let args = Args::parse(args, &["reset-totp"])?;
+
+    let username = args.positional.first().ok_or_else(|| USAGE.to_string())?;
+    let account = db
+        .get_account_by_username(username.trim())
+        .await
+        .map_err(|e| e.to_string())?
+        .ok_or_else(|| format!("No account named {}", username))?;
+
+    if args.switch("reset-totp") {
+        db.disable_totp(account.id)
+            .await
+            .map_err(|e| e.to_string())?;
+    }
+    db.revoke_password_resets(account.id)
+        .await
+        .map_err(|e| e.to_string())?;
+    let token = new_token();
+    let expires_at = db
+        .insert_account_token(
+            &hash_token(&token),
+            "reset",
+            Some(account.id),
+            None,
+            CLI_ACTOR,
+            RESET_TTL_SECS,
+        )
+        .await
+        .map_err(|e| e.to_string())?;
+    println!(
+        "Reset token for {} (valid until {} UTC):",
+        account.username, expires_at
+    );
+    println!("{}", token);
+    Ok(())
+}
@@ -0,0 +1,18 @@
+use axum::http::HeaderMap;
+use std::net::{IpAddr, SocketAddr};
+
+/// The visitor's address. `X-Forwarded-For` is only honoured when the server is
+/// configured to sit behind a trusted reverse proxy, since clients can set it freely.
+pub fn client_ip(headers: &HeaderMap, peer: SocketAddr, trust_proxy: bool) -> IpAddr {
+    if trust_proxy {
+        let forwarded = headers
+            .get("x-forwarded-for")
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.split(',').next())
+            .and_then(|v| v.trim().parse().ok());
+        if let Some(ip) = forwarded {
+            return ip;
+        }
+    }
+    peer.ip()
+}
@@ -1,4 +1,17 @@
-use sqlx::{sqlite::SqlitePoolOptions, Pool, Sqlite};
+use sqlx::{
+    sqlite::{SqlitePoolOptions, SqliteRow},
+    FromRow, Pool, QueryBuilder, Sqlite, Transaction,
+};
+use std::future::Future;
+use tokio_stream::StreamExt;
+
+use crate::{
+    expiry::format_utc,
+    health::HealthCheck,
+    import::{parse_timestamp, ImportRecord},
+    auth::Role,
+    models::{Account, ClickEvent, CountryStats, DeviceRule, FolderStats, GeoRule, JobRun, RotationDestination, RotationEntry, NewUrl, TagStats, UpdateUrlRequest, UrlFilter, UrlRecord},
+};
 
 #[derive(Clone)]
 pub struct Database {
@@ -39,16 +52,287 @@ impl Database {
             .execute(&pool)
             .await?;
 
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS click_events (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                url_id INTEGER NOT NULL,
+                clicked_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+                referrer TEXT,
+                user_agent TEXT
+            )
+            "#,
+        )
+        .execute(&pool)
+        .await?;
+
+        sqlx::query("CREATE INDEX IF NOT EXISTS idx_click_events_url_id ON click_events(url_id)")
+            .execute(&pool)
+            .await?;
+
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS tags (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                name TEXT UNIQUE NOT NULL
+            )
+            "#,
+        )
+        .execute(&pool)
+        .await?;
+
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS url_tags (
+                url_id INTEGER NOT NULL,
+                tag_id INTEGER NOT NULL,
+                PRIMARY KEY (url_id, tag_id)
+            )
+            "#,
+        )
+        .execute(&pool)
+        .await?;
+
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS folders (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                name TEXT UNIQUE NOT NULL,
+                created_at DATETIME DEFAULT CURRENT_TIMESTAMP
+            )
+            "#,
+        )
+        .execute(&pool)
+        .await?;
+
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS device_rules (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                url_id INTEGER NOT NULL,
+                position INTEGER NOT NULL,
+                platform TEXT,
+                browser TEXT,
+                url TEXT NOT NULL
+            )
+            "#,
+        )
+        .execute(&pool)
+        .await?;
+
+        sqlx::query("CREATE INDEX IF NOT EXISTS idx_device_rules_url_id ON device_rules(url_id, position)")
+            .execute(&pool)
+            .await?;
+
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS geo_rules (
+                url_id INTEGER NOT NULL,
+                country TEXT NOT NULL,
+                url TEXT NOT NULL,
+                PRIMARY KEY (url_id, country)
+            )
+            "#,
+        )
+        .execute(&pool)
+        .await?;
+
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS rotation_destinations (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                url_id INTEGER NOT NULL,
+                position INTEGER NOT NULL,
+                url TEXT NOT NULL,
+                weight INTEGER NOT NULL,
+                clicks INTEGER NOT NULL DEFAULT 0
+            )
+            "#,
+        )
+        .execute(&pool)
+        .await?;
+
+        sqlx::query(
+            "CREATE INDEX IF NOT EXISTS idx_rotation_destinations_url_id ON rotation_destinations(url_id, position)",
+        )
+        .execute(&pool)
+        .await?;
+
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS click_rollups (
+                url_id INTEGER NOT NULL,
+                day DATE NOT NULL,
+                country TEXT NOT NULL DEFAULT '',
+                clicks INTEGER NOT NULL,
+                PRIMARY KEY (url_id, day, country)
+            )
+            "#,
+        )
+        .execute(&pool)
+        .await?;
+
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS archived_urls (
+                id INTEGER PRIMARY KEY,
+                slug TEXT NOT NULL,
+                original_url TEXT NOT NULL,
+                expires_at DATETIME,
+                clicks INTEGER NOT NULL,
+                data TEXT NOT NULL,
+                archived_at DATETIME DEFAULT CURRENT_TIMESTAMP
+            )
+            "#,
+        )
+        .execute(&pool)
+        .await?;
+
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS job_runs (
+                name TEXT PRIMARY KEY,
+                schedule TEXT NOT NULL,
+                next_run_at DATETIME NOT NULL,
+                lease_holder TEXT,
+                lease_until DATETIME,
+                last_started_at DATETIME,
+                last_finished_at DATETIME,
+                last_status TEXT,
+                last_message TEXT
+            )
+            "#,
+        )
+        .execute(&pool)
+        .await?;
+
+        // Built-in accounts, for instances without an external login provider
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS accounts (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                username TEXT NOT NULL UNIQUE COLLATE NOCASE,
+                password_hash TEXT NOT NULL,
+                role TEXT NOT NULL DEFAULT 'user',
+                totp_secret TEXT,
+                totp_enabled BOOLEAN NOT NULL DEFAULT 0,
+                totp_last_step INTEGER,
+                disabled BOOLEAN NOT NULL DEFAULT 0,
+                sessions_valid_after INTEGER NOT NULL DEFAULT 0,
+                created_by TEXT,
+                created_at DATETIME DEFAULT CURRENT_TIMESTAMP
+            )
+            "#,
+        )
+        .execute(&pool)
+        .await?;
+
+        // Single-use invite and password reset tokens, stored as SHA-256 hashes
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS account_tokens (
+                token_hash TEXT PRIMARY KEY,
+                kind TEXT NOT NULL,
+                account_id INTEGER,
+                role TEXT,
+                created_by TEXT NOT NULL,
+                expires_at DATETIME NOT NULL,
+                used_at DATETIME
+            )
+            "#,
+        )
+        .execute(&pool)
+        .await?;
+
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS recovery_codes (
+                account_id INTEGER NOT NULL,
+                code_hash TEXT NOT NULL,
+                used_at DATETIME,
+                PRIMARY KEY (account_id, code_hash)
+            )
+            "#,
+        )
+        .execute(&pool)
+        .await?;
+
+        // Columns added after the first release
+        add_column_if_missing(&pool, "urls", "folder_id", "INTEGER").await?;
+        add_column_if_missing(&pool, "urls", "title", "TEXT").await?;
+        add_column_if_missing(&pool, "urls", "description", "TEXT").await?;
+        add_column_if_missing(&pool, "urls", "notes", "TEXT").await?;
+        add_column_if_missing(&pool, "urls", "password_hash", "TEXT").await?;
+        add_column_if_missing(&pool, "urls", "max_clicks", "INTEGER").await?;
+        add_column_if_missing(&pool, "urls", "activates_at", "DATETIME").await?;
+        add_column_if_missing(&pool, "urls", "pending_url", "TEXT").await?;
+        add_column_if_missing(&pool, "urls", "disabled", "BOOLEAN NOT NULL DEFAULT 0").await?;
+        add_column_if_missing(&pool, "urls", "disabled_reason", "TEXT").await?;
+        add_column_if_missing(&pool, "urls", "fallback_url", "TEXT").await?;
+        add_column_if_missing(&pool, "urls", "fallback_page", "TEXT").await?;
+        add_column_if_missing(&pool, "urls", "redirect_type", "INTEGER").await?;
+        add_column_if_missing(&pool, "urls", "query_passthrough", "TEXT").await?;
+        add_column_if_missing(&pool, "urls", "path_passthrough", "BOOLEAN NOT NULL DEFAULT 0").await?;
+        add_column_if_missing(&pool, "urls", "utm_source", "TEXT").await?;
+        add_column_if_missing(&pool, "urls", "utm_medium", "TEXT").await?;
+        add_column_if_missing(&pool, "urls", "utm_campaign", "TEXT").await?;
+        add_column_if_missing(&pool, "urls", "utm_term", "TEXT").await?;
+        add_column_if_missing(&pool, "urls", "utm_content", "TEXT").await?;
+        add_column_if_missing(&pool, "urls", "parent_id", "INTEGER").await?;
+        add_column_if_missing(&pool, "click_events", "country", "TEXT").await?;
+        add_column_if_missing(&pool, "urls", "sticky_rotation", "BOOLEAN NOT NULL DEFAULT 0").await?;
+        add_column_if_missing(&pool, "urls", "created_by", "TEXT").await?;
+        add_column_if_missing(&pool, "urls", "untrusted", "BOOLEAN NOT NULL DEFAULT 0").await?;
+        add_column_if_missing(&pool, "urls", "untrusted_reason", "TEXT").await?;
+        add_column_if_missing(&pool, "urls", "health_status", "INTEGER").await?;
+        add_column_if_missing(&pool, "urls", "health_error", "TEXT").await?;
+        add_column_if_missing(&pool, "urls", "health_latency_ms", "INTEGER").await?;
+        add_column_if_missing(&pool, "urls", "health_chain", "TEXT").await?;
+        add_column_if_missing(&pool, "urls", "health_checked_at", "DATETIME").await?;
+        add_column_if_missing(&pool, "urls", "health_failures", "INTEGER NOT NULL DEFAULT 0").await?;
+        // Links from before this column, and imported ones, count as unknown rather than anonymous
+        add_column_if_missing(&pool, "urls", "anonymous", "BOOLEAN NOT NULL DEFAULT 0").await?;
+        // Session user id of the owner. Links from before this column are left
+        // to admins, since usernames don't say which login they came from.
+        add_column_if_missing(&pool, "urls", "owner_id", "TEXT").await?;
+
+        normalise_expiry(&pool).await?;
+
+        // Connections that were open while columns were being added can keep a
+        // stale view of the schema, so serve requests from fresh ones
+        pool.close().await;
+        let pool = SqlitePoolOptions::new()
+            .max_connections(5)
+            .connect(&connection_string)
+            .await?;
+
         Ok(Self { pool })
     }
 
-    pub async fn get_by_slug(&self, slug: &str) -> Result<Option<crate::models::UrlRecord>, sqlx::Error> {
-        sqlx::query_as::<_, crate::models::UrlRecord>("SELECT * FROM urls WHERE slug = ?")
+    pub async fn get_by_slug(&self, slug: &str) -> Result<Option<UrlRecord>, sqlx::Error> {
+        sqlx::query_as::<_, UrlRecord>(&format!("SELECT {} FROM urls u WHERE u.slug = ?", URL_COLUMNS))
             .bind(slug)
             .fetch_optional(&self.pool)
             .await
     }
 
+    pub async fn get_by_id(&self, id: i64) -> Result<Option<UrlRecord>, sqlx::Error> {
+        sqlx::query_as::<_, UrlRecord>(&format!("SELECT {} FROM urls u WHERE u.id = ?", URL_COLUMNS))
+            .bind(id)
+            .fetch_optional(&self.pool)
+            .await
+    }
+
+    pub async fn get_variants(&self, parent_id: i64) -> Result<Vec<UrlRecord>, sqlx::Error> {
+        sqlx::query_as::<_, UrlRecord>(&format!(
+            "SELECT {} FROM urls u WHERE u.parent_id = ? ORDER BY u.created_at, u.id",
+            URL_COLUMNS
+        ))
+        .bind(parent_id)
+        .fetch_all(&self.pool)
+        .await
+    }
+
     pub async fn check_slug_exists(&self, slug: &str) -> Result<bool, sqlx::Error> {
         let result: Option<(i64,)> = sqlx::query_as("SELECT id FROM urls WHERE slug = ?")
             .bind(slug)
@@ -57,51 +341,1189 @@ impl Database {
         Ok(result.is_some())
     }
 
-    pub async fn insert_url(
+    pub async fn insert_url(&self, url: &NewUrl) -> Result<i64, sqlx::Error> {
+        let result = sqlx::query(
+            "INSERT INTO urls \
+             (slug, original_url, expires_at, title, description, notes, password_hash, max_clicks, \
+              activates_at, pending_url, fallback_url, fallback_page, redirect_type, query_passthrough, \
+              path_passthrough, utm_source, utm_medium, utm_campaign, utm_term, utm_content, parent_id, \
+              created_by, owner_id, anonymous, untrusted, untrusted_reason) \
+             VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+        )
+        .bind(&url.slug)
+        .bind(&url.original_url)
+        .bind(url.expires_at.map(format_utc))
+        .bind(&url.title)
+        .bind(&url.description)
+        .bind(&url.notes)
+        .bind(&url.password_hash)
+        .bind(url.max_clicks)
+        .bind(url.activates_at.map(format_utc))
+        .bind(&url.pending_url)
+        .bind(&url.fallback_url)
+        .bind(&url.fallback_page)
+        .bind(url.redirect_type)
+        .bind(&url.query_passthrough)
+        .bind(url.path_passthrough)
+        .bind(&url.utm.source)
+        .bind(&url.utm.medium)
+        .bind(&url.utm.campaign)
+        .bind(&url.utm.term)
+        .bind(&url.utm.content)
+        .bind(url.parent_id)
+        .bind(&url.created_by)
+        .bind(&url.owner_id)
+        .bind(url.anonymous)
+        .bind(url.untrusted)
+        .bind(&url.untrusted_reason)
+        .execute(&self.pool)
+        .await?;
+        Ok(result.last_insert_rowid())
+    }
+
+    /// Inserts a link migrated from another shortener, keeping its creation date and click total.
+    pub async fn insert_imported_url(&self, record: &ImportRecord) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            "INSERT INTO urls (slug, original_url, created_at, clicks, expires_at) \
+             VALUES (?, ?, COALESCE(?, CURRENT_TIMESTAMP), ?, ?)",
+        )
+        .bind(&record.slug)
+        .bind(&record.original_url)
+        .bind(&record.created_at)
+        .bind(record.clicks)
+        .bind(&record.expires_at)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    /// Counts a click unless the link has reached its `max_clicks`. The check and
+    /// the increment are one statement, so concurrent visitors can't both get
+    /// the last click. Returns whether the click was allowed.
+    pub async fn consume_click(&self, id: i64) -> Result<bool, sqlx::Error> {
+        let result = sqlx::query(
+            "UPDATE urls SET clicks = clicks + 1 \
+             WHERE id = ? AND (max_clicks IS NULL OR clicks < max_clicks)",
+        )
+        .bind(id)
+        .execute(&self.pool)
+        .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    pub async fn insert_click_event(
         &self,
-        slug: &str,
-        original_url: &str,
-        expires_at: Option<&str>,
+        url_id: i64,
+        referrer: Option<&str>,
+        user_agent: Option<&str>,
+        country: Option<&str>,
     ) -> Result<(), sqlx::Error> {
-        sqlx::query("INSERT INTO urls (slug, original_url, expires_at) VALUES (?, ?, ?)")
-            .bind(slug)
-            .bind(original_url)
-            .bind(expires_at)
+        sqlx::query("INSERT INTO click_events (url_id, referrer, user_agent, country) VALUES (?, ?, ?, ?)")
+            .bind(url_id)
+            .bind(referrer)
+            .bind(user_agent)
+            .bind(country)
             .execute(&self.pool)
             .await?;
         Ok(())
     }
 
-    pub async fn increment_clicks(&self, slug: &str) -> Result<(), sqlx::Error> {
-        sqlx::query("UPDATE urls SET clicks = clicks + 1 WHERE slug = ?")
-            .bind(slug)
+    pub async fn get_urls(&self, filter: &UrlFilter) -> Result<Vec<UrlRecord>, sqlx::Error> {
+        let mut query = QueryBuilder::new(format!("SELECT {} FROM urls u WHERE 1 = 1", URL_COLUMNS));
+        push_url_filter(&mut query, filter);
+        query.push(" ORDER BY u.created_at DESC");
+        query.build_query_as::<UrlRecord>().fetch_all(&self.pool).await
+    }
+
+    /// Streams every link matching `filter` into `f` without loading them all
+    /// into memory. Stops early once `f` returns `false`.
+    pub async fn for_each_url<F, Fut>(&self, filter: &UrlFilter, f: F) -> Result<(), sqlx::Error>
+    where
+        F: FnMut(UrlRecord) -> Fut,
+        Fut: Future<Output = bool>,
+    {
+        let mut query = QueryBuilder::new(format!("SELECT {} FROM urls u WHERE 1 = 1", URL_COLUMNS));
+        push_url_filter(&mut query, filter);
+        query.push(" ORDER BY u.id");
+        self.for_each_row(query, f).await
+    }
+
+    /// Same as `for_each_url`, but for the click events of the matching links.
+    pub async fn for_each_click<F, Fut>(&self, filter: &UrlFilter, f: F) -> Result<(), sqlx::Error>
+    where
+        F: FnMut(ClickEvent) -> Fut,
+        Fut: Future<Output = bool>,
+    {
+        let mut query = QueryBuilder::new(
+            "SELECT c.id, c.url_id, u.slug, c.clicked_at, c.referrer, c.user_agent, c.country \
+             FROM click_events c JOIN urls u ON u.id = c.url_id WHERE 1 = 1",
+        );
+        push_url_filter(&mut query, filter);
+        query.push(" ORDER BY c.id");
+        self.for_each_row(query, f).await
+    }
+
+    async fn for_each_row<T, F, Fut>(
+        &self,
+        mut query: QueryBuilder<'_, Sqlite>,
+        mut f: F,
+    ) -> Result<(), sqlx::Error>
+    where
+        T: for<'r> FromRow<'r, SqliteRow> + Send + Unpin,
+        F: FnMut(T) -> Fut,
+        Fut: Future<Output = bool>,
+    {
+        let mut rows = query.build_query_as::<T>().fetch(&self.pool);
+        while let Some(row) = rows.next().await {
+            if !f(row?).await {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    pub async fn delete_url(&self, id: i64) -> Result<(), sqlx::Error> {
+        let mut tx = self.pool.begin().await?;
+        delete_url_in(&mut tx, id).await?;
+        tx.commit().await
+    }
+
+    /// Applies the fields present in `changes`. Returns `false` if the link doesn't exist.
+    pub async fn update_url(&self, id: i64, changes: &UpdateUrlRequest) -> Result<bool, sqlx::Error> {
+        let mut query = QueryBuilder::new("UPDATE urls SET ");
+        let mut fields = query.separated(", ");
+        fields.push("id = id");
+        if let Some(expires_at) = &changes.expires_at {
+            fields.push("expires_at = ").push_bind_unseparated(expires_at.clone());
+        }
+        if let Some(title) = &changes.title {
+            fields.push("title = ").push_bind_unseparated(title.clone());
+        }
+        if let Some(description) = &changes.description {
+            fields.push("description = ").push_bind_unseparated(description.clone());
+        }
+        if let Some(notes) = &changes.notes {
+            fields.push("notes = ").push_bind_unseparated(notes.clone());
+        }
+        if let Some(max_clicks) = changes.max_clicks {
+            fields.push("max_clicks = ").push_bind_unseparated(max_clicks);
+        }
+        if let Some(activates_at) = &changes.activates_at {
+            fields.push("activates_at = ").push_bind_unseparated(activates_at.clone());
+        }
+        if let Some(pending_url) = &changes.pending_url {
+            fields.push("pending_url = ").push_bind_unseparated(pending_url.clone());
+        }
+        if let Some(disabled) = changes.disabled {
+            fields.push("disabled = ").push_bind_unseparated(disabled);
+            if !disabled {
+                fields.push("disabled_reason = NULL");
+            }
+        }
+        if let Some(disabled_reason) = &changes.disabled_reason {
+            fields.push("disabled_reason = ").push_bind_unseparated(disabled_reason.clone());
+        }
+        if let Some(fallback_url) = &changes.fallback_url {
+            fields.push("fallback_url = ").push_bind_unseparated(fallback_url.clone());
+        }
+        if let Some(fallback_page) = &changes.fallback_page {
+            fields.push("fallback_page = ").push_bind_unseparated(fallback_page.clone());
+        }
+        if let Some(redirect_type) = changes.redirect_type {
+            fields.push("redirect_type = ").push_bind_unseparated(redirect_type);
+        }
+        if let Some(query_passthrough) = &changes.query_passthrough {
+            fields.push("query_passthrough = ").push_bind_unseparated(query_passthrough.clone());
+        }
+        if let Some(path_passthrough) = changes.path_passthrough {
+            fields.push("path_passthrough = ").push_bind_unseparated(path_passthrough);
+        }
+        if let Some(untrusted) = changes.untrusted {
+            fields.push("untrusted = ").push_bind_unseparated(untrusted);
+            if !untrusted {
+                fields.push("untrusted_reason = NULL");
+            }
+        }
+        let utm = [
+            ("utm_source", &changes.utm_source),
+            ("utm_medium", &changes.utm_medium),
+            ("utm_campaign", &changes.utm_campaign),
+            ("utm_term", &changes.utm_term),
+            ("utm_content", &changes.utm_content),
+        ];
+        for (column, value) in utm {
+            if let Some(value) = value {
+                fields.push(column).push_unseparated(" = ").push_bind_unseparated(value.clone());
+            }
+        }
+        query.push(" WHERE id = ").push_bind(id);
+
+        let result = query.build().execute(&self.pool).await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Takes a link offline, e.g. when its destination turns out to be blocked.
+    pub async fn disable_url(&self, id: i64, reason: &str) -> Result<(), sqlx::Error> {
+        sqlx::query("UPDATE urls SET disabled = 1, disabled_reason = ? WHERE id = ?")
+            .bind(reason)
+            .bind(id)
             .execute(&self.pool)
             .await?;
         Ok(())
     }
 
-    pub async fn get_all_urls(&self) -> Result<Vec<crate::models::UrlRecord>, sqlx::Error> {
-        sqlx::query_as::<_, crate::models::UrlRecord>(
-            "SELECT * FROM urls ORDER BY created_at DESC",
+    /// Puts a link behind the untrusted-link interstitial, recording why.
+    pub async fn flag_untrusted(&self, id: i64, reason: &str) -> Result<(), sqlx::Error> {
+        sqlx::query("UPDATE urls SET untrusted = 1, untrusted_reason = ? WHERE id = ?")
+            .bind(reason)
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    /// Id, slug and destinations of every enabled link, for threat screening:
+    /// its own, pending and fallback URLs plus those of its device rules, geo
+    /// rules and rotation. With `only_trusted`, links already flagged as
+    /// untrusted are skipped too.
+    pub async fn get_screenable_links(&self, only_trusted: bool) -> Result<Vec<(i64, String, Vec<String>)>, sqlx::Error> {
+        let rows: Vec<(i64, String, String)> = sqlx::query_as(
+            "SELECT u.id, u.slug, d.url FROM urls u JOIN ( \
+                SELECT id AS url_id, original_url AS url FROM urls \
+                UNION ALL SELECT id, pending_url FROM urls WHERE pending_url IS NOT NULL \
+                UNION ALL SELECT id, fallback_url FROM urls WHERE fallback_url IS NOT NULL \
+                UNION ALL SELECT url_id, url FROM device_rules \
+                UNION ALL SELECT url_id, url FROM geo_rules \
+                UNION ALL SELECT url_id, url FROM rotation_destinations \
+             ) d ON d.url_id = u.id \
+             WHERE u.disabled = 0 AND (? = 0 OR u.untrusted = 0) ORDER BY u.id",
+        )
+        .bind(only_trusted)
+        .fetch_all(&self.pool)
+        .await?;
+        let mut links: Vec<(i64, String, Vec<String>)> = Vec::new();
+        for (id, slug, url) in rows {
+            match links.last_mut() {
+                Some((last_id, _, destinations)) if *last_id == id => destinations.push(url),
+                _ => links.push((id, slug, vec![url])),
+            }
+        }
+        Ok(links)
+    }
+
+    /// Enabled links whose destinations are due for a health check, least
+    /// recently checked first. Each failure in a row doubles the wait, up to 64
+    /// times `interval_secs`.
+    pub async fn get_due_health_checks(&self, interval_secs: i64, limit: i64) -> Result<Vec<i64>, sqlx::Error> {
+        sqlx::query_scalar(
+            "SELECT id FROM urls \
+             WHERE disabled = 0 AND (health_checked_at IS NULL \
+                OR datetime(health_checked_at, '+' || (? << min(health_failures, 6)) || ' seconds') <= datetime('now')) \
+             ORDER BY health_checked_at IS NOT NULL, health_checked_at LIMIT ?",
         )
+        .bind(interval_secs)
+        .bind(limit)
         .fetch_all(&self.pool)
         .await
     }
 
-    pub async fn delete_url(&self, id: i64) -> Result<(), sqlx::Error> {
-        sqlx::query("DELETE FROM urls WHERE id = ?")
+    /// Where a link can send visitors: its own destination first, then those of
+    /// its device rules, geo rules and active rotation, without duplicates.
+    pub async fn get_destinations(&self, id: i64) -> Result<Vec<String>, sqlx::Error> {
+        let urls: Vec<String> = sqlx::query_scalar(
+            "SELECT url FROM ( \
+                SELECT 0 AS source, 0 AS position, original_url AS url FROM urls WHERE id = ? \
+                UNION ALL SELECT 1, position, url FROM device_rules WHERE url_id = ? \
+                UNION ALL SELECT 2, 0, url FROM geo_rules WHERE url_id = ? \
+                UNION ALL SELECT 3, position, url FROM rotation_destinations WHERE url_id = ? AND weight > 0 \
+             ) ORDER BY source, position",
+        )
+        .bind(id)
+        .bind(id)
+        .bind(id)
+        .bind(id)
+        .fetch_all(&self.pool)
+        .await?;
+        let mut destinations = Vec::with_capacity(urls.len());
+        for url in urls {
+            if !destinations.contains(&url) {
+                destinations.push(url);
+            }
+        }
+        Ok(destinations)
+    }
+
+    pub async fn record_health(&self, id: i64, check: &HealthCheck) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            "UPDATE urls SET health_status = ?, health_error = ?, health_latency_ms = ?, health_chain = ?, \
+             health_checked_at = datetime('now'), \
+             health_failures = CASE WHEN ? THEN health_failures + 1 ELSE 0 END \
+             WHERE id = ?",
+        )
+        .bind(check.status.map(i64::from))
+        .bind(&check.error)
+        .bind(check.latency_ms)
+        .bind((!check.chain.is_empty()).then(|| check.chain.join("\n")))
+        .bind(check.is_broken())
+        .bind(id)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    /// Removes links that expired before `cutoff`, first copying them to
+    /// `archived_urls` when `archive` is set. Returns how many were removed.
+    pub async fn purge_expired(&self, cutoff: chrono::DateTime<chrono::Utc>, archive: bool) -> Result<usize, sqlx::Error> {
+        let expired = sqlx::query_as::<_, UrlRecord>(&format!(
+            "SELECT {} FROM urls u WHERE u.expires_at < ?",
+            URL_COLUMNS
+        ))
+        .bind(format_utc(cutoff))
+        .fetch_all(&self.pool)
+        .await?;
+
+        let mut purged = 0;
+        for record in expired {
+            let mut tx = self.pool.begin().await?;
+            if archive {
+                sqlx::query(
+                    "INSERT OR REPLACE INTO archived_urls (id, slug, original_url, expires_at, clicks, data) \
+                     VALUES (?, ?, ?, ?, ?, ?)",
+                )
+                .bind(record.id)
+                .bind(&record.slug)
+                .bind(&record.original_url)
+                .bind(record.expires_at.map(format_utc))
+                .bind(record.clicks)
+                .bind(serde_json::to_string(&record).unwrap_or_default())
+                .execute(&mut *tx)
+                .await?;
+            }
+            delete_url_in(&mut tx, record.id).await?;
+            tx.commit().await?;
+            purged += 1;
+        }
+        Ok(purged)
+    }
+
+    /// Folds click events older than `retention_days` into per-day, per-country
+    /// counts and deletes them. Returns how many events were folded.
+    pub async fn rollup_clicks(&self, retention_days: i64) -> Result<u64, sqlx::Error> {
+        let cutoff = format!("-{} days", retention_days);
+        let mut tx = self.pool.begin().await?;
+        sqlx::query(
+            "INSERT INTO click_rollups (url_id, day, country, clicks) \
+             SELECT url_id, date(clicked_at), COALESCE(country, ''), COUNT(*) FROM click_events \
+             WHERE clicked_at < datetime('now', ?) GROUP BY 1, 2, 3 \
+             ON CONFLICT (url_id, day, country) DO UPDATE SET clicks = clicks + excluded.clicks",
+        )
+        .bind(&cutoff)
+        .execute(&mut *tx)
+        .await?;
+        let result = sqlx::query("DELETE FROM click_events WHERE clicked_at < datetime('now', ?)")
+            .bind(&cutoff)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+        Ok(result.rows_affected())
+    }
+
+    pub async fn vacuum(&self) -> Result<(), sqlx::Error> {
+        sqlx::query("ANALYZE").execute(&self.pool).await?;
+        sqlx::query("VACUUM").execute(&self.pool).await?;
+        Ok(())
+    }
+
+    /// Adds a scheduled job, or reschedules it when its schedule changed.
+    pub async fn register_job(&self, name: &str, schedule: &str, next_run_at: &str) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            "INSERT INTO job_runs (name, schedule, next_run_at) VALUES (?, ?, ?) \
+             ON CONFLICT (name) DO UPDATE SET schedule = excluded.schedule, next_run_at = excluded.next_run_at \
+             WHERE job_runs.schedule != excluded.schedule",
+        )
+        .bind(name)
+        .bind(schedule)
+        .bind(next_run_at)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    /// Takes the lease on a job that is due and not held by anyone else.
+    /// Returns whether this caller got it and should run the job.
+    pub async fn claim_job(&self, name: &str, holder: &str, lease_secs: i64) -> Result<bool, sqlx::Error> {
+        let result = sqlx::query(
+            "UPDATE job_runs SET lease_holder = ?, lease_until = datetime('now', ?), \
+             last_started_at = datetime('now') \
+             WHERE name = ? AND next_run_at <= datetime('now') \
+             AND (lease_until IS NULL OR lease_until < datetime('now'))",
+        )
+        .bind(holder)
+        .bind(format!("+{} seconds", lease_secs))
+        .bind(name)
+        .execute(&self.pool)
+        .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Records a finished run and releases the lease, if this caller still holds it.
+    pub async fn finish_job(&self, name: &str, holder: &str, next_run_at: &str, status: &str, message: &str) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            "UPDATE job_runs SET next_run_at = ?, lease_holder = NULL, lease_until = NULL, \
+             last_finished_at = datetime('now'), last_status = ?, last_message = ? \
+             WHERE name = ? AND lease_holder = ?",
+        )
+        .bind(next_run_at)
+        .bind(status)
+        .bind(message)
+        .bind(name)
+        .bind(holder)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    pub async fn get_jobs(&self) -> Result<Vec<JobRun>, sqlx::Error> {
+        sqlx::query_as::<_, JobRun>("SELECT * FROM job_runs ORDER BY name")
+            .fetch_all(&self.pool)
+            .await
+    }
+
+    /// Makes a job due now, so the next scheduler poll runs it.
+    pub async fn trigger_job(&self, name: &str) -> Result<bool, sqlx::Error> {
+        let result = sqlx::query("UPDATE job_runs SET next_run_at = datetime('now') WHERE name = ?")
+            .bind(name)
+            .execute(&self.pool)
+            .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    pub async fn set_password_hash(&self, id: i64, password_hash: Option<&str>) -> Result<bool, sqlx::Error> {
+        let result = sqlx::query("UPDATE urls SET password_hash = ? WHERE id = ?")
+            .bind(password_hash)
             .bind(id)
             .execute(&self.pool)
             .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Stores fetched page metadata without overwriting values set by a user.
+    pub async fn set_fetched_metadata(
+        &self,
+        id: i64,
+        title: Option<&str>,
+        description: Option<&str>,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            "UPDATE urls SET title = COALESCE(title, ?), description = COALESCE(description, ?) \
+             WHERE id = ?",
+        )
+        .bind(title)
+        .bind(description)
+        .bind(id)
+        .execute(&self.pool)
+        .await?;
         Ok(())
     }
 
-    pub async fn update_expiry(&self, id: i64, expires_at: Option<&str>) -> Result<(), sqlx::Error> {
-        sqlx::query("UPDATE urls SET expires_at = ? WHERE id = ?")
-            .bind(expires_at)
+    pub async fn get_tag_stats(&self) -> Result<Vec<TagStats>, sqlx::Error> {
+        sqlx::query_as::<_, TagStats>(
+            "SELECT t.id, t.name, COUNT(u.id) AS links, COALESCE(SUM(u.clicks), 0) AS clicks \
+             FROM tags t \
+             LEFT JOIN url_tags ut ON ut.tag_id = t.id \
+             LEFT JOIN urls u ON u.id = ut.url_id \
+             GROUP BY t.id ORDER BY t.name",
+        )
+        .fetch_all(&self.pool)
+        .await
+    }
+
+    pub async fn insert_tag(&self, name: &str) -> Result<i64, sqlx::Error> {
+        let result = sqlx::query("INSERT INTO tags (name) VALUES (?)")
+            .bind(name)
+            .execute(&self.pool)
+            .await?;
+        Ok(result.last_insert_rowid())
+    }
+
+    pub async fn rename_tag(&self, id: i64, name: &str) -> Result<bool, sqlx::Error> {
+        let result = sqlx::query("UPDATE tags SET name = ? WHERE id = ?")
+            .bind(name)
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    pub async fn delete_tag(&self, id: i64) -> Result<(), sqlx::Error> {
+        let mut tx = self.pool.begin().await?;
+        sqlx::query("DELETE FROM url_tags WHERE tag_id = ?")
+            .bind(id)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query("DELETE FROM tags WHERE id = ?")
+            .bind(id)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await
+    }
+
+    /// Replaces the tags on a link, creating any tag that doesn't exist yet.
+    /// Returns `false` if the link doesn't exist.
+    pub async fn set_url_tags(&self, url_id: i64, tags: &[String]) -> Result<bool, sqlx::Error> {
+        let mut tx = self.pool.begin().await?;
+        let exists: Option<(i64,)> = sqlx::query_as("SELECT id FROM urls WHERE id = ?")
+            .bind(url_id)
+            .fetch_optional(&mut *tx)
+            .await?;
+        if exists.is_none() {
+            return Ok(false);
+        }
+        sqlx::query("DELETE FROM url_tags WHERE url_id = ?")
+            .bind(url_id)
+            .execute(&mut *tx)
+            .await?;
+        for tag in tags {
+            sqlx::query("INSERT OR IGNORE INTO tags (name) VALUES (?)")
+                .bind(tag)
+                .execute(&mut *tx)
+                .await?;
+            sqlx::query(
+                "INSERT OR IGNORE INTO url_tags (url_id, tag_id) SELECT ?, id FROM tags WHERE name = ?",
+            )
+            .bind(url_id)
+            .bind(tag)
+            .execute(&mut *tx)
+            .await?;
+        }
+        tx.commit().await?;
+        Ok(true)
+    }
+
+    pub async fn get_device_rules(&self, url_id: i64) -> Result<Vec<DeviceRule>, sqlx::Error> {
+        sqlx::query_as::<_, DeviceRule>(
+            "SELECT platform, browser, url FROM device_rules WHERE url_id = ? ORDER BY position",
+        )
+        .bind(url_id)
+        .fetch_all(&self.pool)
+        .await
+    }
+
+    /// Replaces a link's device rules. Returns `false` if the link doesn't exist.
+    pub async fn set_device_rules(&self, url_id: i64, rules: &[DeviceRule]) -> Result<bool, sqlx::Error> {
+        let mut tx = self.pool.begin().await?;
+        let exists: Option<(i64,)> = sqlx::query_as("SELECT id FROM urls WHERE id = ?")
+            .bind(url_id)
+            .fetch_optional(&mut *tx)
+            .await?;
+        if exists.is_none() {
+            return Ok(false);
+        }
+        sqlx::query("DELETE FROM device_rules WHERE url_id = ?")
+            .bind(url_id)
+            .execute(&mut *tx)
+            .await?;
+        for (position, rule) in rules.iter().enumerate() {
+            sqlx::query("INSERT INTO device_rules (url_id, position, platform, browser, url) VALUES (?, ?, ?, ?, ?)")
+                .bind(url_id)
+                .bind(position as i64)
+                .bind(&rule.platform)
+                .bind(&rule.browser)
+                .bind(&rule.url)
+                .execute(&mut *tx)
+                .await?;
+        }
+        tx.commit().await?;
+        Ok(true)
+    }
+
+    pub async fn get_geo_rules(&self, url_id: i64) -> Result<Vec<GeoRule>, sqlx::Error> {
+        sqlx::query_as::<_, GeoRule>("SELECT country, url FROM geo_rules WHERE url_id = ? ORDER BY country")
+            .bind(url_id)
+            .fetch_all(&self.pool)
+            .await
+    }
+
+    pub async fn get_geo_rule(&self, url_id: i64, country: &str) -> Result<Option<String>, sqlx::Error> {
+        let row: Option<(String,)> = sqlx::query_as("SELECT url FROM geo_rules WHERE url_id = ? AND country = ?")
+            .bind(url_id)
+            .bind(country)
+            .fetch_optional(&self.pool)
+            .await?;
+        Ok(row.map(|(url,)| url))
+    }
+
+    /// Replaces a link's geo rules. Returns `false` if the link doesn't exist.
+    pub async fn set_geo_rules(&self, url_id: i64, rules: &[GeoRule]) -> Result<bool, sqlx::Error> {
+        let mut tx = self.pool.begin().await?;
+        let exists: Option<(i64,)> = sqlx::query_as("SELECT id FROM urls WHERE id = ?")
+            .bind(url_id)
+            .fetch_optional(&mut *tx)
+            .await?;
+        if exists.is_none() {
+            return Ok(false);
+        }
+        sqlx::query("DELETE FROM geo_rules WHERE url_id = ?")
+            .bind(url_id)
+            .execute(&mut *tx)
+            .await?;
+        for rule in rules {
+            sqlx::query("INSERT INTO geo_rules (url_id, country, url) VALUES (?, ?, ?)")
+                .bind(url_id)
+                .bind(&rule.country)
+                .bind(&rule.url)
+                .execute(&mut *tx)
+                .await?;
+        }
+        tx.commit().await?;
+        Ok(true)
+    }
+
+    pub async fn get_rotation(&self, url_id: i64) -> Result<Vec<RotationDestination>, sqlx::Error> {
+        sqlx::query_as::<_, RotationDestination>(
+            "SELECT id, url, weight, clicks FROM rotation_destinations WHERE url_id = ? ORDER BY position",
+        )
+        .bind(url_id)
+        .fetch_all(&self.pool)
+        .await
+    }
+
+    /// Replaces a link's rotation. Destinations that keep their URL keep their id
+    /// and click count, so sticky visitors stay put. Returns `false` if the link
+    /// doesn't exist.
+    pub async fn set_rotation(&self, url_id: i64, destinations: &[RotationEntry], sticky: bool) -> Result<bool, sqlx::Error> {
+        let mut tx = self.pool.begin().await?;
+        let result = sqlx::query("UPDATE urls SET sticky_rotation = ? WHERE id = ?")
+            .bind(sticky)
+            .bind(url_id)
+            .execute(&mut *tx)
+            .await?;
+        if result.rows_affected() == 0 {
+            return Ok(false);
+        }
+        let previous: Vec<(i64, String)> = sqlx::query_as("SELECT id, url FROM rotation_destinations WHERE url_id = ?")
+            .bind(url_id)
+            .fetch_all(&mut *tx)
+            .await?;
+        for (id, url) in &previous {
+            if !destinations.iter().any(|d| d.url == *url) {
+                sqlx::query("DELETE FROM rotation_destinations WHERE id = ?")
+                    .bind(id)
+                    .execute(&mut *tx)
+                    .await?;
+            }
+        }
+        for (position, destination) in destinations.iter().enumerate() {
+            match previous.iter().find(|(_, url)| *url == destination.url) {
+                Some((id, _)) => {
+                    sqlx::query("UPDATE rotation_destinations SET position = ?, weight = ? WHERE id = ?")
+                        .bind(position as i64)
+                        .bind(destination.weight)
+                        .bind(id)
+                        .execute(&mut *tx)
+                        .await?;
+                }
+                None => {
+                    sqlx::query(
+                        "INSERT INTO rotation_destinations (url_id, position, url, weight) VALUES (?, ?, ?, ?)",
+                    )
+                    .bind(url_id)
+                    .bind(position as i64)
+                    .bind(&destination.url)
+                    .bind(destination.weight)
+                    .execute(&mut *tx)
+                    .await?;
+                }
+            }
+        }
+        tx.commit().await?;
+        Ok(true)
+    }
+
+    pub async fn record_rotation_click(&self, id: i64) -> Result<(), sqlx::Error> {
+        sqlx::query("UPDATE rotation_destinations SET clicks = clicks + 1 WHERE id = ?")
             .bind(id)
             .execute(&self.pool)
             .await?;
         Ok(())
     }
+
+    pub async fn get_country_stats(&self, url_id: i64) -> Result<Vec<CountryStats>, sqlx::Error> {
+        sqlx::query_as::<_, CountryStats>(
+            "SELECT country, SUM(clicks) AS clicks FROM ( \
+                SELECT country, COUNT(*) AS clicks FROM click_events WHERE url_id = ? GROUP BY country \
+                UNION ALL \
+                SELECT NULLIF(country, ''), SUM(clicks) FROM click_rollups WHERE url_id = ? GROUP BY country \
+             ) GROUP BY country ORDER BY clicks DESC, country",
+        )
+        .bind(url_id)
+        .bind(url_id)
+        .fetch_all(&self.pool)
+        .await
+    }
+
+    pub async fn get_folder_stats(&self) -> Result<Vec<FolderStats>, sqlx::Error> {
+        sqlx::query_as::<_, FolderStats>(
+            "SELECT f.id, f.name, f.created_at, COUNT(u.id) AS links, COALESCE(SUM(u.clicks), 0) AS clicks \
+             FROM folders f \
+             LEFT JOIN urls u ON u.folder_id = f.id \
+             GROUP BY f.id ORDER BY f.name",
+        )
+        .fetch_all(&self.pool)
+        .await
+    }
+
+    pub async fn insert_folder(&self, name: &str) -> Result<i64, sqlx::Error> {
+        let result = sqlx::query("INSERT INTO folders (name) VALUES (?)")
+            .bind(name)
+            .execute(&self.pool)
+            .await?;
+        Ok(result.last_insert_rowid())
+    }
+
+    pub async fn rename_folder(&self, id: i64, name: &str) -> Result<bool, sqlx::Error> {
+        let result = sqlx::query("UPDATE folders SET name = ? WHERE id = ?")
+            .bind(name)
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    pub async fn delete_folder(&self, id: i64) -> Result<(), sqlx::Error> {
+        let mut tx = self.pool.begin().await?;
+        sqlx::query("UPDATE urls SET folder_id = NULL WHERE folder_id = ?")
+            .bind(id)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query("DELETE FROM folders WHERE id = ?")
+            .bind(id)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await
+    }
+
+    pub async fn folder_exists(&self, id: i64) -> Result<bool, sqlx::Error> {
+        let result: Option<(i64,)> = sqlx::query_as("SELECT id FROM folders WHERE id = ?")
+            .bind(id)
+            .fetch_optional(&self.pool)
+            .await?;
+        Ok(result.is_some())
+    }
+
+    pub async fn set_url_folder(&self, url_id: i64, folder_id: Option<i64>) -> Result<bool, sqlx::Error> {
+        let result = sqlx::query("UPDATE urls SET folder_id = ? WHERE id = ?")
+            .bind(folder_id)
+            .bind(url_id)
+            .execute(&self.pool)
+            .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    pub async fn count_accounts(&self) -> Result<i64, sqlx::Error> {
+        let (count,): (i64,) = sqlx::query_as("SELECT COUNT(*) FROM accounts")
+            .fetch_one(&self.pool)
+            .await?;
+        Ok(count)
+    }
+
+    pub async fn get_accounts(&self) -> Result<Vec<Account>, sqlx::Error> {
+        sqlx::query_as::<_, Account>("SELECT * FROM accounts ORDER BY username")
+            .fetch_all(&self.pool)
+            .await
+    }
+
+    pub async fn get_account(&self, id: i64) -> Result<Option<Account>, sqlx::Error> {
+        sqlx::query_as::<_, Account>("SELECT * FROM accounts WHERE id = ?")
+            .bind(id)
+            .fetch_optional(&self.pool)
+            .await
+    }
+
+    /// Looks up an account by username, ignoring case.
+    pub async fn get_account_by_username(&self, username: &str) -> Result<Option<Account>, sqlx::Error> {
+        sqlx::query_as::<_, Account>("SELECT * FROM accounts WHERE username = ?")
+            .bind(username)
+            .fetch_optional(&self.pool)
+            .await
+    }
+
+    pub async fn insert_account(&self, username: &str, password_hash: &str, role: Role, created_by: &str) -> Result<i64, sqlx::Error> {
+        let result = sqlx::query("INSERT INTO accounts (username, password_hash, role, created_by) VALUES (?, ?, ?, ?)")
+            .bind(username)
+            .bind(password_hash)
+            .bind(role)
+            .bind(created_by)
+            .execute(&self.pool)
+            .await?;
+        Ok(result.last_insert_rowid())
+    }
+
+    /// Applies the fields that are set. Returns `false` if the account doesn't exist.
+    pub async fn update_account(&self, id: i64, role: Option<Role>, disabled: Option<bool>) -> Result<bool, sqlx::Error> {
+        let result = sqlx::query(
+            "UPDATE accounts SET role = COALESCE(?, role), disabled = COALESCE(?, disabled) WHERE id = ?",
+        )
+        .bind(role)
+        .bind(disabled)
+        .bind(id)
+        .execute(&self.pool)
+        .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    pub async fn delete_account(&self, id: i64) -> Result<bool, sqlx::Error> {
+        let mut tx = self.pool.begin().await?;
+        sqlx::query("DELETE FROM recovery_codes WHERE account_id = ?")
+            .bind(id)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query("DELETE FROM account_tokens WHERE account_id = ?")
+            .bind(id)
+            .execute(&mut *tx)
+            .await?;
+        let result = sqlx::query("DELETE FROM accounts WHERE id = ?")
+            .bind(id)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Stores an invite (`account_id` unset) or a password reset token, valid for `ttl_secs`.
+    /// Returns when it expires.
+    pub async fn insert_account_token(
+        &self,
+        token_hash: &str,
+        kind: &str,
+        account_id: Option<i64>,
+        role: Option<Role>,
+        created_by: &str,
+        ttl_secs: i64,
+    ) -> Result<String, sqlx::Error> {
+        let (expires_at,): (String,) = sqlx::query_as(
+            "INSERT INTO account_tokens (token_hash, kind, account_id, role, created_by, expires_at) \
+             VALUES (?, ?, ?, ?, ?, datetime('now', ?)) RETURNING expires_at",
+        )
+        .bind(token_hash)
+        .bind(kind)
+        .bind(account_id)
+        .bind(role)
+        .bind(created_by)
+        .bind(format!("+{} seconds", ttl_secs))
+        .fetch_one(&self.pool)
+        .await?;
+        Ok(expires_at)
+    }
+
+    /// Creates an account from an unused, unexpired invite and uses up the invite. Returns
+    /// `None` if the invite isn't valid; a taken username is a unique violation and leaves
+    /// the invite unused.
+    pub async fn redeem_invite(&self, token_hash: &str, username: &str, password_hash: &str) -> Result<Option<(i64, Role)>, sqlx::Error> {
+        let mut tx = self.pool.begin().await?;
+        let invite: Option<(Role, String)> = sqlx::query_as(
+            "UPDATE account_tokens SET used_at = datetime('now') \
+             WHERE token_hash = ? AND kind = 'invite' AND used_at IS NULL AND expires_at > datetime('now') \
+             RETURNING role, created_by",
+        )
+        .bind(token_hash)
+        .fetch_optional(&mut *tx)
+        .await?;
+        let Some((role, invited_by)) = invite else {
+            return Ok(None);
+        };
+        let result = sqlx::query("INSERT INTO accounts (username, password_hash, role, created_by) VALUES (?, ?, ?, ?)")
+            .bind(username)
+            .bind(password_hash)
+            .bind(role)
+            .bind(invited_by)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+        Ok(Some((result.last_insert_rowid(), role)))
+    }
+
+    /// Sets a new password from an unused, unexpired reset token and uses up the token.
+    /// Returns the account's id, or `None` if the token isn't valid.
+    pub async fn redeem_password_reset(&self, token_hash: &str, password_hash: &str) -> Result<Option<i64>, sqlx::Error> {
+        let mut tx = self.pool.begin().await?;
+        let reset: Option<(i64,)> = sqlx::query_as(
+            "UPDATE account_tokens SET used_at = datetime('now') \
+             WHERE token_hash = ? AND kind = 'reset' AND used_at IS NULL AND expires_at > datetime('now') \
+             RETURNING account_id",
+        )
+        .bind(token_hash)
+        .fetch_optional(&mut *tx)
+        .await?;
+        let Some((account_id,)) = reset else {
+            return Ok(None);
+        };
+        let result = sqlx::query(
+            "UPDATE accounts SET password_hash = ?, sessions_valid_after = ? WHERE id = ?",
+        )
+        .bind(password_hash)
+        .bind(chrono::Utc::now().timestamp_millis())
+        .bind(account_id)
+        .execute(&mut *tx)
+        .await?;
+        if result.rows_affected() == 0 {
+            return Ok(None);
+        }
+        tx.commit().await?;
+        Ok(Some(account_id))
+    }
+
+    /// Withdraws any reset tokens not yet used for an account.
+    pub async fn revoke_password_resets(&self, account_id: i64) -> Result<(), sqlx::Error> {
+        sqlx::query("DELETE FROM account_tokens WHERE account_id = ? AND kind = 'reset' AND used_at IS NULL")
+            .bind(account_id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    /// Stores a new TOTP secret, not yet enabled, replacing one not yet confirmed.
+    /// Returns `false` if the account doesn't exist or already has TOTP enabled.
+    pub async fn set_pending_totp(&self, id: i64, secret: &str) -> Result<bool, sqlx::Error> {
+        let result = sqlx::query("UPDATE accounts SET totp_secret = ? WHERE id = ? AND NOT totp_enabled")
+            .bind(secret)
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Turns on TOTP, recording the step of the code that confirmed it, and replaces
+    /// the account's recovery codes.
+    pub async fn enable_totp(&self, id: i64, step: i64, recovery_code_hashes: &[String]) -> Result<(), sqlx::Error> {
+        let mut tx = self.pool.begin().await?;
+        sqlx::query("UPDATE accounts SET totp_enabled = 1, totp_last_step = ? WHERE id = ?")
+            .bind(step)
+            .bind(id)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query("DELETE FROM recovery_codes WHERE account_id = ?")
+            .bind(id)
+            .execute(&mut *tx)
+            .await?;
+        for code_hash in recovery_code_hashes {
+            sqlx::query("INSERT INTO recovery_codes (account_id, code_hash) VALUES (?, ?)")
+                .bind(id)
+                .bind(code_hash)
+                .execute(&mut *tx)
+                .await?;
+        }
+        tx.commit().await
+    }
+
+    pub async fn disable_totp(&self, id: i64) -> Result<(), sqlx::Error> {
+        let mut tx = self.pool.begin().await?;
+        sqlx::query("UPDATE accounts SET totp_secret = NULL, totp_enabled = 0, totp_last_step = NULL WHERE id = ?")
+            .bind(id)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query("DELETE FROM recovery_codes WHERE account_id = ?")
+            .bind(id)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await
+    }
+
+    /// Accepts a TOTP code's time step if it is later than the last one accepted, so each
+    /// code works once even with concurrent logins.
+    pub async fn accept_totp_step(&self, id: i64, step: i64) -> Result<bool, sqlx::Error> {
+        let result = sqlx::query(
+            "UPDATE accounts SET totp_last_step = ? WHERE id = ? AND (totp_last_step IS NULL OR totp_last_step < ?)",
+        )
+        .bind(step)
+        .bind(id)
+        .bind(step)
+        .execute(&self.pool)
+        .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Uses up a recovery code. Returns `false` if it doesn't exist or was already used.
+    pub async fn use_recovery_code(&self, account_id: i64, code_hash: &str) -> Result<bool, sqlx::Error> {
+        let result = sqlx::query(
+            "UPDATE recovery_codes SET used_at = datetime('now') \
+             WHERE account_id = ? AND code_hash = ? AND used_at IS NULL",
+        )
+        .bind(account_id)
+        .bind(code_hash)
+        .execute(&self.pool)
+        .await?;
+        Ok(result.rows_affected() > 0)
+    }
+}
+
+// Link columns plus derived flags and the link's tags joined into one comma-separated string
+const URL_COLUMNS: &str = "u.*, \
+    u.password_hash IS NOT NULL AS password_protected, \
+    (SELECT group_concat(t.name, ',') FROM url_tags ut JOIN tags t ON t.id = ut.tag_id \
+     WHERE ut.url_id = u.id) AS tags";
+
+// Deletes a link with its clicks, tags and rules, as part of `tx`
+async fn delete_url_in(tx: &mut Transaction<'_, Sqlite>, id: i64) -> Result<(), sqlx::Error> {
+    sqlx::query("DELETE FROM urls WHERE id = ?")
+        .bind(id)
+        .execute(&mut **tx)
+        .await?;
+    sqlx::query("DELETE FROM click_events WHERE url_id = ?")
+        .bind(id)
+        .execute(&mut **tx)
+        .await?;
+    sqlx::query("DELETE FROM click_rollups WHERE url_id = ?")
+        .bind(id)
+        .execute(&mut **tx)
+        .await?;
+    sqlx::query("DELETE FROM url_tags WHERE url_id = ?")
+        .bind(id)
+        .execute(&mut **tx)
+        .await?;
+    sqlx::query("DELETE FROM device_rules WHERE url_id = ?")
+        .bind(id)
+        .execute(&mut **tx)
+        .await?;
+    sqlx::query("DELETE FROM geo_rules WHERE url_id = ?")
+        .bind(id)
+        .execute(&mut **tx)
+        .await?;
+    sqlx::query("DELETE FROM rotation_destinations WHERE url_id = ?")
+        .bind(id)
+        .execute(&mut **tx)
+        .await?;
+    // Variants outlive their parent as standalone links
+    sqlx::query("UPDATE urls SET parent_id = NULL WHERE parent_id = ?")
+        .bind(id)
+        .execute(&mut **tx)
+        .await?;
+    Ok(())
+}
+
+async fn add_column_if_missing(
+    pool: &Pool<Sqlite>,
+    table: &str,
+    column: &str,
+    definition: &str,
+) -> Result<(), sqlx::Error> {
+    let existing: Option<(String,)> =
+        sqlx::query_as("SELECT name FROM pragma_table_info(?) WHERE name = ?")
+            .bind(table)
+            .bind(column)
+            .fetch_optional(pool)
+            .await?;
+    if existing.is_none() {
+        sqlx::query(&format!("ALTER TABLE {} ADD COLUMN {} {}", table, column, definition))
+            .execute(pool)
+            .await?;
+    }
+    Ok(())
+}
+
+// Rewrites expiry times stored before they were validated into the UTC format.
+// Values that can't be read take the link offline instead of leaving it live
+// forever, so an admin can set a proper expiry.
+async fn normalise_expiry(pool: &Pool<Sqlite>) -> Result<(), sqlx::Error> {
+    let rows: Vec<(i64, String)> =
+        sqlx::query_as("SELECT id, CAST(expires_at AS TEXT) FROM urls WHERE expires_at IS NOT NULL")
+            .fetch_all(pool)
+            .await?;
+    for (id, expires_at) in rows {
+        match parse_timestamp(expires_at.trim()).map(format_utc) {
+            Some(normalised) if normalised == expires_at => {}
+            Some(normalised) => {
+                sqlx::query("UPDATE urls SET expires_at = ? WHERE id = ?")
+                    .bind(normalised)
+                    .bind(id)
+                    .execute(pool)
+                    .await?;
+            }
+            None => {
+                tracing::warn!("Disabling link {}: unreadable expiry {:?}", id, expires_at);
+                sqlx::query("UPDATE urls SET expires_at = NULL, disabled = 1, disabled_reason = ? WHERE id = ?")
+                    .bind(format!("Unreadable expiry \"{}\"; set a new expiry and re-enable the link", expires_at))
+                    .bind(id)
+                    .execute(pool)
+                    .await?;
+            }
+        }
+    }
+    Ok(())
+}
+
+// Appends the shared list/export filters. Expects the `urls` table aliased as `u`.
+fn push_url_filter(query: &mut QueryBuilder<'_, Sqlite>, filter: &UrlFilter) {
+    if let Some(owner_id) = &filter.owner_id {
+        query.push(" AND u.owner_id = ").push_bind(owner_id.clone());
+    }
+    if let Some(q) = filter.q.as_deref().filter(|q| !q.is_empty()) {
+        let pattern = format!("%{}%", q);
+        query.push(" AND (u.slug LIKE ").push_bind(pattern.clone());
+        query.push(" OR u.original_url LIKE ").push_bind(pattern).push(")");
+    }
+    if let Some(after) = &filter.created_after {
+        query.push(" AND u.created_at >= ").push_bind(after.clone());
+    }
+    if let Some(before) = &filter.created_before {
+        query.push(" AND u.created_at < ").push_bind(before.clone());
+    }
+    // Comma-separated tags; a link must carry all of them
+    if let Some(tags) = &filter.tag {
+        for tag in tags.split(',').map(str::trim).filter(|t| !t.is_empty()) {
+            query.push(
+                " AND EXISTS (SELECT 1 FROM url_tags ut JOIN tags t ON t.id = ut.tag_id \
+                 WHERE ut.url_id = u.id AND t.name = ",
+            );
+            query.push_bind(tag.to_string()).push(")");
+        }
+    }
+    if let Some(folder) = &filter.folder {
+        query.push(" AND u.folder_id = (SELECT id FROM folders WHERE name = ");
+        query.push_bind(folder.clone()).push(")");
+    }
+    if filter.broken {
+        query.push(" AND (u.health_error IS NOT NULL OR u.health_status >= 400)");
+    }
+}
+
+#[cfg(test)]
+pub mod testing {
+    use rand::Rng;
+    use std::{ops::Deref, path::PathBuf};
+
+    use super::Database;
+
+    /// A database in a fresh temporary file, removed when dropped.
+    pub struct TempDb {
+        db: Database,
+        path: PathBuf,
+    }
+
+    impl TempDb {
+        pub async fn new() -> Self {
+            let path = std::env::temp_dir().join(format!(
+                "meoshorturl-test-{:016x}.sqlite",
+                rand::thread_rng().gen::<u64>()
+            ));
+            let db = Database::new(path.to_str().unwrap()).await.unwrap();
+            Self { db, path }
+        }
+    }
+
+    impl Deref for TempDb {
+        type Target = Database;
+
+        fn deref(&self) -> &Database {
+            &self.db
+        }
+    }
+
+    impl Drop for TempDb {
+        fn drop(&mut self) {
+            for suffix in ["", "-wal", "-shm"] {
+                let _ = std::fs::remove_file(format!("{}{}", self.path.display(), suffix));
+            }
+        }
+    }
 }
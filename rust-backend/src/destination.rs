@@ -0,0 +1,224 @@
+use url::{form_urlencoded, Url};
+
+/// How a visitor's query string is combined with the destination's.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QueryPassthrough {
+    // Destination parameters win when both set the same key
+    Merge,
+    // Visitor parameters replace destination parameters with the same key
+    Override,
+}
+
+impl QueryPassthrough {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "merge" => Some(Self::Merge),
+            "override" => Some(Self::Override),
+            _ => None,
+        }
+    }
+}
+
+/// Sets the link's `utm` parameters on the destination, appends `extra_path`
+/// (the part of the request after the slug) to its path and forwards `query`
+/// according to `mode`. The UTM parameters count as part of the destination, so
+/// only `Override` lets a visitor replace them. Returns the destination untouched
+/// when there is nothing to add or it doesn't parse.
+pub fn build(
+    destination: &str,
+    utm: &[(&str, &str)],
+    extra_path: Option<&str>,
+    query: Option<&str>,
+    mode: Option<QueryPassthrough>,
+) -> String {
+    let extra_path = extra_path.filter(|p| !p.trim_start_matches('/').is_empty());
+    let query = query.filter(|q| !q.is_empty());
+    if utm.is_empty() && extra_path.is_none() && (query.is_none() || mode.is_none()) {
+        return destination.to_string();
+    }
+    let Ok(mut url) = Url::parse(destination) else {
+        return destination.to_string();
+    };
+
+    if let Some(extra_path) = extra_path {
+        if let Ok(mut segments) = url.path_segments_mut() {
+            // "." and ".." are skipped, so the path can't climb above the destination's
+            segments
+                .pop_if_empty()
+                .extend(extra_path.trim_start_matches('/').split('/'));
+        }
+    }
+
+    // Rebuilding the query normalises its encoding, so leave it alone unless it changes
+    let forwarded_query = query.zip(mode);
+    if utm.is_empty() && forwarded_query.is_none() {
+        return url.to_string();
+    }
+
+    let mut pairs: Vec<(String, String)> = url.query_pairs().into_owned().collect();
+    for (key, value) in utm {
+        pairs.retain(|(k, _)| k != key);
+        pairs.push((key.to_string(), value.to_string()));
+    }
+
+    if let Some((query, mode)) = forwarded_query {
+        let incoming: Vec<(String, String)> = form_urlencoded::parse(query.as_bytes())
+            .into_owned()
+            .collect();
+        match mode {
+            QueryPassthrough::Merge => {
+                let existing: Vec<String> = pairs.iter().map(|(k, _)| k.clone()).collect();
+                pairs.extend(incoming.into_iter().filter(|(k, _)| !existing.contains(k)));
+            }
+            QueryPassthrough::Override => {
+                pairs.retain(|(k, _)| !incoming.iter().any(|(key, _)| key == k));
+                pairs.extend(incoming);
+            }
+        }
+    }
+
+    if pairs.is_empty() {
+        url.set_query(None);
+    } else {
+        url.query_pairs_mut().clear().extend_pairs(pairs);
+    }
+
+    url.to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const UTM: &[(&str, &str)] = &[("utm_source", "news"), ("utm_medium", "email")];
+
+    #[test]
+    fn untouched_without_anything_to_add() {
+        let destination = "https://example.com/a?b=%7e&c";
+        assert_eq!(build(destination, &[], None, None, None), destination);
+        assert_eq!(build(destination, &[], Some("/"), None, None), destination);
+        // A query with passthrough off isn't forwarded
+        assert_eq!(
+            build(destination, &[], None, Some("x=1"), None),
+            destination
+        );
+        assert_eq!(
+            build("not a url", UTM, Some("/a"), Some("x=1"), None),
+            "not a url"
+        );
+    }
+
+    #[test]
+    fn merge_keeps_destination_parameters() {
+        let merge = Some(QueryPassthrough::Merge);
+        assert_eq!(
+            build(
+                "https://example.com/?a=1&b=2",
+                &[],
+                None,
+                Some("b=3&c=4"),
+                merge
+            ),
+            "https://example.com/?a=1&b=2&c=4"
+        );
+        assert_eq!(
+            build("https://example.com/", &[], None, Some("q=a+b"), merge),
+            "https://example.com/?q=a+b"
+        );
+    }
+
+    #[test]
+    fn override_replaces_destination_parameters() {
+        let override_ = Some(QueryPassthrough::Override);
+        assert_eq!(
+            build(
+                "https://example.com/?a=1&b=2",
+                &[],
+                None,
+                Some("b=3&c=4"),
+                override_
+            ),
+            "https://example.com/?a=1&b=3&c=4"
+        );
+    }
+
+    #[test]
+    fn utm_parameters_only_yield_to_override() {
+        assert_eq!(
+            build(
+                "https://example.com/?utm_source=old&a=1",
+                UTM,
+                None,
+                None,
+                None
+            ),
+            "https://example.com/?a=1&utm_source=news&utm_medium=email"
+        );
+        let visitor = Some("utm_source=visitor");
+        assert_eq!(
+            build(
+                "https://example.com/",
+                UTM,
+                None,
+                visitor,
+                Some(QueryPassthrough::Merge)
+            ),
+            "https://example.com/?utm_source=news&utm_medium=email"
+        );
+        assert_eq!(
+            build(
+                "https://example.com/",
+                UTM,
+                None,
+                visitor,
+                Some(QueryPassthrough::Override)
+            ),
+            "https://example.com/?utm_medium=email&utm_source=visitor"
+        );
+    }
+
+    #[test]
+    fn appends_extra_path() {
+        assert_eq!(
+            build(
+                "https://example.com/docs",
+                &[],
+                Some("/guide/intro"),
+                None,
+                None
+            ),
+            "https://example.com/docs/guide/intro"
+        );
+        assert_eq!(
+            build("https://example.com/docs/?v=1", &[], Some("/a"), None, None),
+            "https://example.com/docs/a?v=1"
+        );
+        assert_eq!(
+            build("https://example.com", &[], Some("/a/"), None, None),
+            "https://example.com/a/"
+        );
+    }
+
+    #[test]
+    fn extra_path_stays_under_the_destination() {
+        for extra in [
+            "/../../admin",
+            "/./../admin",
+            "/%2e%2e/admin",
+            "/a/../../admin",
+        ] {
+            let url = build("https://example.com/docs/", &[], Some(extra), None, None);
+            assert!(
+                url.starts_with("https://example.com/docs/") && !url.contains("/../"),
+                "{} -> {}",
+                extra,
+                url
+            );
+        }
+        // Anything that would end the path is escaped into it
+        assert_eq!(
+            build("https://example.com/docs", &[], Some("/a?b#c"), None, None),
+            "https://example.com/docs/a%3Fb%23c"
+        );
+    }
+}
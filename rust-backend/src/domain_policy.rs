@@ -0,0 +1,433 @@
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::{
+    io::ErrorKind,
+    path::PathBuf,
+    sync::{Arc, Mutex, RwLock},
+};
+
+use crate::{
+    file_watch::{spawn_periodic, FileWatch},
+    interstitial::destination_host,
+};
+
+/// Which list an entry belongs to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PolicyList {
+    Block,
+    Allow,
+}
+
+impl PolicyList {
+    fn name(self) -> &'static str {
+        match self {
+            Self::Block => "block",
+            Self::Allow => "allow",
+        }
+    }
+}
+
+/// One line of the policy file: `block example.com`, `allow *.example.org` or
+/// `block /^login-.*\.example\.net$/`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PolicyEntry {
+    pub list: PolicyList,
+    pub pattern: String,
+}
+
+impl PolicyEntry {
+    fn parse_line(line: &str) -> Option<Result<Self, String>> {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            return None;
+        }
+        let (list, pattern) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+        let list = match list {
+            "block" => PolicyList::Block,
+            "allow" => PolicyList::Allow,
+            _ => {
+                return Some(Err(format!(
+                    "expected \"block\" or \"allow\", found \"{}\"",
+                    list
+                )))
+            }
+        };
+        Some(Ok(Self {
+            list,
+            pattern: pattern.trim().to_string(),
+        }))
+    }
+
+    pub fn line(&self) -> String {
+        format!("{} {}", self.list.name(), self.pattern)
+    }
+}
+
+enum Matcher {
+    // The domain itself
+    Exact(String),
+    // Any subdomain of the domain, but not the domain itself
+    Subdomains(String),
+    // Matched against the whole lowercase host name
+    Regex(Regex),
+}
+
+impl Matcher {
+    fn parse(pattern: &str) -> Result<Self, String> {
+        if let Some(regex) = pattern.strip_prefix('/').and_then(|p| p.strip_suffix('/')) {
+            return Regex::new(regex)
+                .map(Self::Regex)
+                .map_err(|e| format!("invalid regex: {}", e));
+        }
+        let pattern = pattern.trim_end_matches('.').to_ascii_lowercase();
+        let is_domain = |d: &str| {
+            !d.is_empty()
+                && d.split('.').all(|label| {
+                    !label.is_empty()
+                        && label
+                            .chars()
+                            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+                })
+        };
+        match pattern.strip_prefix("*.") {
+            Some(domain) if is_domain(domain) => Ok(Self::Subdomains(domain.to_string())),
+            None if is_domain(&pattern) => Ok(Self::Exact(pattern)),
+            _ => Err(format!(
+                "\"{}\" is not a domain, *.domain or /regex/",
+                pattern
+            )),
+        }
+    }
+
+    fn matches(&self, host: &str) -> bool {
+        match self {
+            Self::Exact(domain) => host == domain,
+            Self::Subdomains(domain) => host
+                .strip_suffix(domain.as_str())
+                .is_some_and(|rest| rest.ends_with('.')),
+            Self::Regex(regex) => regex.is_match(host),
+        }
+    }
+}
+
+#[derive(Default)]
+struct Rules {
+    entries: Vec<PolicyEntry>,
+    block: Vec<Matcher>,
+    allow: Vec<Matcher>,
+}
+
+impl Rules {
+    fn build(entries: Vec<PolicyEntry>) -> Result<Self, String> {
+        let mut rules = Self::default();
+        for entry in &entries {
+            let matcher = Matcher::parse(&entry.pattern)?;
+            match entry.list {
+                PolicyList::Block => rules.block.push(matcher),
+                PolicyList::Allow => rules.allow.push(matcher),
+            }
+        }
+        rules.entries = entries;
+        Ok(rules)
+    }
+}
+
+/// Why a destination isn't accepted.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Violation {
+    Blocked(String),
+    NotAllowed(String),
+    // No host name to find on the allowlist
+    NoDomain,
+}
+
+impl Violation {
+    pub fn message(&self) -> String {
+        match self {
+            Self::Blocked(host) => format!("Destination domain {} is blocked", host),
+            Self::NotAllowed(host) => {
+                format!("Destination domain {} is not on the allowlist", host)
+            }
+            Self::NoDomain => "Destinations need a domain on the allowlist".to_string(),
+        }
+    }
+}
+
+/// Domain blocklist and allowlist for link destinations, kept in a text file
+/// (`DOMAIN_POLICY_FILE`, default `data/domain_policy.txt`) that is re-read
+/// when it changes. Blocked domains always lose; once the allowlist has any
+/// entries, only domains on it are accepted.
+pub struct DomainPolicy {
+    file: FileWatch,
+    rules: RwLock<Rules>,
+    // Serialises edits made through the admin API
+    write_lock: Mutex<()>,
+}
+
+impl DomainPolicy {
+    pub fn from_env() -> Self {
+        let path = std::env::var("DOMAIN_POLICY_FILE")
+            .unwrap_or_else(|_| "data/domain_policy.txt".to_string());
+        Self::new(PathBuf::from(path))
+    }
+
+    /// The policy in `path`; a missing file is an empty policy.
+    pub fn new(path: PathBuf) -> Self {
+        let policy = Self {
+            file: FileWatch::new(path),
+            rules: RwLock::new(Rules::default()),
+            write_lock: Mutex::new(()),
+        };
+        policy.reload_if_changed();
+        policy
+    }
+
+    /// Checks a destination URL against both lists. URLs without a host name
+    /// can't be on the allowlist, so they only pass while it is empty.
+    pub fn check(&self, url: &str) -> Result<(), Violation> {
+        let rules = self.rules.read().unwrap();
+        let Some(host) = destination_host(url) else {
+            if rules.allow.is_empty() {
+                return Ok(());
+            }
+            return Err(Violation::NoDomain);
+        };
+        let host = host.trim_end_matches('.');
+
+        if rules.block.iter().any(|m| m.matches(host)) {
+            return Err(Violation::Blocked(host.to_string()));
+        }
+        if !rules.allow.is_empty() && !rules.allow.iter().any(|m| m.matches(host)) {
+            return Err(Violation::NotAllowed(host.to_string()));
+        }
+        Ok(())
+    }
+
+    pub fn entries(&self) -> Vec<PolicyEntry> {
+        self.rules.read().unwrap().entries.clone()
+    }
+
+    /// Re-reads the file now. Returns the number of entries loaded.
+    pub fn reload(&self) -> Result<usize, String> {
+        let entries = self.read_entries()?;
+        let rules = Rules::build(entries)?;
+        let count = rules.entries.len();
+        *self.rules.write().unwrap() = rules;
+        Ok(count)
+    }
+
+    /// Adds an entry, appending it to the file. Returns `false` if it was already listed.
+    pub fn add(&self, entry: PolicyEntry) -> Result<bool, String> {
+        Matcher::parse(&entry.pattern)?;
+        let _guard = self.write_lock.lock().unwrap();
+        let mut lines = self.read_lines()?;
+        let exists = lines.iter().any(|line| {
+            PolicyEntry::parse_line(line).and_then(Result::ok).as_ref() == Some(&entry)
+        });
+        if exists {
+            return Ok(false);
+        }
+        lines.push(entry.line());
+        self.write_lines(&lines)?;
+        self.reload()?;
+        Ok(true)
+    }
+
+    /// Removes an entry from the file, keeping comments and other lines as they
+    /// are. Returns `false` if it wasn't listed.
+    pub fn remove(&self, entry: &PolicyEntry) -> Result<bool, String> {
+        let _guard = self.write_lock.lock().unwrap();
+        let lines = self.read_lines()?;
+        let kept: Vec<String> = lines
+            .iter()
+            .filter(|line| {
+                PolicyEntry::parse_line(line).and_then(Result::ok).as_ref() != Some(entry)
+            })
+            .cloned()
+            .collect();
+        if kept.len() == lines.len() {
+            return Ok(false);
+        }
+        self.write_lines(&kept)?;
+        self.reload()?;
+        Ok(true)
+    }
+
+    /// Checks the file for changes every `DOMAIN_POLICY_RELOAD_SECS` (default 30).
+    pub fn spawn_reloader(self: Arc<Self>) {
+        spawn_periodic(
+            self,
+            "DOMAIN_POLICY_RELOAD_SECS",
+            30,
+            Self::reload_if_changed,
+        );
+    }
+
+    // A broken file keeps the previous rules in force
+    fn reload_if_changed(&self) {
+        let path = self.file.path().display();
+        match self.file.changed() {
+            Ok(true) => {}
+            Ok(false) => return,
+            Err(e) if e.kind() == ErrorKind::NotFound => return,
+            Err(e) => {
+                tracing::warn!("Domain policy {} unavailable: {}", path, e);
+                return;
+            }
+        }
+        match self.reload() {
+            Ok(count) => tracing::info!("Loaded {} domain policy entries from {}", count, path),
+            Err(e) => tracing::error!("Failed to load domain policy {}: {}", path, e),
+        }
+    }
+
+    fn read_lines(&self) -> Result<Vec<String>, String> {
+        match std::fs::read_to_string(self.file.path()) {
+            Ok(content) => Ok(content.lines().map(str::to_string).collect()),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(Vec::new()),
+            Err(e) => Err(format!("{}: {}", self.file.path().display(), e)),
+        }
+    }
+
+    fn read_entries(&self) -> Result<Vec<PolicyEntry>, String> {
+        self.read_lines()?
+            .iter()
+            .enumerate()
+            .filter_map(|(i, line)| {
+                PolicyEntry::parse_line(line)
+                    .map(|entry| entry.map_err(|e| format!("line {}: {}", i + 1, e)))
+            })
+            .collect()
+    }
+
+    fn write_lines(&self, lines: &[String]) -> Result<(), String> {
+        let path = self.file.path();
+        if let Some(dir) = path.parent().filter(|d| !d.as_os_str().is_empty()) {
+            std::fs::create_dir_all(dir).map_err(|e| format!("{}: {}", dir.display(), e))?;
+        }
+        let mut content = lines.join("\n");
+        content.push('\n');
+        std::fs::write(path, content).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(lines: &[&str]) -> DomainPolicy {
+        let entries = lines
+            .iter()
+            .map(|line| PolicyEntry::parse_line(line).unwrap().unwrap())
+            .collect();
+        DomainPolicy {
+            file: FileWatch::new(PathBuf::from("/nonexistent/domain_policy.txt")),
+            rules: RwLock::new(Rules::build(entries).unwrap()),
+            write_lock: Mutex::new(()),
+        }
+    }
+
+    #[test]
+    fn parses_lines() {
+        assert_eq!(PolicyEntry::parse_line("  # a comment"), None);
+        assert_eq!(PolicyEntry::parse_line(""), None);
+        assert_eq!(
+            PolicyEntry::parse_line("allow  *.example.org ").unwrap(),
+            Ok(PolicyEntry {
+                list: PolicyList::Allow,
+                pattern: "*.example.org".to_string(),
+            })
+        );
+        assert!(PolicyEntry::parse_line("deny example.com")
+            .unwrap()
+            .is_err());
+    }
+
+    #[test]
+    fn parses_patterns() {
+        assert!(Matcher::parse("Example.COM.").is_ok());
+        assert!(Matcher::parse("/^login-.*$/").is_ok());
+        for pattern in ["", "*.", "exa mple.com", "a..b", "*example.com", "/(/"] {
+            assert!(Matcher::parse(pattern).is_err(), "{:?} parsed", pattern);
+        }
+    }
+
+    #[test]
+    fn matches_hosts() {
+        let exact = Matcher::parse("Example.com.").unwrap();
+        assert!(exact.matches("example.com"));
+        assert!(!exact.matches("www.example.com"));
+        assert!(!exact.matches("notexample.com"));
+
+        let subdomains = Matcher::parse("*.example.com").unwrap();
+        assert!(subdomains.matches("www.example.com"));
+        assert!(subdomains.matches("a.b.example.com"));
+        assert!(!subdomains.matches("example.com"));
+        assert!(!subdomains.matches("badexample.com"));
+
+        let regex = Matcher::parse(r"/^login-.*\.example\.net$/").unwrap();
+        assert!(regex.matches("login-bank.example.net"));
+        assert!(!regex.matches("example.net"));
+    }
+
+    #[test]
+    fn block_wins_over_allow() {
+        let policy = policy(&["allow *.example.com", "block evil.example.com"]);
+        assert_eq!(policy.check("https://www.example.com/a"), Ok(()));
+        assert_eq!(
+            policy.check("https://evil.example.com/"),
+            Err(Violation::Blocked("evil.example.com".to_string()))
+        );
+        // Trailing dots and case don't get around either list
+        assert_eq!(
+            policy.check("https://EVIL.example.com./"),
+            Err(Violation::Blocked("evil.example.com".to_string()))
+        );
+        assert_eq!(
+            policy.check("https://example.com/"),
+            Err(Violation::NotAllowed("example.com".to_string()))
+        );
+    }
+
+    #[test]
+    fn hostless_destinations_need_an_empty_allowlist() {
+        let blocklist = policy(&["block example.com"]);
+        assert_eq!(blocklist.check("mailto:someone@example.org"), Ok(()));
+
+        let allowlist = policy(&["allow example.com"]);
+        for url in [
+            "data:text/html,hi",
+            "mailto:someone@example.com",
+            "not a url",
+        ] {
+            assert_eq!(allowlist.check(url), Err(Violation::NoDomain), "{}", url);
+        }
+    }
+
+    #[test]
+    fn edits_the_file() {
+        let path = std::env::temp_dir().join(format!(
+            "meoshorturl-policy-{}-{}.txt",
+            std::process::id(),
+            rand::random::<u32>()
+        ));
+        std::fs::write(&path, "# kept\nblock example.com\n").unwrap();
+        let policy = DomainPolicy::new(path.clone());
+        assert_eq!(policy.entries().len(), 1);
+
+        let entry = PolicyEntry {
+            list: PolicyList::Allow,
+            pattern: "*.example.org".to_string(),
+        };
+        assert_eq!(policy.add(entry.clone()), Ok(true));
+        assert_eq!(policy.add(entry.clone()), Ok(false));
+        assert_eq!(policy.entries().len(), 2);
+        assert_eq!(policy.remove(&entry), Ok(true));
+        assert_eq!(policy.remove(&entry), Ok(false));
+
+        let content = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(content, "# kept\nblock example.com\n");
+    }
+}
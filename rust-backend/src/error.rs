@@ -0,0 +1,203 @@
+use axum::{
+    async_trait,
+    extract::{rejection::JsonRejection, FromRequest, Request},
+    http::{HeaderValue, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+use rand::Rng;
+
+use crate::{domain_policy::Violation, models::ErrorResponse, threat_feed::ThreatMatch};
+
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+tokio::task_local! {
+    static REQUEST_ID: String;
+}
+
+/// An API error: the status, a stable machine-readable code clients can match
+/// on, a message for people, and the underlying cause, which is only logged.
+#[derive(Debug)]
+pub struct AppError {
+    status: StatusCode,
+    code: &'static str,
+    message: String,
+    cause: Option<String>,
+}
+
+pub type AppResult<T> = Result<T, AppError>;
+
+impl AppError {
+    pub fn new(status: StatusCode, code: &'static str, message: impl Into<String>) -> Self {
+        Self {
+            status,
+            code,
+            message: message.into(),
+            cause: None,
+        }
+    }
+
+    /// A 400 with a specific code, e.g. `invalid_url` or `invalid_slug`.
+    pub fn bad_request(code: &'static str, message: impl Into<String>) -> Self {
+        Self::new(StatusCode::BAD_REQUEST, code, message)
+    }
+
+    /// A 400 for input that doesn't have a more specific code.
+    pub fn invalid_input(message: impl Into<String>) -> Self {
+        Self::bad_request("invalid_input", message)
+    }
+
+    pub fn unauthorized() -> Self {
+        Self::new(StatusCode::UNAUTHORIZED, "unauthorized", "Unauthorized")
+    }
+
+    pub fn forbidden(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::FORBIDDEN, "forbidden", message)
+    }
+
+    pub fn not_found(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::NOT_FOUND, "not_found", message)
+    }
+
+    pub fn conflict(code: &'static str, message: impl Into<String>) -> Self {
+        Self::new(StatusCode::CONFLICT, code, message)
+    }
+
+    pub fn rate_limited(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::TOO_MANY_REQUESTS, "rate_limited", message)
+    }
+
+    /// A 500. The message is shown to the client, so keep details in the cause.
+    pub fn internal(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::INTERNAL_SERVER_ERROR, "internal_error", message)
+    }
+
+    pub fn with_cause(mut self, cause: impl std::fmt::Display) -> Self {
+        self.cause = Some(cause.to_string());
+        self
+    }
+}
+
+impl std::fmt::Display for AppError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl From<sqlx::Error> for AppError {
+    fn from(e: sqlx::Error) -> Self {
+        Self::internal("Database error").with_cause(e)
+    }
+}
+
+/// For `map_err`: a unique constraint violation becomes a 409 with `code`, and
+/// other database errors a 500.
+pub fn conflict_on_unique(
+    code: &'static str,
+    message: &'static str,
+) -> impl FnOnce(sqlx::Error) -> AppError {
+    move |e| match &e {
+        sqlx::Error::Database(db_err) if db_err.is_unique_violation() => {
+            AppError::conflict(code, message)
+        }
+        _ => e.into(),
+    }
+}
+
+impl From<Violation> for AppError {
+    fn from(violation: Violation) -> Self {
+        let code = match violation {
+            Violation::Blocked(_) => "domain_blocked",
+            Violation::NotAllowed(_) | Violation::NoDomain => "domain_not_allowed",
+        };
+        Self::bad_request(code, violation.message())
+    }
+}
+
+impl From<ThreatMatch> for AppError {
+    fn from(threat: ThreatMatch) -> Self {
+        Self::bad_request(
+            "destination_flagged",
+            format!("Destination rejected: {}", threat.message()),
+        )
+    }
+}
+
+impl IntoResponse for AppError {
+    fn into_response(self) -> Response {
+        let request_id = current_request_id();
+        let cause = self.cause.as_deref().unwrap_or(&self.message);
+        if self.status.is_server_error() {
+            tracing::error!(
+                request_id = request_id.as_deref().unwrap_or("-"),
+                code = self.code,
+                "{}",
+                cause
+            );
+        } else {
+            tracing::debug!(
+                request_id = request_id.as_deref().unwrap_or("-"),
+                code = self.code,
+                "{}",
+                cause
+            );
+        }
+
+        let body = ErrorResponse {
+            error: self.message,
+            code: self.code,
+            request_id,
+        };
+        (self.status, Json(body)).into_response()
+    }
+}
+
+/// `Json` for request bodies, rejecting malformed ones with an `AppError`
+/// (code `invalid_body`) instead of a plain text response.
+pub struct ApiJson<T>(pub T);
+
+#[async_trait]
+impl<S, T> FromRequest<S> for ApiJson<T>
+where
+    Json<T>: FromRequest<S, Rejection = JsonRejection>,
+    S: Send + Sync,
+{
+    type Rejection = AppError;
+
+    async fn from_request(request: Request, state: &S) -> Result<Self, Self::Rejection> {
+        match Json::<T>::from_request(request, state).await {
+            Ok(Json(value)) => Ok(Self(value)),
+            Err(rejection) => Err(AppError::new(
+                rejection.status(),
+                "invalid_body",
+                rejection.body_text(),
+            )),
+        }
+    }
+}
+
+/// The id of the request being handled, if called within `request_id_layer`.
+pub fn current_request_id() -> Option<String> {
+    REQUEST_ID.try_with(Clone::clone).ok()
+}
+
+/// Tags each request with an id, taken from an incoming `X-Request-Id` (e.g.
+/// set by a proxy) or generated, and echoes it in the response header.
+pub async fn request_id_layer(request: Request, next: Next) -> Response {
+    let request_id = request
+        .headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .filter(|id| !id.is_empty() && id.len() <= 128)
+        .map(str::to_string)
+        .unwrap_or_else(|| format!("{:016x}", rand::thread_rng().gen::<u64>()));
+
+    let mut response = REQUEST_ID
+        .scope(request_id.clone(), next.run(request))
+        .await;
+    if let Ok(value) = HeaderValue::from_str(&request_id) {
+        response.headers_mut().insert(REQUEST_ID_HEADER, value);
+    }
+    response
+}
@@ -0,0 +1,210 @@
+use chrono::{DateTime, Duration, SecondsFormat, SubsecRound, Utc};
+
+/// How link expiry times are accepted. With `MAX_LINK_LIFETIME` (a duration
+/// such as `90d`) set, every link must expire within that long, and links
+/// created without an expiry get the maximum.
+#[derive(Debug, Clone)]
+pub struct ExpiryPolicy {
+    pub max_lifetime: Option<Duration>,
+}
+
+impl ExpiryPolicy {
+    pub fn from_env() -> Self {
+        let max_lifetime = std::env::var("MAX_LINK_LIFETIME")
+            .ok()
+            .filter(|v| !v.is_empty())
+            .map(|v| {
+                parse_duration(&v)
+                    .filter(|&max| Utc::now().checked_add_signed(max).is_some())
+                    .unwrap_or_else(|| {
+                        panic!("MAX_LINK_LIFETIME must be a duration like 30d, got {}", v)
+                    })
+            });
+
+        Self { max_lifetime }
+    }
+
+    /// The expiry for a new link: `value` validated, or the maximum lifetime
+    /// when none was given.
+    pub fn for_new_link(&self, value: Option<&str>) -> Result<Option<DateTime<Utc>>, String> {
+        let now = Utc::now().trunc_subsecs(0);
+        match value.map(str::trim).filter(|v| !v.is_empty()) {
+            Some(value) => self.resolve(value, now).map(Some),
+            None => self.max_lifetime.map(|max| add(now, max)).transpose(),
+        }
+    }
+
+    /// The expiry for an edit, where `None` removes it.
+    pub fn for_update(&self, value: Option<&str>) -> Result<Option<DateTime<Utc>>, String> {
+        match value.map(str::trim).filter(|v| !v.is_empty()) {
+            Some(value) => self.resolve(value, Utc::now().trunc_subsecs(0)).map(Some),
+            None if self.max_lifetime.is_some() => {
+                Err("Links on this instance must have an expiry".to_string())
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn resolve(&self, value: &str, now: DateTime<Utc>) -> Result<DateTime<Utc>, String> {
+        let expiry = parse(value, now)?;
+        if expiry <= now {
+            return Err(format!("Expiry {} is in the past", format_utc(expiry)));
+        }
+        if let Some(max) = self.max_lifetime {
+            if expiry > add(now, max)? {
+                return Err(format!(
+                    "Expiry can be at most {} days from now",
+                    max.num_days()
+                ));
+            }
+        }
+        Ok(expiry)
+    }
+}
+
+/// Reads an expiry given as an RFC 3339 timestamp with an offset
+/// (`2026-01-31T18:00:00+07:00`) or as a duration from `now` (`30m`, `12h`,
+/// `7d`, `2w`).
+pub fn parse(value: &str, now: DateTime<Utc>) -> Result<DateTime<Utc>, String> {
+    let value = value.trim();
+    if let Some(duration) = parse_duration(value.strip_prefix('+').unwrap_or(value)) {
+        return add(now, duration);
+    }
+    DateTime::parse_from_rfc3339(value)
+        .map(|t| t.with_timezone(&Utc).trunc_subsecs(0))
+        .map_err(|_| {
+            format!(
+                "Invalid expiry \"{}\": use an RFC 3339 timestamp with an offset \
+                 (e.g. 2026-01-31T18:00:00Z) or a duration like 30m, 12h, 7d or 2w",
+                value
+            )
+        })
+}
+
+/// Reads a link's activation time, an RFC 3339 timestamp with an offset.
+pub fn parse_activation(value: &str) -> Option<DateTime<Utc>> {
+    DateTime::parse_from_rfc3339(value.trim())
+        .ok()
+        .map(|t| t.with_timezone(&Utc).trunc_subsecs(0))
+}
+
+fn add(time: DateTime<Utc>, duration: Duration) -> Result<DateTime<Utc>, String> {
+    time.checked_add_signed(duration)
+        .ok_or_else(|| "Expiry is too far in the future".to_string())
+}
+
+/// A whole number of minutes, hours, days or weeks, e.g. `7d`.
+pub fn parse_duration(value: &str) -> Option<Duration> {
+    let value = value.trim();
+    let unit_len = value.chars().last().map_or(0, char::len_utf8);
+    let (count, unit) = value.split_at(value.len() - unit_len);
+    let count: i64 = count.parse().ok().filter(|&n| n > 0)?;
+    match unit {
+        "m" => Duration::try_minutes(count),
+        "h" => Duration::try_hours(count),
+        "d" => Duration::try_days(count),
+        "w" => Duration::try_weeks(count),
+        _ => None,
+    }
+}
+
+/// The format expiry and activation times are stored in: UTC, whole seconds,
+/// `Z` suffix. Stored values sort and compare correctly as text.
+pub fn format_utc(time: DateTime<Utc>) -> String {
+    time.to_rfc3339_opts(SecondsFormat::Secs, true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn now() -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2026, 1, 1, 12, 0, 0).unwrap()
+    }
+
+    #[test]
+    fn durations() {
+        assert_eq!(parse_duration("30m"), Some(Duration::minutes(30)));
+        assert_eq!(parse_duration(" 12h "), Some(Duration::hours(12)));
+        assert_eq!(parse_duration("7d"), Some(Duration::days(7)));
+        assert_eq!(parse_duration("2w"), Some(Duration::weeks(2)));
+        for invalid in [
+            "",
+            "d",
+            "0d",
+            "-1d",
+            "1.5h",
+            "7",
+            "7y",
+            "7 d",
+            "99999999999999999999d",
+        ] {
+            assert_eq!(parse_duration(invalid), None, "{}", invalid);
+        }
+        // Too long to be a `Duration` at all
+        assert_eq!(parse_duration("9223372036854775807w"), None);
+    }
+
+    #[test]
+    fn relative_and_absolute_expiry() {
+        assert_eq!(
+            parse("7d", now()),
+            Ok(Utc.with_ymd_and_hms(2026, 1, 8, 12, 0, 0).unwrap())
+        );
+        assert_eq!(
+            parse("+90m", now()),
+            Ok(Utc.with_ymd_and_hms(2026, 1, 1, 13, 30, 0).unwrap())
+        );
+        // Offsets are converted to UTC and fractions of a second dropped
+        assert_eq!(
+            parse("2026-01-31T18:00:00.750+07:00", now()),
+            Ok(Utc.with_ymd_and_hms(2026, 1, 31, 11, 0, 0).unwrap())
+        );
+        for invalid in ["tomorrow", "2026-01-31", "2026-01-31 18:00:00", "7y"] {
+            assert!(parse(invalid, now()).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn overflowing_expiry_is_an_error() {
+        assert!(parse("99999999d", now()).is_err());
+        assert!(parse("9999999999999w", now()).is_err());
+
+        let policy = ExpiryPolicy {
+            max_lifetime: Some(Duration::days(99999999)),
+        };
+        assert!(policy.for_new_link(None).is_err());
+        assert!(policy.for_new_link(Some("1d")).is_err());
+    }
+
+    #[test]
+    fn max_lifetime() {
+        let policy = ExpiryPolicy {
+            max_lifetime: Some(Duration::days(30)),
+        };
+        assert_eq!(policy.resolve("30d", now()), Ok(now() + Duration::days(30)));
+        assert!(policy.resolve("31d", now()).is_err());
+        assert!(policy.resolve("2025-12-31T00:00:00Z", now()).is_err());
+        assert!(policy.for_new_link(None).unwrap().is_some());
+        assert!(policy.for_update(None).is_err());
+
+        let unlimited = ExpiryPolicy { max_lifetime: None };
+        assert_eq!(unlimited.for_new_link(Some(" ")), Ok(None));
+        assert_eq!(unlimited.for_update(None), Ok(None));
+        assert!(unlimited.resolve("5200w", now()).is_ok());
+    }
+
+    #[test]
+    fn activation_times() {
+        assert_eq!(
+            parse_activation(" 2026-01-31T18:00:00-05:00 "),
+            Some(Utc.with_ymd_and_hms(2026, 1, 31, 23, 0, 0).unwrap())
+        );
+        assert_eq!(parse_activation("7d"), None);
+        assert_eq!(
+            format_utc(Utc.with_ymd_and_hms(2026, 1, 31, 23, 0, 0).unwrap()),
+            "2026-01-31T23:00:00Z"
+        );
+    }
+}
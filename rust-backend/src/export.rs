@@ -0,0 +1,315 @@
+use serde::Serialize;
+use tokio::sync::mpsc;
+
+use crate::{db::Database, models::UrlFilter};
+
+// Chunks buffered between the database reader and the response body
+const CHANNEL_CAPACITY: usize = 64;
+
+pub type ExportChunk = Result<Vec<u8>, std::io::Error>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExportFormat {
+    Csv,
+    Json,
+    Ndjson,
+}
+
+impl ExportFormat {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.to_ascii_lowercase().as_str() {
+            "csv" => Some(Self::Csv),
+            "json" => Some(Self::Json),
+            "ndjson" | "jsonl" => Some(Self::Ndjson),
+            _ => None,
+        }
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Self::Csv => "text/csv; charset=utf-8",
+            Self::Json => "application/json",
+            Self::Ndjson => "application/x-ndjson",
+        }
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            Self::Csv => "csv",
+            Self::Json => "json",
+            Self::Ndjson => "ndjson",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExportKind {
+    Links,
+    Clicks,
+}
+
+impl ExportKind {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "links" => Some(Self::Links),
+            "clicks" => Some(Self::Clicks),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Links => "links",
+            Self::Clicks => "clicks",
+        }
+    }
+}
+
+// Turns rows into bytes one at a time so nothing but the current row is held in memory
+struct Encoder {
+    format: ExportFormat,
+    rows: usize,
+}
+
+impl Encoder {
+    fn new(format: ExportFormat) -> Self {
+        Self { format, rows: 0 }
+    }
+
+    fn row<T: Serialize>(&mut self, row: &T) -> std::io::Result<Vec<u8>> {
+        let first = self.rows == 0;
+        self.rows += 1;
+
+        match self.format {
+            ExportFormat::Csv => {
+                // The header is derived from the first row's field names
+                let mut writer = csv::WriterBuilder::new()
+                    .has_headers(first)
+                    .from_writer(Vec::new());
+                writer.serialize(row).map_err(std::io::Error::other)?;
+                writer
+                    .into_inner()
+                    .map_err(|e| std::io::Error::other(e.to_string()))
+            }
+            ExportFormat::Json => {
+                let mut out = if first {
+                    b"[\n".to_vec()
+                } else {
+                    b",\n".to_vec()
+                };
+                serde_json::to_writer(&mut out, row)?;
+                Ok(out)
+            }
+            ExportFormat::Ndjson => {
+                let mut out = serde_json::to_vec(row)?;
+                out.push(b'\n');
+                Ok(out)
+            }
+        }
+    }
+
+    fn finish(&self) -> Vec<u8> {
+        match self.format {
+            ExportFormat::Json if self.rows == 0 => b"[]\n".to_vec(),
+            ExportFormat::Json => b"\n]\n".to_vec(),
+            _ => Vec::new(),
+        }
+    }
+}
+
+/// Starts a background export and returns the receiving end of its output.
+/// The channel is bounded, so a slow consumer throttles the database reader.
+pub fn spawn_export(
+    db: Database,
+    kind: ExportKind,
+    format: ExportFormat,
+    filter: UrlFilter,
+) -> mpsc::Receiver<ExportChunk> {
+    let (tx, rx) = mpsc::channel(CHANNEL_CAPACITY);
+
+    tokio::spawn(async move {
+        let mut encoder = Encoder::new(format);
+
+        let result = match kind {
+            ExportKind::Links => {
+                db.for_each_url(&filter, |row| send_row(&tx, encoder.row(&row)))
+                    .await
+            }
+            ExportKind::Clicks => {
+                db.for_each_click(&filter, |row| send_row(&tx, encoder.row(&row)))
+                    .await
+            }
+        };
+
+        match result {
+            Ok(()) => {
+                let _ = tx.send(Ok(encoder.finish())).await;
+            }
+            Err(e) => {
+                tracing::error!("Export of {} failed: {}", kind.name(), e);
+                let _ = tx.send(Err(std::io::Error::other(e))).await;
+            }
+        }
+    });
+
+    rx
+}
+
+fn send_row(
+    tx: &mpsc::Sender<ExportChunk>,
+    chunk: ExportChunk,
+) -> impl std::future::Future<Output = bool> {
+    let tx = tx.clone();
+    async move {
+        let failed = chunk.is_err();
+        // A closed channel means the client went away
+        tx.send(chunk).await.is_ok() && !failed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{db::testing::TempDb, models::NewUrl};
+
+    #[derive(Serialize)]
+    struct Row {
+        slug: &'static str,
+        note: Option<&'static str>,
+    }
+
+    fn encode(format: ExportFormat, rows: &[Row]) -> String {
+        let mut encoder = Encoder::new(format);
+        let mut out = Vec::new();
+        for row in rows {
+            out.extend(encoder.row(row).unwrap());
+        }
+        out.extend(encoder.finish());
+        String::from_utf8(out).unwrap()
+    }
+
+    fn rows(n: usize) -> Vec<Row> {
+        ["a", "b", "c"][..n]
+            .iter()
+            .map(|&slug| Row { slug, note: None })
+            .collect()
+    }
+
+    async fn collect(mut rx: mpsc::Receiver<ExportChunk>) -> String {
+        let mut out = Vec::new();
+        while let Some(chunk) = rx.recv().await {
+            out.extend(chunk.unwrap());
+        }
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn csv_has_one_header_and_escapes_fields() {
+        let rows = [
+            Row {
+                slug: "plain",
+                note: None,
+            },
+            Row {
+                slug: "comma",
+                note: Some("a, b"),
+            },
+            Row {
+                slug: "quote",
+                note: Some("say \"hi\""),
+            },
+            Row {
+                slug: "lines",
+                note: Some("one\ntwo"),
+            },
+        ];
+        assert_eq!(
+            encode(ExportFormat::Csv, &rows),
+            "slug,note\nplain,\ncomma,\"a, b\"\nquote,\"say \"\"hi\"\"\"\nlines,\"one\ntwo\"\n"
+        );
+        assert_eq!(encode(ExportFormat::Csv, &[]), "");
+    }
+
+    #[test]
+    fn json_is_always_one_array() {
+        assert_eq!(encode(ExportFormat::Json, &rows(0)), "[]\n");
+        assert_eq!(
+            encode(ExportFormat::Json, &rows(1)),
+            "[\n{\"slug\":\"a\",\"note\":null}\n]\n"
+        );
+        let many: serde_json::Value =
+            serde_json::from_str(&encode(ExportFormat::Json, &rows(3))).unwrap();
+        let slugs: Vec<_> = many
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|row| row["slug"].as_str().unwrap())
+            .collect();
+        assert_eq!(slugs, ["a", "b", "c"]);
+    }
+
+    #[test]
+    fn ndjson_writes_a_line_per_row() {
+        assert_eq!(encode(ExportFormat::Ndjson, &rows(0)), "");
+        assert_eq!(
+            encode(ExportFormat::Ndjson, &rows(2)),
+            "{\"slug\":\"a\",\"note\":null}\n{\"slug\":\"b\",\"note\":null}\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn streams_more_rows_than_the_channel_holds() {
+        let db = TempDb::new().await;
+        let total = CHANNEL_CAPACITY * 2 + 1;
+        for i in 0..total {
+            let id = db
+                .insert_url(&NewUrl {
+                    slug: format!("link-{}", i),
+                    original_url: format!("https://example.com/{}", i),
+                    ..Default::default()
+                })
+                .await
+                .unwrap();
+            db.insert_click_event(id, None, Some("curl/8.5.0"), None)
+                .await
+                .unwrap();
+        }
+
+        let links = collect(spawn_export(
+            (*db).clone(),
+            ExportKind::Links,
+            ExportFormat::Ndjson,
+            UrlFilter::default(),
+        ))
+        .await;
+        assert_eq!(links.lines().count(), total);
+
+        let clicks = collect(spawn_export(
+            (*db).clone(),
+            ExportKind::Clicks,
+            ExportFormat::Csv,
+            UrlFilter::default(),
+        ))
+        .await;
+        let mut lines = clicks.lines();
+        assert!(lines.next().unwrap().starts_with("id,url_id,slug,"));
+        assert_eq!(lines.count(), total);
+
+        // Filters apply to the stream the same way they do to the list endpoint
+        let filtered = collect(spawn_export(
+            (*db).clone(),
+            ExportKind::Links,
+            ExportFormat::Json,
+            UrlFilter {
+                q: Some("link-10".to_string()),
+                ..Default::default()
+            },
+        ))
+        .await;
+        let filtered: Vec<serde_json::Value> = serde_json::from_str(&filtered).unwrap();
+        assert!(filtered
+            .iter()
+            .all(|row| row["slug"].as_str().unwrap().starts_with("link-10")));
+        assert!(!filtered.is_empty());
+    }
+}
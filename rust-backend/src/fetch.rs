@@ -0,0 +1,413 @@
+use reqwest::{redirect::Policy, Method, Url};
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::Duration,
+};
+
+const MAX_REDIRECTS: usize = 5;
+const USER_AGENT: &str = "meoShortURL/0.1";
+
+/// Limits for outgoing requests to user-supplied destinations.
+#[derive(Debug, Clone)]
+pub struct FetchConfig {
+    pub timeout: Duration,
+    pub max_bytes: usize,
+    // Only meant for tests against a local server
+    pub allow_private: bool,
+}
+
+impl FetchConfig {
+    pub fn from_env() -> Self {
+        let timeout_secs = std::env::var("FETCH_TIMEOUT_SECS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(5);
+        let max_bytes = std::env::var("FETCH_MAX_BYTES")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(512 * 1024);
+        let allow_private = std::env::var("FETCH_ALLOW_PRIVATE")
+            .map(|v| v == "true" || v == "1")
+            .unwrap_or(false);
+
+        Self {
+            timeout: Duration::from_secs(timeout_secs),
+            max_bytes,
+            allow_private,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct FetchedPage {
+    pub status: u16,
+    pub content_type: Option<String>,
+    // Truncated to `max_bytes`; always empty for HEAD requests
+    pub body: Vec<u8>,
+    // Every URL requested, starting with the original and ending with the one
+    // that answered
+    pub chain: Vec<String>,
+}
+
+#[derive(Debug)]
+pub enum FetchError {
+    InvalidUrl,
+    BlockedAddress,
+    TooManyRedirects,
+    Timeout,
+    Request(reqwest::Error),
+}
+
+impl std::fmt::Display for FetchError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::InvalidUrl => f.write_str("invalid or unsupported URL"),
+            Self::BlockedAddress => f.write_str("destination resolves to a private address"),
+            Self::TooManyRedirects => f.write_str("too many redirects"),
+            Self::Timeout => f.write_str("timed out"),
+            Self::Request(e) => write!(f, "request failed: {}", e),
+        }
+    }
+}
+
+/// Requests `url`, following redirects manually so every hop is checked against
+/// private address ranges. The resolved address is pinned for the connection,
+/// so a second DNS lookup can't swap in an internal host.
+pub async fn fetch(
+    config: &FetchConfig,
+    method: Method,
+    url: &str,
+) -> Result<FetchedPage, FetchError> {
+    tokio::time::timeout(config.timeout, fetch_inner(config, method, url))
+        .await
+        .map_err(|_| FetchError::Timeout)?
+}
+
+async fn fetch_inner(
+    config: &FetchConfig,
+    method: Method,
+    url: &str,
+) -> Result<FetchedPage, FetchError> {
+    let mut url = Url::parse(url).map_err(|_| FetchError::InvalidUrl)?;
+    let mut redirects = 0;
+    let mut chain = vec![url.to_string()];
+
+    loop {
+        let response = request_once(config, method.clone(), &url).await?;
+
+        if response.status().is_redirection() {
+            if let Some(location) = response
+                .headers()
+                .get(reqwest::header::LOCATION)
+                .and_then(|v| v.to_str().ok())
+            {
+                if redirects >= MAX_REDIRECTS {
+                    return Err(FetchError::TooManyRedirects);
+                }
+                url = url.join(location).map_err(|_| FetchError::InvalidUrl)?;
+                chain.push(url.to_string());
+                redirects += 1;
+                continue;
+            }
+        }
+
+        let status = response.status().as_u16();
+        let content_type = response
+            .headers()
+            .get(reqwest::header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .map(|v| v.to_string());
+
+        let mut body = Vec::new();
+        if method != Method::HEAD {
+            let mut response = response;
+            while body.len() < config.max_bytes {
+                match response.chunk().await.map_err(FetchError::Request)? {
+                    Some(chunk) => body.extend_from_slice(&chunk),
+                    None => break,
+                }
+            }
+            body.truncate(config.max_bytes);
+        }
+
+        return Ok(FetchedPage {
+            status,
+            content_type,
+            body,
+            chain,
+        });
+    }
+}
+
+async fn request_once(
+    config: &FetchConfig,
+    method: Method,
+    url: &Url,
+) -> Result<reqwest::Response, FetchError> {
+    if !matches!(url.scheme(), "http" | "https") {
+        return Err(FetchError::InvalidUrl);
+    }
+    let host = url.host_str().ok_or(FetchError::InvalidUrl)?;
+    let port = url.port_or_known_default().ok_or(FetchError::InvalidUrl)?;
+
+    // Reject the host if any of its addresses is internal
+    let host_for_lookup = host.trim_start_matches('[').trim_end_matches(']');
+    let addrs: Vec<SocketAddr> = tokio::net::lookup_host((host_for_lookup, port))
+        .await
+        .map_err(|_| FetchError::InvalidUrl)?
+        .collect();
+    let addr = *addrs.first().ok_or(FetchError::InvalidUrl)?;
+    if !config.allow_private && addrs.iter().any(|a| !is_public_ip(a.ip())) {
+        return Err(FetchError::BlockedAddress);
+    }
+
+    let client = reqwest::Client::builder()
+        .redirect(Policy::none())
+        .resolve(host_for_lookup, addr)
+        .user_agent(USER_AGENT)
+        .build()
+        .map_err(FetchError::Request)?;
+
+    client
+        .request(method, url.clone())
+        .send()
+        .await
+        .map_err(FetchError::Request)
+}
+
+/// Whether an address is routable on the public internet. IPv6 addresses that
+/// embed an IPv4 address (mapped, compatible, NAT64 and 6to4) are judged by
+/// that address.
+pub fn is_public_ip(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(v4) => {
+            let [a, b, c, _] = v4.octets();
+            !(v4.is_private()
+                || v4.is_loopback()
+                || v4.is_link_local()
+                || v4.is_unspecified()
+                || v4.is_broadcast()
+                || v4.is_multicast()
+                || v4.is_documentation()
+                || a == 0
+                // Carrier-grade NAT, 100.64.0.0/10
+                || (a == 100 && (64..128).contains(&b))
+                // IETF protocol assignments, 192.0.0.0/24
+                || (a == 192 && b == 0 && c == 0)
+                // Benchmarking, 198.18.0.0/15
+                || (a == 198 && (b & 0xfe) == 18)
+                // Reserved, 240.0.0.0/4
+                || a >= 240)
+        }
+        IpAddr::V6(v6) => {
+            if let Some(v4) = embedded_ipv4(v6) {
+                return is_public_ip(IpAddr::V4(v4));
+            }
+            let segments = v6.segments();
+            let first = segments[0];
+            !(v6.is_loopback()
+                || v6.is_unspecified()
+                || v6.is_multicast()
+                // Unique local, fc00::/7
+                || (first & 0xfe00) == 0xfc00
+                // Link local, fe80::/10
+                || (first & 0xffc0) == 0xfe80
+                // Local-use NAT64, 64:ff9b:1::/48
+                || (first == 0x64 && segments[1] == 0xff9b)
+                // Teredo, 2001::/32, which can tunnel to any IPv4 address
+                || (first == 0x2001 && segments[1] == 0)
+                // Documentation, 2001:db8::/32
+                || (first == 0x2001 && segments[1] == 0xdb8))
+        }
+    }
+}
+
+// The IPv4 address in an IPv4-mapped (::ffff:a.b.c.d), IPv4-compatible
+// (::a.b.c.d), NAT64 (64:ff9b::/96) or 6to4 (2002::/16) address
+fn embedded_ipv4(v6: Ipv6Addr) -> Option<Ipv4Addr> {
+    if let Some(v4) = v6.to_ipv4_mapped() {
+        return Some(v4);
+    }
+    let segments = v6.segments();
+    let octets = v6.octets();
+    let last = Ipv4Addr::new(octets[12], octets[13], octets[14], octets[15]);
+    match segments {
+        // :: and ::1 are handled as themselves
+        [0, 0, 0, 0, 0, 0, _, _] if !v6.is_loopback() && !v6.is_unspecified() => Some(last),
+        [0x64, 0xff9b, 0, 0, 0, 0, _, _] => Some(last),
+        [0x2002, ..] => Some(Ipv4Addr::new(octets[2], octets[3], octets[4], octets[5])),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{
+        http::{header, StatusCode},
+        response::{IntoResponse, Redirect},
+        routing::get,
+        Router,
+    };
+
+    fn ip(value: &str) -> IpAddr {
+        value.parse().unwrap()
+    }
+
+    fn local_config() -> FetchConfig {
+        FetchConfig {
+            timeout: Duration::from_secs(2),
+            max_bytes: 64,
+            allow_private: true,
+        }
+    }
+
+    // Serves a few pages on a random local port and returns its base URL
+    async fn serve() -> String {
+        let app = Router::new()
+            .route(
+                "/page",
+                get(|| async { ([(header::CONTENT_TYPE, "text/html")], "<title>Hi</title>") }),
+            )
+            .route("/big", get(|| async { "x".repeat(1000) }))
+            .route("/hop", get(|| async { Redirect::temporary("/page") }))
+            .route("/loop", get(|| async { Redirect::temporary("/loop") }))
+            .route(
+                "/slow",
+                get(|| async {
+                    tokio::time::sleep(Duration::from_secs(10)).await;
+                    StatusCode::OK.into_response()
+                }),
+            );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        format!("http://{}", addr)
+    }
+
+    #[test]
+    fn public_ipv4() {
+        assert!(is_public_ip(ip("93.184.216.34")));
+        for private in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "100.64.0.1",
+            "0.1.2.3",
+            "192.0.0.8",
+            "198.18.0.1",
+            "198.19.255.255",
+            "240.0.0.1",
+            "255.255.255.255",
+        ] {
+            assert!(!is_public_ip(ip(private)), "{}", private);
+        }
+    }
+
+    #[test]
+    fn ipv6_embedding_ipv4_is_judged_by_it() {
+        for private in [
+            "::ffff:127.0.0.1",
+            "::127.0.0.1",
+            "::10.0.0.1",
+            "64:ff9b::a9fe:a9fe",
+            "64:ff9b::127.0.0.1",
+            "2002:7f00:1::",
+            "2002:c0a8:101::1",
+        ] {
+            assert!(!is_public_ip(ip(private)), "{}", private);
+        }
+        assert!(is_public_ip(ip("64:ff9b::5db8:d822")));
+        assert!(is_public_ip(ip("2002:5db8:d822::1")));
+    }
+
+    #[test]
+    fn public_ipv6() {
+        assert!(is_public_ip(ip("2606:4700::1111")));
+        for private in [
+            "::1",
+            "::",
+            "fc00::1",
+            "fe80::1",
+            "ff02::1",
+            "64:ff9b:1::1",
+            "2001::1",
+            "2001:db8::1",
+        ] {
+            assert!(!is_public_ip(ip(private)), "{}", private);
+        }
+    }
+
+    #[tokio::test]
+    async fn follows_redirects_and_records_the_chain() {
+        let base = serve().await;
+        let page = fetch(&local_config(), Method::GET, &format!("{}/hop", base))
+            .await
+            .unwrap();
+        assert_eq!(page.status, 200);
+        assert_eq!(page.content_type.as_deref(), Some("text/html"));
+        assert_eq!(page.body, b"<title>Hi</title>");
+        assert_eq!(
+            page.chain,
+            vec![format!("{}/hop", base), format!("{}/page", base)]
+        );
+    }
+
+    #[tokio::test]
+    async fn head_requests_have_no_body() {
+        let base = serve().await;
+        let page = fetch(&local_config(), Method::HEAD, &format!("{}/page", base))
+            .await
+            .unwrap();
+        assert_eq!(page.status, 200);
+        assert!(page.body.is_empty());
+    }
+
+    #[tokio::test]
+    async fn truncates_large_bodies() {
+        let base = serve().await;
+        let page = fetch(&local_config(), Method::GET, &format!("{}/big", base))
+            .await
+            .unwrap();
+        assert_eq!(page.body.len(), 64);
+    }
+
+    #[tokio::test]
+    async fn stops_redirect_loops() {
+        let base = serve().await;
+        let result = fetch(&local_config(), Method::GET, &format!("{}/loop", base)).await;
+        assert!(matches!(result, Err(FetchError::TooManyRedirects)));
+    }
+
+    #[tokio::test]
+    async fn times_out() {
+        let base = serve().await;
+        let config = FetchConfig {
+            timeout: Duration::from_millis(200),
+            ..local_config()
+        };
+        let result = fetch(&config, Method::GET, &format!("{}/slow", base)).await;
+        assert!(matches!(result, Err(FetchError::Timeout)));
+    }
+
+    #[tokio::test]
+    async fn refuses_private_addresses() {
+        let base = serve().await;
+        let config = FetchConfig {
+            allow_private: false,
+            ..local_config()
+        };
+        let result = fetch(&config, Method::GET, &format!("{}/page", base)).await;
+        assert!(matches!(result, Err(FetchError::BlockedAddress)));
+        let result = fetch(&config, Method::GET, "http://[::ffff:127.0.0.1]/").await;
+        assert!(matches!(result, Err(FetchError::BlockedAddress)));
+    }
+
+    #[tokio::test]
+    async fn rejects_other_schemes() {
+        let result = fetch(&local_config(), Method::GET, "file:///etc/passwd").await;
+        assert!(matches!(result, Err(FetchError::InvalidUrl)));
+    }
+}
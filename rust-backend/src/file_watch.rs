@@ -0,0 +1,65 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
+
+/// Tracks a file's modification time so data loaded from it can be refreshed
+/// when it is replaced on disk.
+pub struct FileWatch {
+    path: PathBuf,
+    modified: Mutex<Option<SystemTime>>,
+}
+
+impl FileWatch {
+    pub fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            modified: Mutex::new(None),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Whether the file changed since the last time this returned `true`.
+    pub fn changed(&self) -> std::io::Result<bool> {
+        let modified = std::fs::metadata(&self.path)?.modified()?;
+        let mut last = self.modified.lock().unwrap();
+        if *last == Some(modified) {
+            return Ok(false);
+        }
+        *last = Some(modified);
+        Ok(true)
+    }
+
+    /// Forgets the last seen modification time, so the next check reloads.
+    pub fn reset(&self) {
+        *self.modified.lock().unwrap() = None;
+    }
+}
+
+/// Runs `check` on the blocking pool every `env_var` seconds (`default_secs` when
+/// unset), skipping the first immediate tick.
+pub fn spawn_periodic<T, F>(target: Arc<T>, env_var: &str, default_secs: u64, check: F)
+where
+    T: Send + Sync + 'static,
+    F: Fn(&T) + Send + Sync + Copy + 'static,
+{
+    let secs = std::env::var(env_var)
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .filter(|&secs| secs > 0)
+        .unwrap_or(default_secs);
+
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(secs));
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            let target = target.clone();
+            let _ = tokio::task::spawn_blocking(move || check(&target)).await;
+        }
+    });
+}
@@ -0,0 +1,197 @@
+use maxminddb::{geoip2, Reader};
+use std::{
+    net::IpAddr,
+    path::PathBuf,
+    sync::{Arc, RwLock},
+};
+
+use crate::file_watch::{spawn_periodic, FileWatch};
+
+/// Country lookups against a local MaxMind-format database (GeoLite2 Country or
+/// City). The file is re-read whenever its modification time changes, so it can
+/// be updated in place without a restart.
+pub struct GeoIp {
+    file: Option<FileWatch>,
+    reader: RwLock<Option<Arc<Reader<Vec<u8>>>>>,
+}
+
+impl GeoIp {
+    pub fn from_env() -> Self {
+        Self::new(std::env::var("GEOIP_DB_PATH").ok().map(PathBuf::from))
+    }
+
+    /// Loads the database at `path` right away; without one every lookup misses.
+    pub fn new(path: Option<PathBuf>) -> Self {
+        let geoip = Self {
+            file: path.map(FileWatch::new),
+            reader: RwLock::new(None),
+        };
+        geoip.reload_if_changed();
+        geoip
+    }
+
+    /// The ISO 3166-1 alpha-2 code of the country `ip` belongs to.
+    pub fn country(&self, ip: IpAddr) -> Option<String> {
+        let reader = self.reader.read().unwrap().clone()?;
+        let country: geoip2::Country = reader.lookup(ip).ok()?;
+        country
+            .country
+            .and_then(|c| c.iso_code)
+            .map(|code| code.to_ascii_uppercase())
+    }
+
+    // Keeps serving the previous database if the new file can't be read
+    fn reload_if_changed(&self) {
+        let Some(file) = &self.file else {
+            return;
+        };
+        let path = file.path().display();
+        match file.changed() {
+            Ok(true) => {}
+            Ok(false) => return,
+            Err(e) => {
+                tracing::warn!("GeoIP database {} unavailable: {}", path, e);
+                return;
+            }
+        }
+
+        match Reader::open_readfile(file.path()) {
+            Ok(reader) => {
+                *self.reader.write().unwrap() = Some(Arc::new(reader));
+                tracing::info!("Loaded GeoIP database {}", path);
+            }
+            Err(e) => {
+                // Try again on the next check rather than waiting for another change
+                file.reset();
+                tracing::error!("Failed to load GeoIP database {}: {}", path, e);
+            }
+        }
+    }
+
+    /// Checks the database file for changes every `GEOIP_RELOAD_SECS` (default 60).
+    pub fn spawn_reloader(self: Arc<Self>) {
+        if self.file.is_some() {
+            spawn_periodic(self, "GEOIP_RELOAD_SECS", 60, Self::reload_if_changed);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, SystemTime};
+
+    fn string(out: &mut Vec<u8>, value: &str) {
+        out.push(0x40 | value.len() as u8);
+        out.extend(value.as_bytes());
+    }
+
+    fn uint(out: &mut Vec<u8>, control: u8, value: u64, bytes: usize) {
+        out.push(control | bytes as u8);
+        out.extend(&value.to_be_bytes()[8 - bytes..]);
+    }
+
+    // A minimal IPv4 MaxMind DB mapping the /8 starting with `first_octet` to `country`
+    fn mmdb(first_octet: u8, country: &str) -> Vec<u8> {
+        const NODES: u32 = 8;
+        let mut out = Vec::new();
+        // One node per bit of the prefix; the other branch of each has no data
+        for depth in 0..NODES {
+            let next = if depth + 1 == NODES {
+                NODES + 16
+            } else {
+                depth + 1
+            };
+            let (left, right) = if first_octet >> (7 - depth) & 1 == 0 {
+                (next, NODES)
+            } else {
+                (NODES, next)
+            };
+            out.extend(&left.to_be_bytes()[1..]);
+            out.extend(&right.to_be_bytes()[1..]);
+        }
+        out.extend([0; 16]);
+
+        // {"country": {"iso_code": country}}
+        out.push(0xE1);
+        string(&mut out, "country");
+        out.push(0xE1);
+        string(&mut out, "iso_code");
+        string(&mut out, country);
+
+        out.extend(b"\xAB\xCD\xEFMaxMind.com");
+        out.push(0xE9);
+        string(&mut out, "binary_format_major_version");
+        uint(&mut out, 0xA0, 2, 1);
+        string(&mut out, "binary_format_minor_version");
+        uint(&mut out, 0xA0, 0, 0);
+        string(&mut out, "build_epoch");
+        out.extend([0x08, 0x02]);
+        out.extend(0u64.to_be_bytes());
+        string(&mut out, "database_type");
+        string(&mut out, "Test-Country");
+        string(&mut out, "description");
+        out.push(0xE0);
+        string(&mut out, "ip_version");
+        uint(&mut out, 0xA0, 4, 1);
+        string(&mut out, "languages");
+        out.extend([0x00, 0x04]);
+        string(&mut out, "node_count");
+        uint(&mut out, 0xC0, NODES as u64, 1);
+        string(&mut out, "record_size");
+        uint(&mut out, 0xA0, 24, 1);
+        out
+    }
+
+    fn write(path: &std::path::Path, contents: &[u8], modified: SystemTime) {
+        std::fs::write(path, contents).unwrap();
+        std::fs::File::options()
+            .write(true)
+            .open(path)
+            .unwrap()
+            .set_modified(modified)
+            .unwrap();
+    }
+
+    #[test]
+    fn looks_up_countries_and_reloads_in_place() {
+        let path = std::env::temp_dir().join(format!(
+            "meoshorturl-geoip-{:016x}.mmdb",
+            rand::random::<u64>()
+        ));
+        let then = SystemTime::now() - Duration::from_secs(60);
+        write(&path, &mmdb(81, "de"), then);
+
+        let geoip = GeoIp::new(Some(path.clone()));
+        assert_eq!(
+            geoip.country("81.2.69.160".parse().unwrap()).as_deref(),
+            Some("DE")
+        );
+        assert_eq!(geoip.country("82.2.69.160".parse().unwrap()), None);
+        assert_eq!(geoip.country("::1".parse().unwrap()), None);
+
+        // A broken replacement keeps the previous database in service
+        write(&path, b"not a database", then + Duration::from_secs(1));
+        geoip.reload_if_changed();
+        assert_eq!(
+            geoip.country("81.2.69.160".parse().unwrap()).as_deref(),
+            Some("DE")
+        );
+
+        write(&path, &mmdb(82, "GB"), then + Duration::from_secs(2));
+        geoip.reload_if_changed();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(geoip.country("81.2.69.160".parse().unwrap()), None);
+        assert_eq!(
+            geoip.country("82.2.69.160".parse().unwrap()).as_deref(),
+            Some("GB")
+        );
+
+        // A missing file leaves the loaded database alone, and no path means no lookups
+        geoip.reload_if_changed();
+        assert!(geoip.country("82.2.69.160".parse().unwrap()).is_some());
+        assert!(GeoIp::new(None)
+            .country("82.2.69.160".parse().unwrap())
+            .is_none());
+    }
+}
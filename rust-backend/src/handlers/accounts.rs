@@ -0,0 +1,435 @@
+use axum::{
+    extract::{ConnectInfo, Path, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use std::{net::SocketAddr, sync::Arc};
+
+use crate::{
+    accounts::{
+        self, dummy_password_hash, hash_recovery_code, hash_token, new_recovery_codes, new_token,
+        new_totp_secret, normalize_username, totp_url, validate_password, verify_totp,
+        INVITE_TTL_SECS, RESET_TTL_SECS,
+    },
+    auth::{Admin, CurrentUser, RequireRole, Role},
+    client_ip::client_ip,
+    error::{conflict_on_unique, ApiJson, AppError, AppResult},
+    handlers::redirect::secure_attribute,
+    models::{
+        Account, AccountLoginRequest, AccountLoginResponse, AccountTokenResponse,
+        AdminResetRequest, CreateAccountRequest, DisableTotpRequest, IdResponse, InviteRequest,
+        RecoveryCodesResponse, RegisterRequest, ResetPasswordRequest, SessionUser, SuccessResponse,
+        TotpCodeRequest, TotpSetupResponse, UpdateAccountRequest,
+    },
+    password::{hash_password_async, verify_password_async},
+    session::{encode_session, SESSION_COOKIE, SESSION_TTL_SECS},
+    AppState,
+};
+
+/// Signs in with a built-in account. Accounts with a second factor also need
+/// `code`, from their authenticator app or one of their recovery codes.
+pub async fn login(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    ApiJson(req): ApiJson<AccountLoginRequest>,
+) -> AppResult<Response> {
+    let username = req.username.trim();
+    let limit_key = format!(
+        "{}:{}",
+        client_ip(&headers, peer, state.trust_proxy),
+        username.to_lowercase()
+    );
+    if state.login_limiter.is_limited(&limit_key) {
+        return Err(AppError::rate_limited(
+            "Too many attempts. Try again later.",
+        ));
+    }
+
+    let account = state.db.get_account_by_username(username).await?;
+    let password_hash = match &account {
+        Some(account) => account.password_hash.clone(),
+        None => tokio::task::spawn_blocking(dummy_password_hash)
+            .await
+            .unwrap_or_default(),
+    };
+    let password_ok = verify_password_async(req.password, password_hash).await;
+    let account = match account {
+        Some(account) if password_ok => account,
+        _ => {
+            state.login_limiter.record_failure(&limit_key);
+            return Err(AppError::new(
+                StatusCode::UNAUTHORIZED,
+                "invalid_credentials",
+                "Incorrect username or password",
+            ));
+        }
+    };
+    if account.disabled {
+        return Err(AppError::new(
+            StatusCode::FORBIDDEN,
+            "account_disabled",
+            "This account has been disabled",
+        ));
+    }
+
+    if account.totp_enabled {
+        let Some(code) = req.code.filter(|code| !code.trim().is_empty()) else {
+            return Err(AppError::new(
+                StatusCode::UNAUTHORIZED,
+                "totp_required",
+                "Enter the code from your authenticator app",
+            ));
+        };
+        if !check_second_factor(&state, &account, &code).await? {
+            state.login_limiter.record_failure(&limit_key);
+            return Err(AppError::new(
+                StatusCode::UNAUTHORIZED,
+                "invalid_code",
+                "Incorrect or already used code",
+            ));
+        }
+    }
+    state.login_limiter.reset(&limit_key);
+
+    let user = accounts::session_user(&account);
+    tracing::info!("{} ({}) signed in with a password", user.username, user.id);
+    Ok(start_session(&state, user))
+}
+
+/// Creates an account from an invite and signs it in.
+pub async fn register(
+    State(state): State<Arc<AppState>>,
+    ApiJson(req): ApiJson<RegisterRequest>,
+) -> AppResult<Response> {
+    let username = normalize_username(&req.username)?;
+    validate_password(&req.password)?;
+    let password_hash = hash_password_async(req.password)
+        .await
+        .ok_or_else(|| AppError::internal("Failed to hash password"))?;
+
+    let (id, role) = state
+        .db
+        .redeem_invite(&hash_token(&req.invite), &username, &password_hash)
+        .await
+        .map_err(conflict_on_unique("username_taken", "Username is taken"))?
+        .ok_or_else(invalid_token)?;
+
+    let user = SessionUser {
+        id: format!("{}{}", accounts::ACCOUNT_ID_PREFIX, id),
+        username,
+        avatar: None,
+        role,
+    };
+    tracing::info!("{} ({}) registered with an invite", user.username, user.id);
+    Ok(start_session(&state, user))
+}
+
+/// Sets a new password with a token from an admin. Ends the account's other
+/// sessions; the user then signs in as usual, second factor included.
+pub async fn reset_password(
+    State(state): State<Arc<AppState>>,
+    ApiJson(req): ApiJson<ResetPasswordRequest>,
+) -> AppResult<Json<SuccessResponse>> {
+    validate_password(&req.password)?;
+    let password_hash = hash_password_async(req.password)
+        .await
+        .ok_or_else(|| AppError::internal("Failed to hash password"))?;
+
+    let id = state
+        .db
+        .redeem_password_reset(&hash_token(&req.token), &password_hash)
+        .await?
+        .ok_or_else(invalid_token)?;
+    tracing::info!("Password reset for account {}", id);
+    Ok(Json(SuccessResponse { success: true }))
+}
+
+/// Starts turning on TOTP: a new secret to add to an authenticator app. It only
+/// takes effect once confirmed with a code.
+pub async fn setup_totp(
+    State(state): State<Arc<AppState>>,
+    CurrentUser(user): CurrentUser,
+) -> AppResult<Json<TotpSetupResponse>> {
+    let account = local_account(&state, &user).await?;
+    let secret = new_totp_secret();
+    if !state.db.set_pending_totp(account.id, &secret).await? {
+        return Err(AppError::conflict(
+            "totp_enabled",
+            "Two-factor authentication is already on",
+        ));
+    }
+    let otpauth_url = totp_url(&secret, &account.username)
+        .ok_or_else(|| AppError::internal("Failed to set up two-factor authentication"))?;
+    Ok(Json(TotpSetupResponse {
+        secret,
+        otpauth_url,
+    }))
+}
+
+/// Turns on TOTP once the user shows a code for the new secret. Returns
+/// recovery codes, which are only shown this once.
+pub async fn enable_totp(
+    State(state): State<Arc<AppState>>,
+    CurrentUser(user): CurrentUser,
+    ApiJson(req): ApiJson<TotpCodeRequest>,
+) -> AppResult<Json<RecoveryCodesResponse>> {
+    let account = local_account(&state, &user).await?;
+    let secret = match &account.totp_secret {
+        Some(secret) if !account.totp_enabled => secret,
+        _ => {
+            return Err(AppError::bad_request(
+                "totp_not_set_up",
+                "Start two-factor setup first",
+            ))
+        }
+    };
+    let step = verify_totp(secret, &req.code)
+        .ok_or_else(|| AppError::bad_request("invalid_code", "Incorrect code"))?;
+
+    let recovery_codes = new_recovery_codes();
+    let hashes: Vec<String> = recovery_codes
+        .iter()
+        .map(|code| hash_recovery_code(code))
+        .collect();
+    state.db.enable_totp(account.id, step, &hashes).await?;
+    tracing::info!("{} turned on two-factor authentication", user.username);
+    Ok(Json(RecoveryCodesResponse { recovery_codes }))
+}
+
+/// Turns off TOTP, after checking the account's password and, once TOTP is
+/// on, a code from the authenticator app or a recovery code.
+pub async fn disable_totp(
+    State(state): State<Arc<AppState>>,
+    CurrentUser(user): CurrentUser,
+    ApiJson(req): ApiJson<DisableTotpRequest>,
+) -> AppResult<Json<SuccessResponse>> {
+    let account = local_account(&state, &user).await?;
+    // A stolen session shouldn't get unlimited guesses either
+    let limit_key = format!("disable-totp:{}", account.id);
+    if state.login_limiter.is_limited(&limit_key) {
+        return Err(AppError::rate_limited(
+            "Too many attempts. Try again later.",
+        ));
+    }
+    if !verify_password_async(req.password, account.password_hash.clone()).await {
+        state.login_limiter.record_failure(&limit_key);
+        return Err(AppError::bad_request(
+            "invalid_password",
+            "Incorrect password",
+        ));
+    }
+    if account.totp_enabled {
+        let Some(code) = req.code.filter(|code| !code.trim().is_empty()) else {
+            return Err(AppError::bad_request(
+                "totp_required",
+                "Enter the code from your authenticator app",
+            ));
+        };
+        if !check_second_factor(&state, &account, &code).await? {
+            state.login_limiter.record_failure(&limit_key);
+            return Err(AppError::bad_request(
+                "invalid_code",
+                "Incorrect or already used code",
+            ));
+        }
+    }
+    state.login_limiter.reset(&limit_key);
+    state.db.disable_totp(account.id).await?;
+    tracing::info!("{} turned off two-factor authentication", user.username);
+    Ok(Json(SuccessResponse { success: true }))
+}
+
+pub async fn list_accounts(
+    State(state): State<Arc<AppState>>,
+    _admin: RequireRole<Admin>,
+) -> AppResult<Json<Vec<Account>>> {
+    Ok(Json(state.db.get_accounts().await?))
+}
+
+pub async fn create_account(
+    State(state): State<Arc<AppState>>,
+    RequireRole(admin, _): RequireRole<Admin>,
+    ApiJson(req): ApiJson<CreateAccountRequest>,
+) -> AppResult<Json<IdResponse>> {
+    let username = normalize_username(&req.username)?;
+    validate_password(&req.password)?;
+    let password_hash = hash_password_async(req.password)
+        .await
+        .ok_or_else(|| AppError::internal("Failed to hash password"))?;
+
+    let id = state
+        .db
+        .insert_account(&username, &password_hash, req.role, &admin.id)
+        .await
+        .map_err(conflict_on_unique("username_taken", "Username is taken"))?;
+    tracing::info!(
+        "{} created account {} as {:?}",
+        admin.username,
+        username,
+        req.role
+    );
+    Ok(Json(IdResponse { success: true, id }))
+}
+
+/// Changes an account's role or disables it. Takes effect on its existing
+/// sessions too.
+pub async fn update_account(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<i64>,
+    RequireRole(admin, _): RequireRole<Admin>,
+    ApiJson(req): ApiJson<UpdateAccountRequest>,
+) -> AppResult<Json<SuccessResponse>> {
+    let demotes_self =
+        req.role.is_some_and(|role| role < Role::Admin) || req.disabled == Some(true);
+    if demotes_self && accounts::account_id(&admin.id) == Some(id) {
+        return Err(AppError::bad_request(
+            "cannot_modify_self",
+            "You can't disable your own account or remove your own admin role",
+        ));
+    }
+    if !state.db.update_account(id, req.role, req.disabled).await? {
+        return Err(AppError::not_found("Account not found"));
+    }
+    tracing::info!("{} updated account {}", admin.username, id);
+    Ok(Json(SuccessResponse { success: true }))
+}
+
+pub async fn delete_account(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<i64>,
+    RequireRole(admin, _): RequireRole<Admin>,
+) -> AppResult<Json<SuccessResponse>> {
+    if accounts::account_id(&admin.id) == Some(id) {
+        return Err(AppError::bad_request(
+            "cannot_modify_self",
+            "You can't delete your own account",
+        ));
+    }
+    if !state.db.delete_account(id).await? {
+        return Err(AppError::not_found("Account not found"));
+    }
+    tracing::info!("{} deleted account {}", admin.username, id);
+    Ok(Json(SuccessResponse { success: true }))
+}
+
+/// Issues a password reset token to pass on to the account's owner. Earlier
+/// unused tokens stop working. With `reset_totp`, also turns off the account's
+/// second factor, for users who lost their authenticator.
+pub async fn reset_account(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<i64>,
+    RequireRole(admin, _): RequireRole<Admin>,
+    ApiJson(req): ApiJson<AdminResetRequest>,
+) -> AppResult<Json<AccountTokenResponse>> {
+    if state.db.get_account(id).await?.is_none() {
+        return Err(AppError::not_found("Account not found"));
+    }
+    if req.reset_totp {
+        state.db.disable_totp(id).await?;
+    }
+    state.db.revoke_password_resets(id).await?;
+
+    let token = new_token();
+    let expires_at = state
+        .db
+        .insert_account_token(
+            &hash_token(&token),
+            "reset",
+            Some(id),
+            None,
+            &admin.id,
+            RESET_TTL_SECS,
+        )
+        .await?;
+    tracing::info!(
+        "{} issued a password reset for account {}{}",
+        admin.username,
+        id,
+        if req.reset_totp {
+            " and turned off its two-factor authentication"
+        } else {
+            ""
+        }
+    );
+    Ok(Json(AccountTokenResponse { token, expires_at }))
+}
+
+/// Issues a single-use invite to register an account with the given role.
+pub async fn create_invite(
+    State(state): State<Arc<AppState>>,
+    RequireRole(admin, _): RequireRole<Admin>,
+    ApiJson(req): ApiJson<InviteRequest>,
+) -> AppResult<Json<AccountTokenResponse>> {
+    let token = new_token();
+    let expires_at = state
+        .db
+        .insert_account_token(
+            &hash_token(&token),
+            "invite",
+            None,
+            Some(req.role),
+            &admin.id,
+            INVITE_TTL_SECS,
+        )
+        .await?;
+    tracing::info!("{} created an invite as {:?}", admin.username, req.role);
+    Ok(Json(AccountTokenResponse { token, expires_at }))
+}
+
+// A TOTP code for a step after the last one used, or an unused recovery code
+async fn check_second_factor(state: &AppState, account: &Account, code: &str) -> AppResult<bool> {
+    if let Some(step) = account
+        .totp_secret
+        .as_deref()
+        .and_then(|secret| verify_totp(secret, code))
+    {
+        return Ok(state.db.accept_totp_step(account.id, step).await?);
+    }
+    let used = state
+        .db
+        .use_recovery_code(account.id, &hash_recovery_code(code))
+        .await?;
+    if used {
+        tracing::warn!("{} used a recovery code", account.username);
+    }
+    Ok(used)
+}
+
+// The built-in account of the signed-in user
+async fn local_account(state: &AppState, user: &SessionUser) -> AppResult<Account> {
+    let id = accounts::account_id(&user.id).ok_or_else(|| {
+        AppError::bad_request(
+            "not_local_account",
+            "Only built-in accounts have passwords and two-factor settings",
+        )
+    })?;
+    state
+        .db
+        .get_account(id)
+        .await?
+        .ok_or_else(|| AppError::not_found("Account not found"))
+}
+
+/// Sets the session cookie, as the external login callback does, and returns
+/// the token too for API clients.
+fn start_session(state: &AppState, user: SessionUser) -> Response {
+    let token = encode_session(&user, &state.signer);
+    let cookie = format!(
+        "{}={}; Path=/; HttpOnly;{} SameSite=Lax; Max-Age={}",
+        SESSION_COOKIE,
+        token,
+        secure_attribute(state),
+        SESSION_TTL_SECS
+    );
+    (
+        [(header::SET_COOKIE, cookie)],
+        Json(AccountLoginResponse { user, token }),
+    )
+        .into_response()
+}
+
+fn invalid_token() -> AppError {
+    AppError::bad_request("invalid_token", "This link is invalid or has expired")
+}
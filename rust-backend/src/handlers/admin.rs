@@ -1,103 +1,187 @@
 use axum::{
-    extract::{Path, State},
-    http::{HeaderMap, StatusCode},
-    response::IntoResponse,
+    extract::{Path, Query, State},
     Json,
 };
 use std::sync::Arc;
 
 use crate::{
-    models::{MeResponse, SuccessResponse, UpdateUrlRequest},
-    session::extract_session_from_cookie,
+    auth::{require_role, CurrentUser, Role},
+    destination::QueryPassthrough,
+    error::{ApiJson, AppError, AppResult},
+    expiry::{format_utc, parse_activation},
+    health,
+    handlers::{
+        redirect::is_redirect_status,
+        shorten::{check_destination, is_http_url},
+    },
+    models::{CountryStats, MeResponse, SessionUser, SuccessResponse, UpdateUrlRequest, UrlFilter, UrlRecord},
+    password::hash_password_async,
     AppState,
 };
 
-// Helper to check auth from headers
-fn check_auth(headers: &HeaderMap) -> bool {
-    let cookie_header = headers
-        .get("cookie")
-        .and_then(|v| v.to_str().ok())
-        .unwrap_or("");
-    extract_session_from_cookie(cookie_header).is_some()
+/// The link `id` if `user` may manage it: admins manage every link, users
+/// only their own. Other users' links are reported as missing.
+pub async fn owned_url(state: &AppState, user: &SessionUser, id: i64) -> AppResult<UrlRecord> {
+    state
+        .db
+        .get_by_id(id)
+        .await?
+        .filter(|record| {
+            user.role == Role::Admin || record.owner_id.as_deref() == Some(user.id.as_str())
+        })
+        .ok_or_else(|| AppError::not_found("URL not found"))
 }
 
-pub async fn list_urls(State(state): State<Arc<AppState>>, headers: HeaderMap) -> impl IntoResponse {
-    if !check_auth(&headers) {
-        return (
-            StatusCode::UNAUTHORIZED,
-            Json(serde_json::json!({"error": "Unauthorized"})),
-        );
-    }
-    match state.db.get_all_urls().await {
-        Ok(urls) => (StatusCode::OK, Json(serde_json::to_value(urls).unwrap())),
-        Err(_) => (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            Json(serde_json::json!({"error": "Database error"})),
-        ),
+/// Limits `filter` to the user's own links unless they are an admin.
+pub fn restrict_to_owner(filter: &mut UrlFilter, user: &SessionUser) {
+    if user.role != Role::Admin {
+        filter.owner_id = Some(user.id.clone());
     }
 }
 
+pub async fn list_urls(
+    State(state): State<Arc<AppState>>,
+    Query(mut filter): Query<UrlFilter>,
+    CurrentUser(user): CurrentUser,
+) -> AppResult<Json<Vec<UrlRecord>>> {
+    restrict_to_owner(&mut filter, &user);
+    Ok(Json(state.db.get_urls(&filter).await?))
+}
+
 pub async fn delete_url(
     State(state): State<Arc<AppState>>,
     Path(id): Path<i64>,
-    headers: HeaderMap,
-) -> impl IntoResponse {
-    if !check_auth(&headers) {
-        return (
-            StatusCode::UNAUTHORIZED,
-            Json(serde_json::json!({"error": "Unauthorized"})),
-        );
-    }
-    match state.db.delete_url(id).await {
-        Ok(_) => (
-            StatusCode::OK,
-            Json(serde_json::to_value(SuccessResponse { success: true }).unwrap()),
-        ),
-        Err(_) => (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            Json(serde_json::json!({"error": "Database error"})),
-        ),
-    }
+    CurrentUser(user): CurrentUser,
+) -> AppResult<Json<SuccessResponse>> {
+    owned_url(&state, &user, id).await?;
+    state.db.delete_url(id).await?;
+    Ok(Json(SuccessResponse { success: true }))
 }
 
 pub async fn update_url(
     State(state): State<Arc<AppState>>,
     Path(id): Path<i64>,
-    headers: HeaderMap,
-    Json(payload): Json<UpdateUrlRequest>,
-) -> impl IntoResponse {
-    if !check_auth(&headers) {
-        return (
-            StatusCode::UNAUTHORIZED,
-            Json(serde_json::json!({"error": "Unauthorized"})),
-        );
+    CurrentUser(user): CurrentUser,
+    ApiJson(mut payload): ApiJson<UpdateUrlRequest>,
+) -> AppResult<Json<SuccessResponse>> {
+    // The fallback page is served as HTML from our own origin, and the others
+    // would let anyone lift a moderation or threat-feed block
+    let admin_fields = payload.fallback_page.is_some()
+        || payload.disabled.is_some()
+        || payload.disabled_reason.is_some()
+        || payload.untrusted.is_some();
+    if admin_fields {
+        require_role(&user, Role::Admin)?;
     }
-    match state.db.update_expiry(id, payload.expires_at.as_deref()).await {
-        Ok(_) => (
-            StatusCode::OK,
-            Json(serde_json::to_value(SuccessResponse { success: true }).unwrap()),
-        ),
-        Err(_) => (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            Json(serde_json::json!({"error": "Database error"})),
-        ),
+    let record = owned_url(&state, &user, id).await?;
+
+    if payload.max_clicks.flatten().is_some_and(|max| max < 1) {
+        return Err(AppError::invalid_input("max_clicks must be at least 1"));
     }
-}
 
-pub async fn get_me(headers: HeaderMap) -> impl IntoResponse {
-    let cookie_header = headers
-        .get("cookie")
-        .and_then(|v| v.to_str().ok())
-        .unwrap_or("");
+    if payload.redirect_type.flatten().is_some_and(|status| !is_redirect_status(status)) {
+        return Err(AppError::invalid_input("redirect_type must be 301, 302, 307 or 308"));
+    }
 
-    match extract_session_from_cookie(cookie_header) {
-        Some(user) => (
-            StatusCode::OK,
-            Json(serde_json::to_value(MeResponse { user }).unwrap()),
-        ),
-        None => (
-            StatusCode::UNAUTHORIZED,
-            Json(serde_json::json!({"error": "Unauthorized"})),
+    if payload
+        .query_passthrough
+        .as_ref()
+        .and_then(|mode| mode.as_deref())
+        .is_some_and(|mode| QueryPassthrough::parse(mode).is_none())
+    {
+        return Err(AppError::invalid_input("query_passthrough must be \"merge\" or \"override\""));
+    }
+
+    let destinations = [&payload.pending_url, &payload.fallback_url];
+    for url in destinations.into_iter().flatten().flatten() {
+        if !is_http_url(url) {
+            return Err(AppError::bad_request("invalid_url", "URLs must start with http:// or https://"));
+        }
+        check_destination(&state, url)?;
+    }
+
+    // Stored normalised to UTC, so relative values like "7d" are resolved here
+    let expiry = match &payload.expires_at {
+        Some(expires_at) => Some(
+            state
+                .expiry
+                .for_update(expires_at.as_deref())
+                .map_err(|error| AppError::bad_request("invalid_expiry", error))?,
         ),
+        None => None,
+    };
+    let activation = match &payload.activates_at {
+        Some(Some(activates_at)) => Some(Some(parse_activation(activates_at).ok_or_else(|| {
+            AppError::invalid_input("activates_at must be an RFC 3339 timestamp")
+        })?)),
+        Some(None) => Some(None),
+        None => None,
+    };
+
+    // Whichever of the two isn't changing keeps its stored value
+    let expires_at = expiry.unwrap_or(record.expires_at);
+    let activates_at = activation.unwrap_or(record.activates_at);
+    if let (Some(expiry), Some(activation)) = (expires_at, activates_at) {
+        if expiry <= activation {
+            return Err(AppError::bad_request(
+                "invalid_expiry",
+                "expires_at must be after activates_at",
+            ));
+        }
+    }
+    if let Some(expiry) = expiry {
+        payload.expires_at = Some(expiry.map(format_utc));
     }
+    if let Some(activation) = activation {
+        payload.activates_at = Some(activation.map(format_utc));
+    }
+
+    if let Some(password) = &payload.password {
+        let hash = match password.clone().filter(|p| !p.is_empty()) {
+            Some(password) => Some(
+                hash_password_async(password)
+                    .await
+                    .ok_or_else(|| AppError::internal("Failed to hash password"))?,
+            ),
+            None => None,
+        };
+        state.db.set_password_hash(id, hash.as_deref()).await?;
+    }
+
+    if !state.db.update_url(id, &payload).await? {
+        return Err(AppError::not_found("URL not found"));
+    }
+    Ok(Json(SuccessResponse { success: true }))
+}
+
+pub async fn get_me(CurrentUser(user): CurrentUser) -> Json<MeResponse> {
+    Json(MeResponse { user })
+}
+
+/// Clicks on a link broken down by visitor country.
+pub async fn get_country_stats(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<i64>,
+    CurrentUser(user): CurrentUser,
+) -> AppResult<Json<Vec<CountryStats>>> {
+    owned_url(&state, &user, id).await?;
+    Ok(Json(state.db.get_country_stats(id).await?))
+}
+
+/// Checks a link's destinations right away and returns the updated link.
+pub async fn check_url_health(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<i64>,
+    CurrentUser(user): CurrentUser,
+) -> AppResult<Json<UrlRecord>> {
+    owned_url(&state, &user, id).await?;
+    let urls = state.db.get_destinations(id).await?;
+    let result = health::check_all(&state.fetch, &urls).await;
+    state.db.record_health(id, &result).await?;
+    let record = state
+        .db
+        .get_by_id(id)
+        .await?
+        .ok_or_else(|| AppError::not_found("URL not found"))?;
+    Ok(Json(record))
 }
@@ -1,108 +1,135 @@
 use axum::{
-    extract::{Query, State},
-    http::{header, StatusCode},
+    extract::{Path, Query, State},
+    http::{header, HeaderMap, StatusCode},
     response::{IntoResponse, Redirect, Response},
+    Json,
 };
+use rand::Rng;
 use std::sync::Arc;
 
 use crate::{
-    models::{CallbackQuery, DiscordTokenResponse, DiscordUser},
-    session::{encode_session, SESSION_COOKIE},
+    auth_provider::ProviderSummary,
+    error::AppResult,
+    handlers::redirect::secure_attribute,
+    models::CallbackQuery,
+    session::{cookie_value, encode_session, SESSION_COOKIE, SESSION_TTL_SECS},
     AppState,
 };
 
-pub async fn discord_redirect(State(state): State<Arc<AppState>>) -> Response {
-    if state.discord_client_id.is_empty() || state.discord_redirect_uri.is_empty() {
-        return (StatusCode::INTERNAL_SERVER_ERROR, "Discord Env Missing").into_response();
+// Holds the state and nonce of a login in progress
+const LOGIN_COOKIE: &str = "meo_login";
+const LOGIN_TTL_SECS: i64 = 10 * 60;
+
+/// The enabled login providers, for the login page. Built-in accounts are
+/// listed as `local` once any exist; they sign in by posting to `login_url`.
+pub async fn list_providers(
+    State(state): State<Arc<AppState>>,
+) -> AppResult<Json<Vec<ProviderSummary>>> {
+    let mut providers = state.auth_providers.summaries();
+    if state.db.count_accounts().await? > 0 {
+        providers.push(ProviderSummary {
+            name: "local".to_string(),
+            display_name: "Username and password".to_string(),
+            login_url: "/api/accounts/login".to_string(),
+        });
     }
+    Ok(Json(providers))
+}
 
-    let url = format!(
-        "https://discord.com/api/oauth2/authorize?client_id={}&redirect_uri={}&response_type=code&scope=identify",
-        state.discord_client_id,
-        urlencoding::encode(&state.discord_redirect_uri)
-    );
+/// Sends the browser to the provider's login page.
+pub async fn login(State(state): State<Arc<AppState>>, Path(provider): Path<String>) -> Response {
+    let Some(auth) = state.auth_providers.get(&provider) else {
+        return (StatusCode::NOT_FOUND, "Unknown login provider").into_response();
+    };
 
-    Redirect::temporary(&url).into_response()
+    let login_state = random_hex();
+    let nonce = random_hex();
+    let url = match auth
+        .authorize_url(state.auth_providers.client(), &login_state, &nonce)
+        .await
+    {
+        Ok(url) => url,
+        Err(e) => {
+            tracing::error!("Login with {} failed: {}", provider, e);
+            return (StatusCode::INTERNAL_SERVER_ERROR, "Auth Failed").into_response();
+        }
+    };
+
+    // The callback only accepts the state it was sent away with, from the
+    // same browser
+    let token = state.signer.sign_expiring(
+        &login_message(&provider, &login_state, &nonce),
+        LOGIN_TTL_SECS,
+    );
+    let cookie = format!(
+        "{}={}.{}.{}; Path=/auth; HttpOnly;{} SameSite=Lax; Max-Age={}",
+        LOGIN_COOKIE,
+        login_state,
+        nonce,
+        token,
+        secure_attribute(&state),
+        LOGIN_TTL_SECS
+    );
+    ([(header::SET_COOKIE, cookie)], Redirect::temporary(&url)).into_response()
 }
 
-pub async fn discord_callback(
+pub async fn callback(
     State(state): State<Arc<AppState>>,
+    Path(provider): Path<String>,
     Query(query): Query<CallbackQuery>,
+    headers: HeaderMap,
 ) -> Response {
+    let Some(auth) = state.auth_providers.get(&provider) else {
+        return (StatusCode::NOT_FOUND, "Unknown login provider").into_response();
+    };
     let code = match query.code {
         Some(code) => code,
         None => return (StatusCode::BAD_REQUEST, "No code").into_response(),
     };
 
-    // Exchange code for token
-    let client = reqwest::Client::new();
-    let token_response = client
-        .post("https://discord.com/api/oauth2/token")
-        .header("Content-Type", "application/x-www-form-urlencoded")
-        .form(&[
-            ("client_id", state.discord_client_id.as_str()),
-            ("client_secret", state.discord_client_secret.as_str()),
-            ("grant_type", "authorization_code"),
-            ("code", &code),
-            ("redirect_uri", state.discord_redirect_uri.as_str()),
-        ])
-        .send()
-        .await;
-
-    let token_data: DiscordTokenResponse = match token_response {
-        Ok(res) if res.status().is_success() => match res.json().await {
-            Ok(data) => data,
-            Err(e) => {
-                tracing::error!("Failed to parse token response: {}", e);
-                return (StatusCode::INTERNAL_SERVER_ERROR, "Auth Failed").into_response();
-            }
-        },
-        Ok(res) => {
-            let text = res.text().await.unwrap_or_default();
-            tracing::error!("Token exchange failed: {}", text);
-            return (StatusCode::INTERNAL_SERVER_ERROR, "Auth Failed").into_response();
-        }
-        Err(e) => {
-            tracing::error!("Token request failed: {}", e);
-            return (StatusCode::INTERNAL_SERVER_ERROR, "Auth Failed").into_response();
-        }
+    let login = cookie_value(&headers, LOGIN_COOKIE).and_then(|value| {
+        let mut parts = value.splitn(3, '.');
+        let (login_state, nonce, token) = (parts.next()?, parts.next()?, parts.next()?);
+        let valid = state
+            .signer
+            .verify_expiring(&login_message(&provider, login_state, nonce), token);
+        valid.then(|| (login_state.to_string(), nonce.to_string()))
+    });
+    let nonce = match login {
+        Some((login_state, nonce)) if query.state.as_deref() == Some(login_state.as_str()) => nonce,
+        _ => return (StatusCode::BAD_REQUEST, "Login expired, please try again").into_response(),
     };
 
-    // Get user info from Discord
-    let user_response = client
-        .get("https://discord.com/api/users/@me")
-        .header("Authorization", format!("Bearer {}", token_data.access_token))
-        .send()
-        .await;
-
-    let user_data: DiscordUser = match user_response {
-        Ok(res) if res.status().is_success() => match res.json().await {
-            Ok(data) => data,
-            Err(e) => {
-                tracing::error!("Failed to parse user response: {}", e);
-                return (StatusCode::INTERNAL_SERVER_ERROR, "Failed to get user info").into_response();
-            }
-        },
-        Ok(res) => {
-            let text = res.text().await.unwrap_or_default();
-            tracing::error!("User fetch failed: {}", text);
-            return (StatusCode::INTERNAL_SERVER_ERROR, "Failed to get user info").into_response();
-        }
+    let user = match auth
+        .authenticate(state.auth_providers.client(), &code, &nonce)
+        .await
+    {
+        Ok(user) => user,
         Err(e) => {
-            tracing::error!("User request failed: {}", e);
-            return (StatusCode::INTERNAL_SERVER_ERROR, "Failed to get user info").into_response();
+            tracing::error!("Login with {} failed: {}", provider, e);
+            return (StatusCode::INTERNAL_SERVER_ERROR, "Auth Failed").into_response();
         }
     };
+    tracing::info!(
+        "{} ({}) signed in with {} as {:?}",
+        user.username,
+        user.id,
+        provider,
+        user.role
+    );
 
     // Create session cookie
-    let session_value = encode_session(&user_data);
-    let is_production = state.base_url.starts_with("https");
+    let session_value = encode_session(&user, &state.signer);
     let cookie = format!(
         "{}={}; Path=/; HttpOnly;{} SameSite=Lax; Max-Age={}",
         SESSION_COOKIE,
         session_value,
-        if is_production { " Secure;" } else { "" },
-        60 * 60 * 24 * 7 // 7 days
+        secure_attribute(&state),
+        SESSION_TTL_SECS
+    );
+    let clear_login = format!(
+        "{}=; Path=/auth; HttpOnly; SameSite=Lax; Max-Age=0",
+        LOGIN_COOKIE
     );
 
     // Redirect with Set-Cookie header
@@ -110,6 +137,7 @@ pub async fn discord_callback(
         .status(StatusCode::FOUND)
         .header(header::LOCATION, format!("{}/dashboard", state.base_url))
         .header(header::SET_COOKIE, cookie)
+        .header(header::SET_COOKIE, clear_login)
         .body(axum::body::Body::empty())
         .unwrap()
 }
@@ -128,3 +156,12 @@ pub async fn logout(State(state): State<Arc<AppState>>) -> Response {
         .body(axum::body::Body::empty())
         .unwrap()
 }
+
+fn login_message(provider: &str, login_state: &str, nonce: &str) -> String {
+    format!("login|{}|{}|{}", provider, login_state, nonce)
+}
+
+fn random_hex() -> String {
+    let bytes: [u8; 16] = rand::thread_rng().gen();
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
@@ -0,0 +1,77 @@
+use axum::{extract::State, http::StatusCode, Json};
+use std::sync::Arc;
+
+use crate::{
+    auth::{Admin, RequireRole},
+    domain_policy::PolicyEntry,
+    error::{ApiJson, AppError, AppResult},
+    models::SuccessResponse,
+    AppState,
+};
+
+pub async fn list_entries(
+    State(state): State<Arc<AppState>>,
+    _admin: RequireRole<Admin>,
+) -> AppResult<Json<Vec<PolicyEntry>>> {
+    Ok(Json(state.domain_policy.entries()))
+}
+
+/// Adds a block or allow entry. Existing links are re-checked when they are next followed.
+pub async fn add_entry(
+    State(state): State<Arc<AppState>>,
+    RequireRole(admin, _): RequireRole<Admin>,
+    ApiJson(mut entry): ApiJson<PolicyEntry>,
+) -> AppResult<Json<SuccessResponse>> {
+    entry.pattern = entry.pattern.trim().to_string();
+    let line = entry.line();
+    let policy = state.domain_policy.clone();
+    match tokio::task::spawn_blocking(move || policy.add(entry)).await {
+        Ok(Ok(true)) => {
+            tracing::info!("{} added domain policy entry {}", admin.username, line);
+            Ok(Json(SuccessResponse { success: true }))
+        }
+        Ok(Ok(false)) => Err(AppError::conflict("entry_exists", "Entry already exists")),
+        Ok(Err(error)) => Err(AppError::bad_request("invalid_pattern", error)),
+        Err(e) => Err(AppError::internal("Failed to update domain policy").with_cause(e)),
+    }
+}
+
+pub async fn remove_entry(
+    State(state): State<Arc<AppState>>,
+    RequireRole(admin, _): RequireRole<Admin>,
+    ApiJson(mut entry): ApiJson<PolicyEntry>,
+) -> AppResult<Json<SuccessResponse>> {
+    entry.pattern = entry.pattern.trim().to_string();
+    let line = entry.line();
+    let policy = state.domain_policy.clone();
+    match tokio::task::spawn_blocking(move || policy.remove(&entry)).await {
+        Ok(Ok(true)) => {
+            tracing::info!("{} removed domain policy entry {}", admin.username, line);
+            Ok(Json(SuccessResponse { success: true }))
+        }
+        Ok(Ok(false)) => Err(AppError::not_found("Entry not found")),
+        Ok(Err(error)) => {
+            Err(AppError::internal("Failed to update domain policy").with_cause(error))
+        }
+        Err(e) => Err(AppError::internal("Failed to update domain policy").with_cause(e)),
+    }
+}
+
+/// Re-reads the policy file right away instead of waiting for the next check.
+pub async fn reload(
+    State(state): State<Arc<AppState>>,
+    _admin: RequireRole<Admin>,
+) -> AppResult<Json<serde_json::Value>> {
+    let policy = state.domain_policy.clone();
+    match tokio::task::spawn_blocking(move || policy.reload()).await {
+        Ok(Ok(entries)) => Ok(Json(
+            serde_json::json!({"success": true, "entries": entries}),
+        )),
+        Ok(Err(error)) => Err(AppError::new(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "invalid_policy_file",
+            error,
+        )),
+        Err(e) => Err(AppError::internal("Failed to reload domain policy").with_cause(e)),
+    }
+}
@@ -0,0 +1,56 @@
+use axum::{
+    body::Body,
+    extract::{Path, Query, State},
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+};
+use std::sync::Arc;
+use tokio_stream::wrappers::ReceiverStream;
+
+use crate::{
+    auth::CurrentUser,
+    error::{AppError, AppResult},
+    export::{spawn_export, ExportFormat, ExportKind},
+    handlers::admin::restrict_to_owner,
+    models::ExportQuery,
+    AppState,
+};
+
+pub async fn export(
+    State(state): State<Arc<AppState>>,
+    Path(kind): Path<String>,
+    Query(mut query): Query<ExportQuery>,
+    CurrentUser(user): CurrentUser,
+) -> AppResult<Response> {
+    restrict_to_owner(&mut query.filter, &user);
+    let Some(kind) = ExportKind::parse(&kind) else {
+        return Err(AppError::not_found("Unknown export"));
+    };
+
+    let format = match query.format.as_deref() {
+        None => ExportFormat::Csv,
+        Some(value) => ExportFormat::parse(value)
+            .ok_or_else(|| AppError::invalid_input("Format must be csv, json or ndjson"))?,
+    };
+
+    let rx = spawn_export(state.db.clone(), kind, format, query.filter);
+    let filename = format!(
+        "{}-{}.{}",
+        kind.name(),
+        chrono::Utc::now().format("%Y%m%d"),
+        format.extension()
+    );
+
+    Ok((
+        StatusCode::OK,
+        [
+            (header::CONTENT_TYPE, format.content_type().to_string()),
+            (
+                header::CONTENT_DISPOSITION,
+                format!("attachment; filename=\"{}\"", filename),
+            ),
+        ],
+        Body::from_stream(ReceiverStream::new(rx)),
+    )
+        .into_response())
+}
@@ -0,0 +1,80 @@
+use axum::{
+    extract::{Path, State},
+    Json,
+};
+use std::sync::Arc;
+
+use crate::{
+    auth::{Admin, CurrentUser, RequireRole},
+    error::{conflict_on_unique, ApiJson, AppError, AppResult},
+    handlers::{admin::owned_url, tags::normalize_name},
+    models::{FolderStats, IdResponse, NameRequest, SetFolderRequest, SuccessResponse},
+    AppState,
+};
+
+pub async fn list_folders(
+    State(state): State<Arc<AppState>>,
+    _user: CurrentUser,
+) -> AppResult<Json<Vec<FolderStats>>> {
+    Ok(Json(state.db.get_folder_stats().await?))
+}
+
+pub async fn create_folder(
+    State(state): State<Arc<AppState>>,
+    _user: CurrentUser,
+    ApiJson(payload): ApiJson<NameRequest>,
+) -> AppResult<Json<IdResponse>> {
+    let name = normalize_name(&payload.name)?;
+    let id = state
+        .db
+        .insert_folder(&name)
+        .await
+        .map_err(conflict_on_unique("folder_exists", "Folder already exists"))?;
+    Ok(Json(IdResponse { success: true, id }))
+}
+
+/// Folders are shared by everyone's links, so only admins rename and delete them.
+pub async fn rename_folder(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<i64>,
+    _admin: RequireRole<Admin>,
+    ApiJson(payload): ApiJson<NameRequest>,
+) -> AppResult<Json<SuccessResponse>> {
+    let name = normalize_name(&payload.name)?;
+    let renamed = state
+        .db
+        .rename_folder(id, &name)
+        .await
+        .map_err(conflict_on_unique("folder_exists", "Folder already exists"))?;
+    if !renamed {
+        return Err(AppError::not_found("Folder not found"));
+    }
+    Ok(Json(SuccessResponse { success: true }))
+}
+
+pub async fn delete_folder(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<i64>,
+    _admin: RequireRole<Admin>,
+) -> AppResult<Json<SuccessResponse>> {
+    state.db.delete_folder(id).await?;
+    Ok(Json(SuccessResponse { success: true }))
+}
+
+pub async fn set_url_folder(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<i64>,
+    CurrentUser(user): CurrentUser,
+    ApiJson(payload): ApiJson<SetFolderRequest>,
+) -> AppResult<Json<SuccessResponse>> {
+    owned_url(&state, &user, id).await?;
+    if let Some(folder_id) = payload.folder_id {
+        if !state.db.folder_exists(folder_id).await? {
+            return Err(AppError::not_found("Folder not found"));
+        }
+    }
+    if !state.db.set_url_folder(id, payload.folder_id).await? {
+        return Err(AppError::not_found("URL not found"));
+    }
+    Ok(Json(SuccessResponse { success: true }))
+}
@@ -0,0 +1,41 @@
+use axum::{
+    extract::{Query, State},
+    Json,
+};
+use std::sync::Arc;
+
+use crate::{
+    auth::{Admin, RequireRole},
+    error::{AppError, AppResult},
+    import::{import, ImportError, ImportReport, ImportSource},
+    models::ImportQuery,
+    AppState,
+};
+
+pub async fn import_urls(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<ImportQuery>,
+    _admin: RequireRole<Admin>,
+    body: String,
+) -> AppResult<Json<ImportReport>> {
+    let Some(source) = ImportSource::parse(&query.format) else {
+        return Err(AppError::invalid_input(
+            "Format must be yourls, shlink, kutt or bitly",
+        ));
+    };
+
+    let report = import(
+        &state.db,
+        &state.domain_policy,
+        &state.threat_feeds,
+        source,
+        &body,
+        query.dry_run,
+    )
+    .await;
+    match report {
+        Ok(report) => Ok(Json(report)),
+        Err(ImportError::Invalid(error)) => Err(AppError::bad_request("invalid_import", error)),
+        Err(ImportError::Database(e)) => Err(e.into()),
+    }
+}
@@ -0,0 +1,32 @@
+use axum::{
+    extract::{Path, State},
+    Json,
+};
+use std::sync::Arc;
+
+use crate::{
+    auth::{Admin, RequireRole},
+    error::{AppError, AppResult},
+    models::{JobRun, SuccessResponse},
+    AppState,
+};
+
+pub async fn list_jobs(
+    State(state): State<Arc<AppState>>,
+    _admin: RequireRole<Admin>,
+) -> AppResult<Json<Vec<JobRun>>> {
+    Ok(Json(state.db.get_jobs().await?))
+}
+
+/// Queues a job to run on the next scheduler poll, on whichever replica claims it.
+pub async fn run_job(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+    RequireRole(admin, _): RequireRole<Admin>,
+) -> AppResult<Json<SuccessResponse>> {
+    if !state.db.trigger_job(&name).await? {
+        return Err(AppError::not_found("Job not found"));
+    }
+    tracing::info!("{} queued job {}", admin.username, name);
+    Ok(Json(SuccessResponse { success: true }))
+}
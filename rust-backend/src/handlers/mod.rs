@@ -1,4 +1,14 @@
+pub mod accounts;
 pub mod admin;
 pub mod auth;
+pub mod domain_policy;
+pub mod export;
+pub mod folders;
+pub mod import;
+pub mod jobs;
 pub mod redirect;
 pub mod shorten;
+pub mod tags;
+pub mod targeting;
+pub mod threat_feeds;
+pub mod variants;
@@ -1,15 +1,53 @@
 use axum::{
-    extract::{Path, State},
-    http::StatusCode,
-    response::{IntoResponse, Redirect, Response},
+    extract::{ConnectInfo, Path, RawQuery, Request, State},
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    response::{Html, IntoResponse, Redirect, Response},
+    Form,
 };
-use std::sync::Arc;
+use rand::Rng;
+use std::{net::SocketAddr, sync::Arc};
+use tower::ServiceExt;
+use tower_http::services::ServeDir;
 
-use crate::AppState;
+use crate::{
+    client_ip::client_ip,
+    destination::{self, QueryPassthrough},
+    domain_policy::Violation,
+    expiry::format_utc,
+    interstitial::{continue_message, take_continue_token, CONTINUE_PARAM},
+    models::{RotationDestination, UnlockForm, UrlRecord},
+    pages,
+    password::verify_password_async,
+    session::cookie_value,
+    user_agent::Client,
+    AppState,
+};
+
+// How long a correct password is remembered for a link
+const UNLOCK_TTL_SECS: i64 = 30 * 60;
+// How long a continue link from the interstitial stays valid
+const CONTINUE_TTL_SECS: i64 = 10 * 60;
+// How long a sticky rotation keeps a visitor on the same destination
+const ROTATION_TTL_SECS: i64 = 30 * 24 * 60 * 60;
+
+/// Paths handled by the SPA rather than treated as slugs.
+pub const RESERVED_SLUGS: [&str; 3] = ["dashboard", "login", "logout"];
+
+/// Whether `slug` can be reached through the redirect route. Dots mark static
+/// files and a trailing `+` asks for the preview page.
+pub fn is_servable_slug(slug: &str) -> bool {
+    !slug.is_empty()
+        && !slug.contains(['.', '/', '?', '#'])
+        && !slug.ends_with('+')
+        && !RESERVED_SLUGS.contains(&slug)
+}
 
 pub async fn handle_redirect(
     State(state): State<Arc<AppState>>,
     Path(slug): Path<String>,
+    RawQuery(query): RawQuery,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
 ) -> Response {
     // Ignore requests with file extensions (static assets)
     if slug.contains('.') {
@@ -17,8 +55,7 @@ pub async fn handle_redirect(
     }
 
     // Reserved paths for SPA routing - serve index.html
-    let reserved_paths = ["dashboard", "login", "logout"];
-    if reserved_paths.contains(&slug.as_str()) {
+    if RESERVED_SLUGS.contains(&slug.as_str()) {
         // Return the index.html file for SPA routes
         return match tokio::fs::read("dist/index.html").await {
             Ok(content) => (
@@ -31,6 +68,11 @@ pub async fn handle_redirect(
         };
     }
 
+    // `/<slug>+` shows where the link goes instead of following it
+    if let Some(slug) = slug.strip_suffix('+') {
+        return preview(&state, slug).await;
+    }
+
     // Look up slug in database
     let record = match state.db.get_by_slug(&slug).await {
         Ok(Some(record)) => record,
@@ -42,22 +84,557 @@ pub async fn handle_redirect(
         }
     };
 
-    // Check expiration
-    if let Some(expires_at) = &record.expires_at {
-        if let Ok(expiry) = chrono::DateTime::parse_from_rfc3339(expires_at) {
-            if expiry < chrono::Utc::now() {
-                return (StatusCode::GONE, "URL has expired").into_response();
-            }
+    follow_link(&state, record, None, query.as_deref(), peer, &headers).await
+}
+
+/// Handles `/<slug>/<path>`. Paths under links without path passthrough (and
+/// under unknown slugs) are left to the static files.
+pub async fn handle_redirect_with_path(
+    State(state): State<Arc<AppState>>,
+    Path((slug, extra_path)): Path<(String, String)>,
+    RawQuery(query): RawQuery,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    request: Request,
+) -> Response {
+    let record = match state.db.get_by_slug(&slug).await {
+        Ok(Some(record)) if record.path_passthrough => record,
+        Ok(_) => {
+            return match ServeDir::new("dist").oneshot(request).await {
+                Ok(response) => response.into_response(),
+                Err(never) => match never {},
+            };
+        }
+        Err(_) => {
+            return (StatusCode::INTERNAL_SERVER_ERROR, "Database error").into_response();
+        }
+    };
+
+    follow_link(&state, record, Some(&extra_path), query.as_deref(), peer, request.headers()).await
+}
+
+async fn preview(state: &AppState, slug: &str) -> Response {
+    let record = match state.db.get_by_slug(slug).await {
+        Ok(Some(record)) => record,
+        Ok(None) => {
+            return (StatusCode::NOT_FOUND, "URL not found").into_response();
+        }
+        Err(_) => {
+            return (StatusCode::INTERNAL_SERVER_ERROR, "Database error").into_response();
+        }
+    };
+    let unavailable = unavailable_reason(&record).map(Unavailable::message);
+    (
+        [(header::CACHE_CONTROL, "no-store")],
+        Html(pages::preview(&record, unavailable)),
+    )
+        .into_response()
+}
+
+async fn follow_link(
+    state: &AppState,
+    record: UrlRecord,
+    extra_path: Option<&str>,
+    query: Option<&str>,
+    peer: SocketAddr,
+    headers: &HeaderMap,
+) -> Response {
+    if let Some(reason) = unavailable_reason(&record) {
+        return unavailable_response(state, &record, reason);
+    }
+
+    let (continue_token, query) = take_continue_token(query);
+    let query = query.as_deref();
+
+    // Password-protected links show a form until unlocked
+    if let Some(password_hash) = &record.password_hash {
+        let unlocked = cookie_value(headers, &unlock_cookie_name(&record))
+            .is_some_and(|token| state.signer.verify_expiring(&unlock_message(&record, password_hash), &token));
+        if !unlocked {
+            let next = request_suffix(extra_path, query);
+            return Html(pages::password_form(&record.slug, &next, None)).into_response();
+        }
+    }
+
+    let country = state.geoip.country(client_ip(headers, peer, state.trust_proxy));
+
+    // Redirect to original URL (or the one targeted at this visitor or picked by
+    // the rotation), plus whatever the link forwards from the request
+    let mut rotation = None;
+    let target = match targeted_destination(state, &record, headers, country.as_deref()).await {
+        Some(url) => Some(url),
+        None => rotate(state, &record, headers, continue_token.as_deref()).await.map(|(chosen, cookie)| {
+            rotation = Some((chosen.id, cookie));
+            chosen.url
+        }),
+    };
+    let destination = target.as_deref().unwrap_or(&record.original_url);
+
+    // Flagged links show a warning first. Its continue link carries a token
+    // signed for the destination shown, and only following that counts as a click.
+    if let Some(reason) = state.interstitial.reason(&record, destination) {
+        let message = continue_message(&record, destination);
+        let confirmed = continue_token
+            .as_deref()
+            .is_some_and(|token| state.signer.verify_expiring(&message, token));
+        if !confirmed {
+            let token = state.signer.sign_expiring(&message, CONTINUE_TTL_SECS);
+            let mut continue_url = format!(
+                "/{}{}",
+                urlencoding::encode(&record.slug),
+                request_suffix(extra_path, query)
+            );
+            continue_url.push(if query.is_some() { '&' } else { '?' });
+            continue_url.push_str(&format!("{}={}", CONTINUE_PARAM, token));
+            return (
+                [(header::CACHE_CONTROL, "no-store")],
+                Html(pages::interstitial(
+                    destination,
+                    &continue_url,
+                    reason,
+                    state.interstitial.countdown_secs,
+                )),
+            )
+                .into_response();
         }
     }
 
-    // Increment click count (fire and forget)
+    let location = destination::build(
+        destination,
+        &utm_params(&record),
+        extra_path.filter(|_| record.path_passthrough),
+        query,
+        record.query_passthrough.as_deref().and_then(QueryPassthrough::parse),
+    );
+
+    // Links whose destination was blocked after they were created are taken
+    // offline. The allowlist only applies when links are created or edited.
+    if let Err(violation @ Violation::Blocked(_)) = state.domain_policy.check(&location) {
+        tracing::warn!("Disabling link {}: {}", record.slug, violation.message());
+        let _ = state.db.disable_url(record.id, &violation.message()).await;
+        return unavailable_response(state, &record, Unavailable::Disabled);
+    }
+
+    // Count the click before redirecting so `max_clicks` can't be overrun
+    match state.db.consume_click(record.id).await {
+        Ok(true) => {}
+        Ok(false) => {
+            return unavailable_response(state, &record, Unavailable::Exhausted);
+        }
+        Err(_) => {
+            return (StatusCode::INTERNAL_SERVER_ERROR, "Database error").into_response();
+        }
+    }
+
+    // Record the click (fire and forget)
     let db = state.db.clone();
-    let slug_clone = slug.clone();
+    let url_id = record.id;
+    let referrer = header_value(headers, header::REFERER);
+    let user_agent = header_value(headers, header::USER_AGENT);
+    let rotation_id = rotation.as_ref().map(|(id, _)| *id);
     tokio::spawn(async move {
-        let _ = db.increment_clicks(&slug_clone).await;
+        let _ = db
+            .insert_click_event(url_id, referrer.as_deref(), user_agent.as_deref(), country.as_deref())
+            .await;
+        if let Some(id) = rotation_id {
+            let _ = db.record_rotation_click(id).await;
+        }
     });
 
-    // Redirect to original URL
-    Redirect::temporary(&record.original_url).into_response()
+    let mut response = redirect_to_destination(state, &record, &location);
+    if let Some(cookie) = rotation
+        .and_then(|(_, cookie)| cookie)
+        .and_then(|c| HeaderValue::from_str(&c).ok())
+    {
+        response.headers_mut().append(header::SET_COOKIE, cookie);
+    }
+    response
+}
+
+// Device rules are checked first, then the visitor's country. Lookup failures
+// fall back to the link's own destination rather than failing the click.
+async fn targeted_destination(
+    state: &AppState,
+    record: &UrlRecord,
+    headers: &HeaderMap,
+    country: Option<&str>,
+) -> Option<String> {
+    match state.db.get_device_rules(record.id).await {
+        Ok(rules) if !rules.is_empty() => {
+            let user_agent = header_value(headers, header::USER_AGENT).unwrap_or_default();
+            let client = Client::parse(&user_agent);
+            let matched = rules.into_iter().find(|rule| {
+                rule.platform.as_deref().is_none_or(|p| client.matches_platform(p))
+                    && rule.browser.as_deref().is_none_or(|b| client.matches_browser(b))
+            });
+            if let Some(rule) = matched {
+                return Some(rule.url);
+            }
+        }
+        Ok(_) => {}
+        Err(e) => tracing::error!("Failed to load device rules for link {}: {}", record.id, e),
+    }
+
+    let country = country?;
+    match state.db.get_geo_rule(record.id, country).await {
+        Ok(url) => url,
+        Err(e) => {
+            tracing::error!("Failed to load geo rules for link {}: {}", record.id, e);
+            None
+        }
+    }
+}
+
+// Picks one of the link's rotation destinations in proportion to their weights.
+// A visitor coming back from the interstitial gets the destination it showed.
+// With sticky rotation a returning visitor keeps the destination named in their
+// cookie while it is still active; otherwise the pick comes with a cookie to set.
+async fn rotate(
+    state: &AppState,
+    record: &UrlRecord,
+    headers: &HeaderMap,
+    continue_token: Option<&str>,
+) -> Option<(RotationDestination, Option<String>)> {
+    let destinations = match state.db.get_rotation(record.id).await {
+        Ok(destinations) => destinations,
+        Err(e) => {
+            tracing::error!("Failed to load rotation for link {}: {}", record.id, e);
+            return None;
+        }
+    };
+    let active: Vec<RotationDestination> = destinations.into_iter().filter(|d| d.weight > 0).collect();
+    if active.is_empty() {
+        return None;
+    }
+
+    let cookie_name = format!("meo_rotation_{}", record.id);
+    let sticky_cookie = |chosen: &RotationDestination| {
+        record.sticky_rotation.then(|| {
+            format!(
+                "{}={}; Path=/; HttpOnly;{} SameSite=Lax; Max-Age={}",
+                cookie_name,
+                chosen.id,
+                secure_attribute(state),
+                ROTATION_TTL_SECS
+            )
+        })
+    };
+    if let Some(token) = continue_token {
+        let shown = active
+            .iter()
+            .find(|d| state.signer.verify_expiring(&continue_message(record, &d.url), token));
+        if let Some(chosen) = shown {
+            return Some((chosen.clone(), sticky_cookie(chosen)));
+        }
+    }
+    if record.sticky_rotation {
+        let remembered = cookie_value(headers, &cookie_name).and_then(|v| v.parse::<i64>().ok());
+        if let Some(chosen) = remembered.and_then(|id| active.iter().find(|d| d.id == id)) {
+            return Some((chosen.clone(), None));
+        }
+    }
+
+    let chosen = pick_weighted(&active, &mut rand::thread_rng())?.clone();
+    let cookie = sticky_cookie(&chosen);
+    Some((chosen, cookie))
+}
+
+// Destinations with a weight of 0 or less are never picked
+fn pick_weighted<'a>(destinations: &'a [RotationDestination], rng: &mut impl Rng) -> Option<&'a RotationDestination> {
+    let total: i64 = destinations.iter().map(|d| d.weight.max(0)).sum();
+    if total == 0 {
+        return None;
+    }
+    let mut roll = rng.gen_range(0..total);
+    destinations.iter().filter(|d| d.weight > 0).find(|d| {
+        if roll < d.weight {
+            return true;
+        }
+        roll -= d.weight;
+        false
+    })
+}
+
+pub fn is_redirect_status(status: i64) -> bool {
+    matches!(status, 301 | 302 | 307 | 308)
+}
+
+// Uses the link's redirect status (or the instance default). Temporary redirects
+// are never cached so every click is counted and the destination can change;
+// permanent ones may be cached by browsers and proxies.
+fn redirect_to_destination(state: &AppState, record: &UrlRecord, location: &str) -> Response {
+    let status = record
+        .redirect_type
+        .and_then(|status| u16::try_from(status).ok())
+        .and_then(|status| StatusCode::from_u16(status).ok())
+        .unwrap_or(state.default_redirect_status);
+    let cache_control = match status {
+        StatusCode::MOVED_PERMANENTLY | StatusCode::PERMANENT_REDIRECT => {
+            format!("public, max-age={}", state.permanent_redirect_max_age)
+        }
+        _ => "no-store".to_string(),
+    };
+
+    (
+        status,
+        [
+            (header::LOCATION, location.to_string()),
+            (header::CACHE_CONTROL, cache_control),
+        ],
+    )
+        .into_response()
+}
+
+/// Handles the password form posted by visitors of a protected link.
+pub async fn unlock(
+    State(state): State<Arc<AppState>>,
+    Path(slug): Path<String>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Form(form): Form<UnlockForm>,
+) -> Response {
+    let record = match state.db.get_by_slug(&slug).await {
+        Ok(Some(record)) => record,
+        Ok(None) => {
+            return (StatusCode::NOT_FOUND, "URL not found").into_response();
+        }
+        Err(_) => {
+            return (StatusCode::INTERNAL_SERVER_ERROR, "Database error").into_response();
+        }
+    };
+
+    // Nothing to unlock; the GET route explains why. Only a path or query may
+    // follow the slug, so this can't point at another site.
+    let next = if form.next.starts_with('/') || form.next.starts_with('?') {
+        form.next.as_str()
+    } else {
+        ""
+    };
+    let location = format!("/{}{}", urlencoding::encode(&record.slug), next);
+    let Some(password_hash) = record.password_hash.clone() else {
+        return Redirect::to(&location).into_response();
+    };
+    if unavailable_reason(&record).is_some() {
+        return Redirect::to(&location).into_response();
+    }
+
+    let limit_key = format!("{}:{}", client_ip(&headers, peer, state.trust_proxy), record.id);
+    if state.password_limiter.is_limited(&limit_key) {
+        return (
+            StatusCode::TOO_MANY_REQUESTS,
+            Html(pages::password_form(&record.slug, next, Some("Too many attempts. Try again later."))),
+        )
+            .into_response();
+    }
+
+    if !verify_password_async(form.password, password_hash.clone()).await {
+        state.password_limiter.record_failure(&limit_key);
+        return (
+            StatusCode::UNAUTHORIZED,
+            Html(pages::password_form(&record.slug, next, Some("Incorrect password."))),
+        )
+            .into_response();
+    }
+    state.password_limiter.reset(&limit_key);
+
+    let token = state
+        .signer
+        .sign_expiring(&unlock_message(&record, &password_hash), UNLOCK_TTL_SECS);
+    let cookie = format!(
+        "{}={}; Path=/; HttpOnly;{} SameSite=Lax; Max-Age={}",
+        unlock_cookie_name(&record),
+        token,
+        secure_attribute(&state),
+        UNLOCK_TTL_SECS
+    );
+
+    // Back to the GET route, which counts the click and redirects
+    (
+        StatusCode::SEE_OTHER,
+        [(header::LOCATION, location), (header::SET_COOKIE, cookie)],
+    )
+        .into_response()
+}
+
+/// Why a link can't be followed right now.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Unavailable {
+    Disabled,
+    Expired,
+    Exhausted,
+    NotYetActive,
+}
+
+impl Unavailable {
+    fn message(self) -> &'static str {
+        match self {
+            Self::Disabled => "URL has been disabled",
+            Self::Expired => "URL has expired",
+            Self::Exhausted => "URL has reached its click limit",
+            Self::NotYetActive => "URL is not available yet",
+        }
+    }
+
+    fn status(self) -> StatusCode {
+        match self {
+            Self::NotYetActive => StatusCode::NOT_FOUND,
+            _ => StatusCode::GONE,
+        }
+    }
+}
+
+pub fn unavailable_reason(record: &UrlRecord) -> Option<Unavailable> {
+    if record.disabled {
+        return Some(Unavailable::Disabled);
+    }
+    if is_expired(record) {
+        return Some(Unavailable::Expired);
+    }
+    if record.max_clicks.is_some_and(|max| record.clicks >= max) {
+        return Some(Unavailable::Exhausted);
+    }
+    if pending_activation(record).is_some() {
+        return Some(Unavailable::NotYetActive);
+    }
+    None
+}
+
+// Most specific wins: the link's own settings, then the instance defaults,
+// then the built-in response
+fn unavailable_response(state: &AppState, record: &UrlRecord, reason: Unavailable) -> Response {
+    if reason == Unavailable::NotYetActive {
+        if let Some(pending_url) = &record.pending_url {
+            return Redirect::temporary(pending_url).into_response();
+        }
+    }
+    if let Some(fallback_url) = &record.fallback_url {
+        return Redirect::temporary(fallback_url).into_response();
+    }
+    if let Some(page) = &record.fallback_page {
+        return (reason.status(), Html(pages::fallback(page, &record.slug, reason.message())))
+            .into_response();
+    }
+
+    // A dedicated pending page beats the generic instance fallback
+    let activates_at = record.activates_at.map(format_utc).unwrap_or_default();
+    if reason == Unavailable::NotYetActive && state.pending_page.is_some() {
+        return (
+            reason.status(),
+            Html(pages::not_yet_active(state.pending_page.as_deref(), &activates_at)),
+        )
+            .into_response();
+    }
+    if let Some(fallback_url) = &state.fallback.url {
+        return Redirect::temporary(fallback_url).into_response();
+    }
+    if let Some(page) = &state.fallback.page {
+        return (reason.status(), Html(pages::fallback(page, &record.slug, reason.message())))
+            .into_response();
+    }
+    if reason == Unavailable::NotYetActive {
+        return (reason.status(), Html(pages::not_yet_active(None, &activates_at))).into_response();
+    }
+
+    (reason.status(), reason.message()).into_response()
+}
+
+// A link stops working the moment its expiry is reached
+fn is_expired(record: &UrlRecord) -> bool {
+    record.expires_at.is_some_and(|expiry| expiry <= chrono::Utc::now())
+}
+
+// The activation time, while it is still in the future
+fn pending_activation(record: &UrlRecord) -> Option<chrono::DateTime<chrono::Utc>> {
+    record.activates_at.filter(|&activation| activation > chrono::Utc::now())
+}
+
+fn utm_params(record: &UrlRecord) -> Vec<(&'static str, &str)> {
+    [
+        ("utm_source", &record.utm_source),
+        ("utm_medium", &record.utm_medium),
+        ("utm_campaign", &record.utm_campaign),
+        ("utm_term", &record.utm_term),
+        ("utm_content", &record.utm_content),
+    ]
+    .into_iter()
+    .filter_map(|(key, value)| Some((key, value.as_deref().filter(|v| !v.is_empty())?)))
+    .collect()
+}
+
+// The path and query that followed the slug, re-encoded for use in a URL
+fn request_suffix(extra_path: Option<&str>, query: Option<&str>) -> String {
+    let mut suffix = String::new();
+    if let Some(extra_path) = extra_path {
+        for segment in extra_path.trim_start_matches('/').split('/') {
+            suffix.push('/');
+            suffix.push_str(&urlencoding::encode(segment));
+        }
+    }
+    if let Some(query) = query {
+        suffix.push('?');
+        suffix.push_str(query);
+    }
+    suffix
+}
+
+// Cookies are only marked Secure when the instance is served over HTTPS
+pub fn secure_attribute(state: &AppState) -> &'static str {
+    if state.base_url.starts_with("https") {
+        " Secure;"
+    } else {
+        ""
+    }
+}
+
+fn unlock_cookie_name(record: &UrlRecord) -> String {
+    format!("meo_unlock_{}", record.id)
+}
+
+// Binding the hash means changing the password invalidates earlier unlocks
+fn unlock_message(record: &UrlRecord, password_hash: &str) -> String {
+    format!("unlock:{}:{}", record.id, password_hash)
+}
+
+fn header_value(headers: &HeaderMap, name: header::HeaderName) -> Option<String> {
+    headers
+        .get(name)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{rngs::StdRng, SeedableRng};
+
+    fn destination(id: i64, weight: i64) -> RotationDestination {
+        RotationDestination {
+            id,
+            url: format!("https://example.com/{}", id),
+            weight,
+            clicks: 0,
+        }
+    }
+
+    #[test]
+    fn rotation_follows_weights() {
+        let destinations = [destination(1, 1), destination(2, 0), destination(3, 3), destination(4, -1)];
+        let mut rng = StdRng::seed_from_u64(7);
+        let mut picks = [0; 5];
+        for _ in 0..8000 {
+            picks[pick_weighted(&destinations, &mut rng).unwrap().id as usize] += 1;
+        }
+        assert_eq!((picks[2], picks[4]), (0, 0));
+        // 1:3 within a few percent
+        assert!((1800..2200).contains(&picks[1]), "{:?}", picks);
+        assert!((5800..6200).contains(&picks[3]), "{:?}", picks);
+    }
+
+    #[test]
+    fn rotation_without_weight_picks_nothing() {
+        let mut rng = StdRng::seed_from_u64(7);
+        assert!(pick_weighted(&[], &mut rng).is_none());
+        assert!(pick_weighted(&[destination(1, 0), destination(2, 0)], &mut rng).is_none());
+        let only = [destination(1, 0), destination(2, 5)];
+        assert!((0..100).all(|_| pick_weighted(&only, &mut rng).unwrap().id == 2));
+    }
 }
@@ -1,18 +1,21 @@
-use axum::{
-    extract::State,
-    http::StatusCode,
-    response::IntoResponse,
-    Json,
-};
+use axum::{extract::State, Json};
 use rand::Rng;
 use std::sync::Arc;
 
 use crate::{
-    models::{CreateUrlRequest, CreateUrlResponse},
+    auth::OptionalUser,
+    db::Database,
+    destination::QueryPassthrough,
+    error::{ApiJson, AppError, AppResult},
+    expiry::parse_activation,
+    handlers::redirect::{is_redirect_status, is_servable_slug},
+    metadata::spawn_fetch,
+    models::{CreateUrlRequest, CreateUrlResponse, NewUrl},
+    password::hash_password_async,
     AppState,
 };
 
-fn generate_slug(length: usize) -> String {
+pub fn generate_slug(length: usize) -> String {
     const CHARS: &[u8] = b"abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ0123456789";
     let mut rng = rand::thread_rng();
     (0..length)
@@ -20,63 +23,162 @@ fn generate_slug(length: usize) -> String {
         .collect()
 }
 
+/// Whether a destination is an absolute http(s) URL, the only kind links may
+/// send visitors to.
+pub fn is_http_url(value: &str) -> bool {
+    url::Url::parse(value).is_ok_and(|url| matches!(url.scheme(), "http" | "https"))
+}
+
+/// Refuses a destination blocked by the domain policy or listed in a threat feed.
+pub fn check_destination(state: &AppState, url: &str) -> AppResult<()> {
+    state.domain_policy.check(url)?;
+    match state.threat_feeds.check(url) {
+        Some(threat) => Err(threat.into()),
+        None => Ok(()),
+    }
+}
+
 pub async fn create_short_url(
     State(state): State<Arc<AppState>>,
-    Json(payload): Json<CreateUrlRequest>,
-) -> impl IntoResponse {
-    if payload.url.is_empty() {
-        return (
-            StatusCode::BAD_REQUEST,
-            Json(serde_json::json!({"error": "URL is required"})),
-        );
+    OptionalUser(user): OptionalUser,
+    ApiJson(payload): ApiJson<CreateUrlRequest>,
+) -> AppResult<Json<CreateUrlResponse>> {
+    let destinations = [Some(&payload.url), payload.pending_url.as_ref(), payload.fallback_url.as_ref()];
+    for url in destinations.into_iter().flatten() {
+        if !is_http_url(url) {
+            return Err(AppError::bad_request("invalid_url", "URLs must start with http:// or https://"));
+        }
+        check_destination(&state, url)?;
     }
 
     let is_custom_slug = payload.custom_slug.is_some();
-    let mut slug = payload.custom_slug.clone().unwrap_or_else(|| generate_slug(6));
-    slug = slug.trim().to_string();
+    let slug = payload.custom_slug.clone().unwrap_or_else(|| generate_slug(6));
+    if is_custom_slug && !is_servable_slug(slug.trim()) {
+        return Err(AppError::bad_request(
+            "invalid_slug",
+            "Slug can't contain '.', '/', '?' or '#', end with '+' or be a reserved path",
+        ));
+    }
+
+    let max_clicks = if payload.one_time {
+        Some(1)
+    } else {
+        payload.max_clicks
+    };
+    if max_clicks.is_some_and(|max| max < 1) {
+        return Err(AppError::invalid_input("maxClicks must be at least 1"));
+    }
+
+    let expires_at = state
+        .expiry
+        .for_new_link(payload.expires_at.as_deref())
+        .map_err(|error| AppError::bad_request("invalid_expiry", error))?;
+
+    let activates_at = match &payload.activates_at {
+        Some(activates_at) => Some(
+            parse_activation(activates_at)
+                .ok_or_else(|| AppError::invalid_input("activatesAt must be an RFC 3339 timestamp"))?,
+        ),
+        None => None,
+    };
+    if let (Some(expiry), Some(activation)) = (expires_at, activates_at) {
+        if expiry <= activation {
+            return Err(AppError::bad_request(
+                "invalid_expiry",
+                "expiresAt must be after activatesAt",
+            ));
+        }
+    }
 
-    let expires_at = payload.expires_at.as_deref();
+    if payload.redirect_type.is_some_and(|status| !is_redirect_status(status)) {
+        return Err(AppError::invalid_input("redirectType must be 301, 302, 307 or 308"));
+    }
+
+    if payload
+        .query_passthrough
+        .as_deref()
+        .is_some_and(|mode| QueryPassthrough::parse(mode).is_none())
+    {
+        return Err(AppError::invalid_input("queryPassthrough must be \"merge\" or \"override\""));
+    }
+
+    let password_hash = match payload.password.clone().filter(|p| !p.is_empty()) {
+        Some(password) => Some(
+            hash_password_async(password)
+                .await
+                .ok_or_else(|| AppError::internal("Failed to hash password"))?,
+        ),
+        None => None,
+    };
+
+    let mut new_url = NewUrl {
+        slug: slug.trim().to_string(),
+        original_url: payload.url.clone(),
+        expires_at,
+        title: payload.title.clone(),
+        description: payload.description.clone(),
+        notes: payload.notes.clone(),
+        password_hash,
+        max_clicks,
+        activates_at,
+        pending_url: payload.pending_url.clone(),
+        fallback_url: payload.fallback_url.clone(),
+        redirect_type: payload.redirect_type,
+        query_passthrough: payload.query_passthrough.clone(),
+        path_passthrough: payload.path_passthrough,
+        utm: payload.utm.clone(),
+        parent_id: None,
+        anonymous: user.is_none(),
+        owner_id: user.as_ref().map(|user| user.id.clone()),
+        created_by: user.map(|user| user.username),
+        ..Default::default()
+    };
+
+    let id = insert_with_unique_slug(&state.db, &mut new_url, is_custom_slug).await?;
+
+    // Look up the page title in the background when none was given
+    if state.fetch_link_metadata && new_url.title.is_none() {
+        spawn_fetch(state.db.clone(), state.fetch.clone(), id, new_url.original_url.clone());
+    }
+
+    // Success! Return the response
+    Ok(Json(CreateUrlResponse {
+        success: true,
+        short_url: format!("{}/{}", state.base_url, new_url.slug),
+        slug: new_url.slug,
+        original_url: payload.url,
+        expires_at,
+        max_clicks,
+        activates_at,
+    }))
+}
+
+/// Inserts `new_url`, replacing its slug on collisions unless it was chosen by the user.
+pub async fn insert_with_unique_slug(
+    db: &Database,
+    new_url: &mut NewUrl,
+    is_custom_slug: bool,
+) -> AppResult<i64> {
     const MAX_RETRIES: u32 = 5;
 
     // Try to insert, retry on UNIQUE constraint violation (for auto-generated slugs only)
     for attempt in 0..MAX_RETRIES {
-        match state.db.insert_url(&slug, &payload.url, expires_at).await {
-            Ok(()) => {
-                // Success! Return the response
-                let response = CreateUrlResponse {
-                    success: true,
-                    short_url: format!("{}/{}", state.base_url, slug),
-                    slug: slug.clone(),
-                    original_url: payload.url,
-                    expires_at: payload.expires_at,
-                };
-                return (StatusCode::OK, Json(serde_json::to_value(response).unwrap()));
-            }
+        match db.insert_url(new_url).await {
+            Ok(id) => return Ok(id),
             Err(sqlx::Error::Database(db_err)) if db_err.is_unique_violation() => {
                 if is_custom_slug {
                     // Custom slug collision - return conflict error
-                    return (
-                        StatusCode::CONFLICT,
-                        Json(serde_json::json!({"error": "Slug already exists"})),
-                    );
+                    return Err(AppError::conflict("slug_taken", "Slug already exists"));
                 }
                 // Auto-generated slug collision - retry with new slug
                 if attempt < MAX_RETRIES - 1 {
-                    slug = generate_slug(6);
+                    new_url.slug = generate_slug(6);
                 }
             }
-            Err(_) => {
-                return (
-                    StatusCode::INTERNAL_SERVER_ERROR,
-                    Json(serde_json::json!({"error": "Database error"})),
-                );
-            }
+            Err(e) => return Err(e.into()),
         }
     }
 
     // All retries exhausted
-    (
-        StatusCode::INTERNAL_SERVER_ERROR,
-        Json(serde_json::json!({"error": "Failed to generate unique slug"})),
-    )
+    Err(AppError::internal("Failed to generate unique slug"))
 }
@@ -0,0 +1,100 @@
+use axum::{
+    extract::{Path, State},
+    Json,
+};
+use std::sync::Arc;
+
+use crate::{
+    auth::{Admin, CurrentUser, RequireRole},
+    error::{conflict_on_unique, ApiJson, AppError, AppResult},
+    handlers::admin::owned_url,
+    models::{IdResponse, NameRequest, SetTagsRequest, SuccessResponse, TagStats},
+    AppState,
+};
+
+const MAX_NAME_LENGTH: usize = 64;
+
+/// Trims a tag or folder name and rejects empty, overly long or comma-containing names
+/// (tags are exposed on links as a comma-separated list).
+pub fn normalize_name(name: &str) -> AppResult<String> {
+    let name = name.trim();
+    let error = if name.is_empty() {
+        "Name is required"
+    } else if name.chars().count() > MAX_NAME_LENGTH {
+        "Name is too long"
+    } else if name.contains(',') {
+        "Name must not contain commas"
+    } else {
+        return Ok(name.to_string());
+    };
+    Err(AppError::bad_request("invalid_name", error))
+}
+
+pub async fn list_tags(
+    State(state): State<Arc<AppState>>,
+    _user: CurrentUser,
+) -> AppResult<Json<Vec<TagStats>>> {
+    Ok(Json(state.db.get_tag_stats().await?))
+}
+
+pub async fn create_tag(
+    State(state): State<Arc<AppState>>,
+    _user: CurrentUser,
+    ApiJson(payload): ApiJson<NameRequest>,
+) -> AppResult<Json<IdResponse>> {
+    let name = normalize_name(&payload.name)?;
+    let id = state
+        .db
+        .insert_tag(&name)
+        .await
+        .map_err(conflict_on_unique("tag_exists", "Tag already exists"))?;
+    Ok(Json(IdResponse { success: true, id }))
+}
+
+/// Tags are shared by everyone's links, so only admins rename and delete them.
+pub async fn rename_tag(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<i64>,
+    _admin: RequireRole<Admin>,
+    ApiJson(payload): ApiJson<NameRequest>,
+) -> AppResult<Json<SuccessResponse>> {
+    let name = normalize_name(&payload.name)?;
+    let renamed = state
+        .db
+        .rename_tag(id, &name)
+        .await
+        .map_err(conflict_on_unique("tag_exists", "Tag already exists"))?;
+    if !renamed {
+        return Err(AppError::not_found("Tag not found"));
+    }
+    Ok(Json(SuccessResponse { success: true }))
+}
+
+pub async fn delete_tag(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<i64>,
+    _admin: RequireRole<Admin>,
+) -> AppResult<Json<SuccessResponse>> {
+    state.db.delete_tag(id).await?;
+    Ok(Json(SuccessResponse { success: true }))
+}
+
+pub async fn set_url_tags(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<i64>,
+    CurrentUser(user): CurrentUser,
+    ApiJson(payload): ApiJson<SetTagsRequest>,
+) -> AppResult<Json<SuccessResponse>> {
+    owned_url(&state, &user, id).await?;
+    let mut tags = Vec::with_capacity(payload.tags.len());
+    for tag in &payload.tags {
+        let tag = normalize_name(tag)?;
+        if !tags.contains(&tag) {
+            tags.push(tag);
+        }
+    }
+    if !state.db.set_url_tags(id, &tags).await? {
+        return Err(AppError::not_found("URL not found"));
+    }
+    Ok(Json(SuccessResponse { success: true }))
+}
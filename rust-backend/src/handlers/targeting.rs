@@ -0,0 +1,165 @@
+use axum::{
+    extract::{Path, State},
+    Json,
+};
+use std::sync::Arc;
+
+use crate::{
+    auth::CurrentUser,
+    error::{ApiJson, AppError, AppResult},
+    handlers::{
+        admin::owned_url,
+        shorten::{check_destination, is_http_url},
+    },
+    models::{
+        DeviceRule, GeoRule, Rotation, SetDeviceRulesRequest, SetGeoRulesRequest,
+        SetRotationRequest, SuccessResponse,
+    },
+    user_agent::{BROWSERS, PLATFORMS},
+    AppState,
+};
+
+pub async fn get_device_rules(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<i64>,
+    CurrentUser(user): CurrentUser,
+) -> AppResult<Json<Vec<DeviceRule>>> {
+    owned_url(&state, &user, id).await?;
+    Ok(Json(state.db.get_device_rules(id).await?))
+}
+
+/// Replaces a link's device rules. Visitors matching none of them get the link's own destination.
+pub async fn set_device_rules(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<i64>,
+    CurrentUser(user): CurrentUser,
+    ApiJson(mut payload): ApiJson<SetDeviceRulesRequest>,
+) -> AppResult<Json<SuccessResponse>> {
+    owned_url(&state, &user, id).await?;
+    for rule in &mut payload.rules {
+        rule.platform = rule.platform.take().map(|p| p.trim().to_ascii_lowercase());
+        rule.browser = rule.browser.take().map(|b| b.trim().to_ascii_lowercase());
+        if rule.platform.is_none() && rule.browser.is_none() {
+            return Err(AppError::invalid_input(
+                "Each rule needs a platform or a browser",
+            ));
+        } else if let Some(platform) = rule.platform.as_deref().filter(|p| !PLATFORMS.contains(p)) {
+            return Err(AppError::invalid_input(format!(
+                "Unknown platform \"{}\", expected one of {}",
+                platform,
+                PLATFORMS.join(", ")
+            )));
+        } else if let Some(browser) = rule.browser.as_deref().filter(|b| !BROWSERS.contains(b)) {
+            return Err(AppError::invalid_input(format!(
+                "Unknown browser \"{}\", expected one of {}",
+                browser,
+                BROWSERS.join(", ")
+            )));
+        } else if !is_http_url(&rule.url) {
+            return Err(AppError::bad_request(
+                "invalid_url",
+                "Each rule needs an http(s) URL",
+            ));
+        }
+        check_destination(&state, &rule.url)?;
+    }
+    if !state.db.set_device_rules(id, &payload.rules).await? {
+        return Err(AppError::not_found("URL not found"));
+    }
+    Ok(Json(SuccessResponse { success: true }))
+}
+
+pub async fn get_geo_rules(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<i64>,
+    CurrentUser(user): CurrentUser,
+) -> AppResult<Json<Vec<GeoRule>>> {
+    owned_url(&state, &user, id).await?;
+    Ok(Json(state.db.get_geo_rules(id).await?))
+}
+
+/// Replaces a link's geo rules. They only apply when a GeoIP database is configured.
+pub async fn set_geo_rules(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<i64>,
+    CurrentUser(user): CurrentUser,
+    ApiJson(mut payload): ApiJson<SetGeoRulesRequest>,
+) -> AppResult<Json<SuccessResponse>> {
+    owned_url(&state, &user, id).await?;
+    let mut seen = Vec::with_capacity(payload.rules.len());
+    for rule in &mut payload.rules {
+        rule.country = rule.country.trim().to_ascii_uppercase();
+        if rule.country.len() != 2 || !rule.country.chars().all(|c| c.is_ascii_alphabetic()) {
+            return Err(AppError::invalid_input(format!(
+                "\"{}\" is not a two-letter country code",
+                rule.country
+            )));
+        } else if seen.contains(&rule.country) {
+            return Err(AppError::invalid_input(format!(
+                "Country {} is listed more than once",
+                rule.country
+            )));
+        } else if !is_http_url(&rule.url) {
+            return Err(AppError::bad_request(
+                "invalid_url",
+                "Each rule needs an http(s) URL",
+            ));
+        }
+        check_destination(&state, &rule.url)?;
+        seen.push(rule.country.clone());
+    }
+    if !state.db.set_geo_rules(id, &payload.rules).await? {
+        return Err(AppError::not_found("URL not found"));
+    }
+    Ok(Json(SuccessResponse { success: true }))
+}
+
+/// A link's rotation destinations with the clicks each one received.
+pub async fn get_rotation(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<i64>,
+    CurrentUser(user): CurrentUser,
+) -> AppResult<Json<Rotation>> {
+    let record = owned_url(&state, &user, id).await?;
+    Ok(Json(Rotation {
+        sticky: record.sticky_rotation,
+        destinations: state.db.get_rotation(id).await?,
+    }))
+}
+
+/// Replaces the destinations a link rotates between. An empty list sends every
+/// visitor to the link's own destination again.
+pub async fn set_rotation(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<i64>,
+    CurrentUser(user): CurrentUser,
+    ApiJson(payload): ApiJson<SetRotationRequest>,
+) -> AppResult<Json<SuccessResponse>> {
+    owned_url(&state, &user, id).await?;
+    for (i, destination) in payload.destinations.iter().enumerate() {
+        if !is_http_url(&destination.url) {
+            return Err(AppError::bad_request(
+                "invalid_url",
+                "Each destination needs an http(s) URL",
+            ));
+        } else if destination.weight < 0 {
+            return Err(AppError::invalid_input("Weights can't be negative"));
+        } else if payload.destinations[..i]
+            .iter()
+            .any(|d| d.url == destination.url)
+        {
+            return Err(AppError::invalid_input(
+                "Each destination can only be listed once",
+            ));
+        }
+        check_destination(&state, &destination.url)?;
+    }
+    if !state
+        .db
+        .set_rotation(id, &payload.destinations, payload.sticky)
+        .await?
+    {
+        return Err(AppError::not_found("URL not found"));
+    }
+    Ok(Json(SuccessResponse { success: true }))
+}
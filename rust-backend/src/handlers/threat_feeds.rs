@@ -0,0 +1,30 @@
+use axum::{extract::State, Json};
+use std::sync::Arc;
+
+use crate::{
+    auth::{Admin, RequireRole},
+    error::AppResult,
+    threat_feed::FeedSummary,
+    AppState,
+};
+
+pub async fn list_feeds(
+    State(state): State<Arc<AppState>>,
+    _admin: RequireRole<Admin>,
+) -> AppResult<Json<Vec<FeedSummary>>> {
+    Ok(Json(state.threat_feeds.summaries()))
+}
+
+/// Picks up changed feed files and screens every link now instead of waiting
+/// for the next scheduled scan.
+pub async fn scan(
+    State(state): State<Arc<AppState>>,
+    _admin: RequireRole<Admin>,
+) -> AppResult<Json<serde_json::Value>> {
+    let feeds = state.threat_feeds.clone();
+    let _ = tokio::task::spawn_blocking(move || feeds.reload_if_changed()).await;
+    let matched = state.threat_feeds.screen_links(&state.db).await?;
+    Ok(Json(
+        serde_json::json!({"success": true, "matched": matched}),
+    ))
+}
@@ -0,0 +1,108 @@
+use axum::{
+    extract::{Path, State},
+    Json,
+};
+use std::sync::Arc;
+
+use crate::{
+    auth::CurrentUser,
+    error::{ApiJson, AppError, AppResult},
+    handlers::{
+        admin::owned_url,
+        redirect::is_servable_slug,
+        shorten::{check_destination, generate_slug, insert_with_unique_slug},
+    },
+    models::{CreateUrlResponse, CreateVariantRequest, NewUrl, VariantGroup},
+    AppState,
+};
+
+/// Lists a link's UTM variants together with the group's combined clicks.
+pub async fn list_variants(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<i64>,
+    CurrentUser(user): CurrentUser,
+) -> AppResult<Json<VariantGroup>> {
+    let parent = owned_url(&state, &user, id).await?;
+    let variants = state.db.get_variants(parent.id).await?;
+    let total_clicks = parent.clicks + variants.iter().map(|v| v.clicks).sum::<i64>();
+    Ok(Json(VariantGroup {
+        parent,
+        variants,
+        total_clicks,
+    }))
+}
+
+/// Creates a new short link for the same destination with its own UTM parameters.
+pub async fn create_variant(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<i64>,
+    CurrentUser(user): CurrentUser,
+    ApiJson(payload): ApiJson<CreateVariantRequest>,
+) -> AppResult<Json<CreateUrlResponse>> {
+    let parent = owned_url(&state, &user, id).await?;
+
+    // A variant can't share the parent's click count, so it would be a way
+    // around the limit
+    if parent.disabled || parent.max_clicks.is_some() {
+        return Err(AppError::bad_request(
+            "variant_not_allowed",
+            "Disabled and click-limited links can't have variants",
+        ));
+    }
+    let destinations = [
+        Some(&parent.original_url),
+        parent.pending_url.as_ref(),
+        parent.fallback_url.as_ref(),
+    ];
+    for url in destinations.into_iter().flatten() {
+        check_destination(&state, url)?;
+    }
+
+    // Variants of a variant join the original group so stats stay in one place.
+    // They keep the parent's protections: password, schedule, fallbacks and
+    // the untrusted-link warning.
+    let mut new_url = NewUrl {
+        slug: payload.slug.clone().unwrap_or_default().trim().to_string(),
+        original_url: parent.original_url.clone(),
+        expires_at: parent.expires_at,
+        title: parent.title.clone(),
+        description: parent.description.clone(),
+        password_hash: parent.password_hash.clone(),
+        activates_at: parent.activates_at,
+        pending_url: parent.pending_url.clone(),
+        fallback_url: parent.fallback_url.clone(),
+        fallback_page: parent.fallback_page.clone(),
+        redirect_type: parent.redirect_type,
+        query_passthrough: parent.query_passthrough.clone(),
+        path_passthrough: parent.path_passthrough,
+        utm: payload.utm,
+        parent_id: Some(parent.parent_id.unwrap_or(parent.id)),
+        created_by: Some(user.username),
+        // Variants belong with their group, even when an admin adds one
+        owner_id: parent.owner_id.clone(),
+        untrusted: parent.untrusted,
+        untrusted_reason: parent.untrusted_reason.clone(),
+        ..Default::default()
+    };
+    let is_custom_slug = !new_url.slug.is_empty();
+    if is_custom_slug && !is_servable_slug(&new_url.slug) {
+        return Err(AppError::bad_request(
+            "invalid_slug",
+            "Slug can't contain '.', '/', '?' or '#', end with '+' or be a reserved path",
+        ));
+    }
+    if !is_custom_slug {
+        new_url.slug = generate_slug(6);
+    }
+
+    insert_with_unique_slug(&state.db, &mut new_url, is_custom_slug).await?;
+    Ok(Json(CreateUrlResponse {
+        success: true,
+        short_url: format!("{}/{}", state.base_url, new_url.slug),
+        slug: new_url.slug,
+        original_url: new_url.original_url,
+        expires_at: new_url.expires_at,
+        max_clicks: None,
+        activates_at: new_url.activates_at,
+    }))
+}
@@ -0,0 +1,258 @@
+use reqwest::Method;
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::{sync::Semaphore, task::JoinSet};
+
+use crate::{
+    db::Database,
+    fetch::{fetch, FetchConfig, FetchError},
+};
+
+// How often the checker looks for links that are due, and how many it takes at once
+const SWEEP_SECS: u64 = 60;
+const BATCH_SIZE: i64 = 200;
+
+/// Periodic destination checks. Disabled unless `HEALTH_CHECK_INTERVAL_SECS`
+/// is set, since every link's destination gets requested.
+#[derive(Debug, Clone)]
+pub struct HealthConfig {
+    // Seconds between checks of a working link; doubled per failure in a row
+    pub interval_secs: Option<i64>,
+    // Checks running at the same time
+    pub concurrency: usize,
+}
+
+impl HealthConfig {
+    pub fn from_env() -> Self {
+        let interval_secs = std::env::var("HEALTH_CHECK_INTERVAL_SECS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .filter(|&secs| secs > 0);
+        let concurrency = std::env::var("HEALTH_CHECK_CONCURRENCY")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .filter(|&n| n > 0)
+            .unwrap_or(4);
+
+        Self {
+            interval_secs,
+            concurrency,
+        }
+    }
+}
+
+/// The outcome of requesting a link's destination.
+#[derive(Debug, Clone, Default)]
+pub struct HealthCheck {
+    pub status: Option<u16>,
+    pub error: Option<String>,
+    pub latency_ms: i64,
+    pub chain: Vec<String>,
+}
+
+impl HealthCheck {
+    pub fn is_broken(&self) -> bool {
+        self.error.is_some() || self.status.is_some_and(|status| status >= 400)
+    }
+}
+
+/// Requests `url` with HEAD, retrying with GET when HEAD gets a client error,
+/// 501 or no answer, since some servers only answer GET properly.
+pub async fn check(config: &FetchConfig, url: &str) -> HealthCheck {
+    let started = Instant::now();
+    let head = fetch(config, Method::HEAD, url).await;
+    let retry = match &head {
+        Ok(page) => (400..500).contains(&page.status) || page.status == 501,
+        Err(FetchError::InvalidUrl | FetchError::BlockedAddress) => false,
+        Err(_) => true,
+    };
+    let (result, started) = if retry {
+        let started = Instant::now();
+        (fetch(config, Method::GET, url).await, started)
+    } else {
+        (head, started)
+    };
+    let latency_ms = started.elapsed().as_millis() as i64;
+
+    match result {
+        Ok(page) => HealthCheck {
+            status: Some(page.status),
+            error: None,
+            latency_ms,
+            chain: page.chain,
+        },
+        Err(e) => HealthCheck {
+            status: None,
+            error: Some(e.to_string()),
+            latency_ms,
+            chain: vec![url.to_string()],
+        },
+    }
+}
+
+/// Checks each of a link's destinations in turn, stopping at the first broken
+/// one. The result is that one's, or the first destination's when all work.
+pub async fn check_all(config: &FetchConfig, urls: &[String]) -> HealthCheck {
+    let mut first = None;
+    for url in urls {
+        let result = check(config, url).await;
+        if result.is_broken() {
+            return result;
+        }
+        first.get_or_insert(result);
+    }
+    first.unwrap_or_default()
+}
+
+/// Checks links as they become due, at most `concurrency` at a time.
+pub fn spawn_checker(db: Database, fetch_config: FetchConfig, config: HealthConfig) {
+    let Some(interval_secs) = config.interval_secs else {
+        return;
+    };
+    let permits = Arc::new(Semaphore::new(config.concurrency));
+
+    tokio::spawn(async move {
+        let mut sweep = tokio::time::interval(Duration::from_secs(SWEEP_SECS));
+        loop {
+            sweep.tick().await;
+            let due = match db.get_due_health_checks(interval_secs, BATCH_SIZE).await {
+                Ok(due) => due,
+                Err(e) => {
+                    tracing::error!("Failed to load links for health checks: {}", e);
+                    continue;
+                }
+            };
+
+            let mut checks = JoinSet::new();
+            for id in due {
+                let permit = permits.clone().acquire_owned().await.unwrap();
+                let db = db.clone();
+                let fetch_config = fetch_config.clone();
+                checks.spawn(async move {
+                    let urls = match db.get_destinations(id).await {
+                        Ok(urls) => urls,
+                        Err(e) => {
+                            tracing::error!("Failed to load destinations of link {}: {}", id, e);
+                            return;
+                        }
+                    };
+                    let result = check_all(&fetch_config, &urls).await;
+                    drop(permit);
+                    if result.is_broken() {
+                        let reason = result
+                            .error
+                            .clone()
+                            .or_else(|| result.status.map(|status| format!("HTTP {}", status)));
+                        tracing::warn!(
+                            "Link {} destination {} is broken: {}",
+                            id,
+                            result.chain.first().map(String::as_str).unwrap_or_default(),
+                            reason.unwrap_or_default()
+                        );
+                    }
+                    if let Err(e) = db.record_health(id, &result).await {
+                        tracing::error!("Failed to store health check for link {}: {}", id, e);
+                    }
+                });
+            }
+            while checks.join_next().await.is_some() {}
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{http::StatusCode, response::Redirect, routing::get, Router};
+
+    fn local_config() -> FetchConfig {
+        FetchConfig {
+            timeout: Duration::from_secs(2),
+            max_bytes: 64,
+            allow_private: true,
+        }
+    }
+
+    // Serves pages that answer HEAD and GET differently on a random local port
+    async fn serve() -> String {
+        let app = Router::new()
+            .route("/ok", get(|| async { "ok" }))
+            .route(
+                "/no-head",
+                get(|| async { "ok" }).head(|| async { StatusCode::METHOD_NOT_ALLOWED }),
+            )
+            .route(
+                "/head-forbidden",
+                get(|| async { "ok" }).head(|| async { StatusCode::FORBIDDEN }),
+            )
+            .route("/missing", get(|| async { StatusCode::NOT_FOUND }))
+            .route(
+                "/error",
+                get(|| async { StatusCode::INTERNAL_SERVER_ERROR }),
+            )
+            .route("/moved", get(|| async { Redirect::permanent("/ok") }));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        format!("http://{}", addr)
+    }
+
+    #[tokio::test]
+    async fn working_destination() {
+        let base = serve().await;
+        let result = check(&local_config(), &format!("{}/moved", base)).await;
+        assert!(!result.is_broken());
+        assert_eq!(result.status, Some(200));
+        assert_eq!(
+            result.chain,
+            vec![format!("{}/moved", base), format!("{}/ok", base)]
+        );
+    }
+
+    #[tokio::test]
+    async fn retries_head_client_errors_with_get() {
+        let base = serve().await;
+        for path in ["/no-head", "/head-forbidden"] {
+            let result = check(&local_config(), &format!("{}{}", base, path)).await;
+            assert_eq!(result.status, Some(200), "{}", path);
+        }
+    }
+
+    #[tokio::test]
+    async fn broken_destinations() {
+        let base = serve().await;
+        for (path, status) in [("/missing", 404), ("/error", 500)] {
+            let result = check(&local_config(), &format!("{}{}", base, path)).await;
+            assert!(result.is_broken());
+            assert_eq!(result.status, Some(status));
+        }
+
+        // Nothing listens on port 9 locally
+        let url = "http://127.0.0.1:9/";
+        let result = check(&local_config(), url).await;
+        assert!(result.is_broken());
+        assert_eq!(result.status, None);
+        assert_eq!(result.chain, vec![url.to_string()]);
+    }
+
+    #[tokio::test]
+    async fn reports_first_broken_destination() {
+        let base = serve().await;
+        let urls = |paths: &[&str]| -> Vec<String> {
+            paths
+                .iter()
+                .map(|path| format!("{}{}", base, path))
+                .collect()
+        };
+
+        let result = check_all(&local_config(), &urls(&["/ok", "/moved"])).await;
+        assert!(!result.is_broken());
+        assert_eq!(result.chain, urls(&["/ok"]));
+
+        let result = check_all(&local_config(), &urls(&["/ok", "/missing", "/error"])).await;
+        assert_eq!(result.status, Some(404));
+        assert_eq!(result.chain, urls(&["/missing"]));
+    }
+}
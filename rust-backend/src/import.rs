@@ -0,0 +1,589 @@
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+
+use crate::{
+    db::Database,
+    domain_policy::DomainPolicy,
+    expiry::format_utc,
+    handlers::{redirect::is_servable_slug, shorten::is_http_url},
+    threat_feed::ThreatFeeds,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImportSource {
+    Yourls,
+    Shlink,
+    Kutt,
+    Bitly,
+}
+
+// Accepted column / key names per source, compared lowercased
+struct FieldAliases {
+    slug: &'static [&'static str],
+    url: &'static [&'static str],
+    created_at: &'static [&'static str],
+    clicks: &'static [&'static str],
+    expires_at: &'static [&'static str],
+}
+
+impl ImportSource {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.to_ascii_lowercase().as_str() {
+            "yourls" => Some(Self::Yourls),
+            "shlink" => Some(Self::Shlink),
+            "kutt" => Some(Self::Kutt),
+            "bitly" => Some(Self::Bitly),
+            _ => None,
+        }
+    }
+
+    fn aliases(self) -> FieldAliases {
+        match self {
+            Self::Yourls => FieldAliases {
+                slug: &["keyword", "shorturl"],
+                url: &["url"],
+                created_at: &["timestamp"],
+                clicks: &["clicks"],
+                expires_at: &[],
+            },
+            Self::Shlink => FieldAliases {
+                slug: &["shortcode", "short_code", "shorturl", "short_url"],
+                url: &["longurl", "long_url"],
+                created_at: &["datecreated", "createdat", "created_at"],
+                clicks: &[
+                    "visitssummary.total",
+                    "visitscount",
+                    "visits",
+                    "visits_count",
+                ],
+                expires_at: &["meta.validuntil", "validuntil", "valid_until"],
+            },
+            Self::Kutt => FieldAliases {
+                slug: &["address", "link"],
+                url: &["target"],
+                created_at: &["created_at"],
+                clicks: &["visit_count"],
+                expires_at: &["expire_in"],
+            },
+            Self::Bitly => FieldAliases {
+                slug: &["bitlink", "id", "link", "short link"],
+                url: &["long_url", "long url", "original url"],
+                created_at: &["created_at", "created", "date created"],
+                clicks: &["total clicks", "total_clicks", "clicks", "engagements"],
+                expires_at: &[],
+            },
+        }
+    }
+}
+
+/// A link read from another shortener's export, normalised for our schema.
+#[derive(Debug, Clone, Serialize)]
+pub struct ImportRecord {
+    // 1-based position in the export, for reporting
+    pub row: usize,
+    pub slug: String,
+    pub original_url: String,
+    pub created_at: Option<String>,
+    pub clicks: i64,
+    pub expires_at: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ImportRowError {
+    pub row: usize,
+    pub error: String,
+}
+
+#[derive(Debug)]
+pub enum ImportError {
+    Invalid(String),
+    Database(sqlx::Error),
+}
+
+impl std::fmt::Display for ImportError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Invalid(message) => f.write_str(message),
+            Self::Database(e) => write!(f, "Database error: {}", e),
+        }
+    }
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct ImportReport {
+    pub dry_run: bool,
+    pub imported: usize,
+    pub conflicts: Vec<String>,
+    pub errors: Vec<ImportRowError>,
+    // Rows whose destination the domain policy or a threat feed refused
+    pub rejected: Vec<ImportRowError>,
+}
+
+/// Parses an export (CSV or JSON, detected from the content) into records.
+/// Rows that can't be mapped are returned as errors instead of failing the whole file.
+pub fn parse(
+    source: ImportSource,
+    input: &str,
+) -> Result<(Vec<ImportRecord>, Vec<ImportRowError>), String> {
+    let rows = if input.trim_start().starts_with(['{', '[']) {
+        json_rows(input)?
+    } else {
+        csv_rows(input)?
+    };
+
+    let aliases = source.aliases();
+    let mut records = Vec::new();
+    let mut errors = Vec::new();
+    for (index, row) in rows.iter().enumerate() {
+        match map_row(&aliases, index + 1, row) {
+            Ok(record) => records.push(record),
+            Err(error) => errors.push(ImportRowError {
+                row: index + 1,
+                error,
+            }),
+        }
+    }
+
+    Ok((records, errors))
+}
+
+/// Inserts parsed records, skipping slugs that already exist and destinations
+/// that `shorten` would refuse. Nothing is written on a dry run.
+pub async fn import(
+    db: &Database,
+    domain_policy: &DomainPolicy,
+    threat_feeds: &ThreatFeeds,
+    source: ImportSource,
+    input: &str,
+    dry_run: bool,
+) -> Result<ImportReport, ImportError> {
+    let (records, errors) = parse(source, input).map_err(ImportError::Invalid)?;
+    let mut report = ImportReport {
+        dry_run,
+        errors,
+        ..Default::default()
+    };
+
+    let mut seen = HashSet::new();
+    for record in records {
+        let refused = match domain_policy.check(&record.original_url) {
+            Err(violation) => Some(violation.message()),
+            Ok(()) => threat_feeds
+                .check(&record.original_url)
+                .map(|threat| threat.message()),
+        };
+        if let Some(error) = refused {
+            report.rejected.push(ImportRowError {
+                row: record.row,
+                error,
+            });
+            continue;
+        }
+
+        let exists = db
+            .check_slug_exists(&record.slug)
+            .await
+            .map_err(ImportError::Database)?;
+        if exists || !seen.insert(record.slug.clone()) {
+            report.conflicts.push(record.slug);
+            continue;
+        }
+
+        if !dry_run {
+            match db.insert_imported_url(&record).await {
+                Ok(()) => {}
+                Err(sqlx::Error::Database(db_err)) if db_err.is_unique_violation() => {
+                    report.conflicts.push(record.slug);
+                    continue;
+                }
+                Err(e) => return Err(ImportError::Database(e)),
+            }
+        }
+        report.imported += 1;
+    }
+
+    Ok(report)
+}
+
+fn csv_rows(input: &str) -> Result<Vec<HashMap<String, String>>, String> {
+    let mut reader = csv::ReaderBuilder::new()
+        .flexible(true)
+        .trim(csv::Trim::All)
+        .from_reader(input.as_bytes());
+
+    let headers: Vec<String> = reader
+        .headers()
+        .map_err(|e| format!("Invalid CSV header: {}", e))?
+        .iter()
+        .map(|h| h.trim_start_matches('\u{feff}').to_ascii_lowercase())
+        .collect();
+
+    let mut rows = Vec::new();
+    for record in reader.records() {
+        let record = record.map_err(|e| format!("Invalid CSV: {}", e))?;
+        rows.push(
+            headers
+                .iter()
+                .cloned()
+                .zip(record.iter().map(str::to_string))
+                .collect(),
+        );
+    }
+    Ok(rows)
+}
+
+fn json_rows(input: &str) -> Result<Vec<HashMap<String, String>>, String> {
+    let value: serde_json::Value =
+        serde_json::from_str(input).map_err(|e| format!("Invalid JSON: {}", e))?;
+
+    // Shlink wraps links in `shortUrls.data`, Kutt in `data`, Bitly and YOURLS in `links`
+    let list = [
+        &["shortUrls", "data"][..],
+        &["data"][..],
+        &["links"][..],
+        &[][..],
+    ]
+    .iter()
+    .find_map(|path| {
+        let found = path.iter().try_fold(&value, |v, key| v.get(key))?;
+        match found {
+            serde_json::Value::Array(items) => Some(items.iter().collect::<Vec<_>>()),
+            // YOURLS keys its links by position (`link_1`, `link_2`, ...)
+            serde_json::Value::Object(items) if !path.is_empty() => Some(items.values().collect()),
+            _ => None,
+        }
+    })
+    .ok_or("JSON does not contain a list of links")?;
+
+    Ok(list
+        .into_iter()
+        .map(|item| {
+            let mut row = HashMap::new();
+            flatten_json("", item, &mut row);
+            row
+        })
+        .collect())
+}
+
+// Flattens nested objects into `parent.child` keys so they can share the CSV aliases
+fn flatten_json(prefix: &str, value: &serde_json::Value, row: &mut HashMap<String, String>) {
+    match value {
+        serde_json::Value::Object(map) => {
+            for (key, value) in map {
+                let key = key.to_ascii_lowercase();
+                let key = if prefix.is_empty() {
+                    key
+                } else {
+                    format!("{}.{}", prefix, key)
+                };
+                flatten_json(&key, value, row);
+            }
+        }
+        serde_json::Value::Null => {}
+        serde_json::Value::String(s) => {
+            row.insert(prefix.to_string(), s.clone());
+        }
+        other => {
+            row.insert(prefix.to_string(), other.to_string());
+        }
+    }
+}
+
+fn map_row(
+    aliases: &FieldAliases,
+    index: usize,
+    row: &HashMap<String, String>,
+) -> Result<ImportRecord, String> {
+    let field = |names: &[&str]| {
+        names
+            .iter()
+            .find_map(|name| row.get(*name).filter(|v| !v.is_empty()))
+            .cloned()
+    };
+
+    // Full short URLs such as `bit.ly/abc123` are reduced to their last path segment
+    let slug = field(aliases.slug)
+        .map(|s| {
+            s.trim_end_matches('/')
+                .rsplit('/')
+                .next()
+                .unwrap_or("")
+                .to_string()
+        })
+        .filter(|s| !s.is_empty())
+        .ok_or("Missing slug")?;
+    if !is_servable_slug(&slug) {
+        return Err(format!("Slug '{}' can't be served by this shortener", slug));
+    }
+
+    let original_url = field(aliases.url).ok_or("Missing destination URL")?;
+    if !is_http_url(&original_url) {
+        return Err(format!(
+            "Destination '{}' isn't an http(s) URL",
+            original_url
+        ));
+    }
+
+    let created = field(aliases.created_at)
+        .map(|v| parse_timestamp(&v).ok_or_else(|| format!("Invalid created date '{}'", v)))
+        .transpose()?;
+    let created_at = created.map(|t| t.format("%Y-%m-%d %H:%M:%S").to_string());
+
+    let clicks = match field(aliases.clicks) {
+        Some(v) => v
+            .replace(',', "")
+            .parse::<i64>()
+            .map_err(|_| format!("Invalid click count '{}'", v))?,
+        None => 0,
+    };
+
+    let expires_at = field(aliases.expires_at)
+        .map(|v| {
+            parse_timestamp(&v)
+                .or_else(|| parse_relative_expiry(&v, created))
+                .map(format_utc)
+                .ok_or_else(|| format!("Invalid expiry date '{}'", v))
+        })
+        .transpose()?;
+
+    Ok(ImportRecord {
+        row: index,
+        slug,
+        original_url,
+        created_at,
+        clicks,
+        expires_at,
+    })
+}
+
+// Accepts the date formats found in the supported exports, assuming UTC when no offset is given
+pub fn parse_timestamp(value: &str) -> Option<chrono::DateTime<chrono::Utc>> {
+    use chrono::{DateTime, NaiveDate, NaiveDateTime, Utc};
+
+    if let Ok(t) = DateTime::parse_from_rfc3339(value) {
+        return Some(t.with_timezone(&Utc));
+    }
+    if let Ok(t) = DateTime::parse_from_str(value, "%Y-%m-%dT%H:%M:%S%z") {
+        return Some(t.with_timezone(&Utc));
+    }
+    for format in [
+        "%Y-%m-%d %H:%M:%S",
+        "%Y-%m-%dT%H:%M:%S",
+        "%Y-%m-%d %H:%M:%S%.f",
+    ] {
+        if let Ok(t) = NaiveDateTime::parse_from_str(value, format) {
+            return Some(t.and_utc());
+        }
+    }
+    if let Ok(d) = NaiveDate::parse_from_str(value, "%Y-%m-%d") {
+        return d.and_hms_opt(0, 0, 0).map(|t| t.and_utc());
+    }
+    // Unix timestamps
+    value
+        .parse::<i64>()
+        .ok()
+        .and_then(|secs| DateTime::from_timestamp(secs, 0))
+}
+
+// Kutt exports `expire_in` as a duration from when the link was created, such
+// as "2 hours" or "30m". Without a creation date it counts from now.
+fn parse_relative_expiry(
+    value: &str,
+    created: Option<chrono::DateTime<chrono::Utc>>,
+) -> Option<chrono::DateTime<chrono::Utc>> {
+    use chrono::Duration;
+
+    let value = value.trim().to_ascii_lowercase();
+    let (count, unit) = match value.split_once(char::is_whitespace) {
+        Some((count, unit)) => (count, unit.trim()),
+        None => value.split_at(value.find(|c: char| !c.is_ascii_digit())?),
+    };
+    let count: i64 = count.parse().ok().filter(|&n| n > 0)?;
+    let duration = match unit.trim_end_matches('s') {
+        "m" | "min" | "minute" => Duration::try_minutes(count),
+        "h" | "hr" | "hour" => Duration::try_hours(count),
+        "d" | "day" => Duration::try_days(count),
+        "w" | "week" => Duration::try_weeks(count),
+        _ => None,
+    }?;
+    created
+        .unwrap_or_else(chrono::Utc::now)
+        .checked_add_signed(duration)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{db::testing::TempDb, threat_feed::ThreatAction};
+    use chrono::{TimeZone, Utc};
+    use std::path::PathBuf;
+
+    const YOURLS: &str = "\
+keyword,url,title,timestamp,ip,clicks
+ozh,https://ozh.org/,Ozh,2024-01-02 03:04:05,127.0.0.1,42
+https://sho.rt/yt,https://www.youtube.com/,,2024-01-03 00:00:00,127.0.0.1,0
+";
+
+    const SHLINK: &str = r#"{"shortUrls": {"data": [{
+        "shortCode": "abc12",
+        "longUrl": "https://example.com/shlink",
+        "dateCreated": "2024-01-02T03:04:05+02:00",
+        "visitsSummary": {"total": 12, "nonBots": 10},
+        "meta": {"validSince": null, "validUntil": "2030-01-01T00:00:00+00:00"}
+    }]}}"#;
+
+    const KUTT: &str = r#"{"data": [{
+        "address": "kutt1",
+        "target": "https://example.com/kutt",
+        "created_at": "2024-01-01T00:00:00Z",
+        "visit_count": 3,
+        "expire_in": "2 hours"
+    }]}"#;
+
+    const BITLY: &str = "\
+Bitlink,Long URL,Created,Total Clicks
+bit.ly/3xYz,https://example.com/bitly,2024-03-04,\"1,234\"
+";
+
+    fn parse_ok(source: ImportSource, input: &str) -> Vec<ImportRecord> {
+        let (records, errors) = parse(source, input).unwrap();
+        assert!(errors.is_empty(), "{:?}", errors);
+        records
+    }
+
+    #[test]
+    fn reads_each_format() {
+        let yourls = parse_ok(ImportSource::Yourls, YOURLS);
+        assert_eq!(yourls.len(), 2);
+        assert_eq!(yourls[0].slug, "ozh");
+        assert_eq!(yourls[0].clicks, 42);
+        assert_eq!(yourls[0].created_at.as_deref(), Some("2024-01-02 03:04:05"));
+        // Full short URLs are reduced to the slug
+        assert_eq!(yourls[1].slug, "yt");
+
+        let shlink = &parse_ok(ImportSource::Shlink, SHLINK)[0];
+        assert_eq!(shlink.slug, "abc12");
+        assert_eq!(shlink.original_url, "https://example.com/shlink");
+        assert_eq!(shlink.clicks, 12);
+        // Offsets are converted to UTC
+        assert_eq!(shlink.created_at.as_deref(), Some("2024-01-02 01:04:05"));
+        assert_eq!(
+            shlink.expires_at,
+            Some(format_utc(
+                Utc.with_ymd_and_hms(2030, 1, 1, 0, 0, 0).unwrap()
+            ))
+        );
+
+        // Kutt expiries count from the link's creation
+        let kutt = &parse_ok(ImportSource::Kutt, KUTT)[0];
+        assert_eq!((kutt.slug.as_str(), kutt.clicks), ("kutt1", 3));
+        assert_eq!(
+            kutt.expires_at,
+            Some(format_utc(
+                Utc.with_ymd_and_hms(2024, 1, 1, 2, 0, 0).unwrap()
+            ))
+        );
+
+        let bitly = &parse_ok(ImportSource::Bitly, BITLY)[0];
+        assert_eq!(bitly.slug, "3xYz");
+        assert_eq!(bitly.clicks, 1234);
+        assert_eq!(bitly.created_at.as_deref(), Some("2024-03-04 00:00:00"));
+    }
+
+    #[test]
+    fn reads_relative_expiries() {
+        let created = Utc.with_ymd_and_hms(2024, 1, 1, 0, 0, 0).unwrap();
+        let after = |value| parse_relative_expiry(value, Some(created)).map(|t| t - created);
+        assert_eq!(after("30m"), chrono::Duration::try_minutes(30));
+        assert_eq!(after("2 Hours"), chrono::Duration::try_hours(2));
+        assert_eq!(after("1 day"), chrono::Duration::try_days(1));
+        assert_eq!(after("3 weeks"), chrono::Duration::try_weeks(3));
+        for value in ["", "0m", "2 fortnights", "h", "-1d"] {
+            assert_eq!(after(value), None, "{}", value);
+        }
+    }
+
+    #[test]
+    fn reports_bad_rows() {
+        let input = "\
+keyword,url,timestamp,clicks
+ok,https://example.com/,,1
+,https://example.com/missing-slug,,1
+dashboard,https://example.com/reserved,,1
+js,javascript:alert(1),,1
+count,https://example.com/,,many
+date,https://example.com/,yesterday,1
+";
+        let (records, errors) = parse(ImportSource::Yourls, input).unwrap();
+        assert_eq!(records.len(), 1);
+        let rows: Vec<usize> = errors.iter().map(|e| e.row).collect();
+        assert_eq!(rows, [2, 3, 4, 5, 6]);
+
+        assert!(parse(ImportSource::Kutt, r#"{"links": 5}"#).is_err());
+    }
+
+    fn screens(policy: &str) -> (DomainPolicy, ThreatFeeds, PathBuf) {
+        let path = std::env::temp_dir().join(format!(
+            "meoshorturl-import-policy-{:016x}.txt",
+            rand::random::<u64>()
+        ));
+        std::fs::write(&path, policy).unwrap();
+        let feeds = ThreatFeeds::new(path.with_extension("missing"), ThreatAction::Disable);
+        (DomainPolicy::new(path.clone()), feeds, path)
+    }
+
+    #[tokio::test]
+    async fn reports_conflicts_and_dry_runs() {
+        let db = TempDb::new().await;
+        let (policy, feeds, path) = screens("block blocked.example\n");
+        let input = "\
+keyword,url,clicks
+taken,https://example.com/1,1
+twice,https://example.com/2,2
+twice,https://example.com/3,3
+fresh,https://example.com/4,4
+bad,https://blocked.example/5,5
+";
+        db.insert_imported_url(
+            &parse_ok(
+                ImportSource::Yourls,
+                "keyword,url\ntaken,https://example.com/\n",
+            )[0],
+        )
+        .await
+        .unwrap();
+
+        // A dry run reports the same outcome without writing anything
+        let dry = import(&db, &policy, &feeds, ImportSource::Yourls, input, true)
+            .await
+            .unwrap();
+        assert!(dry.dry_run);
+        assert_eq!(dry.imported, 2);
+        assert_eq!(dry.conflicts, ["taken", "twice"]);
+        assert_eq!(dry.rejected.len(), 1);
+        assert_eq!(dry.rejected[0].row, 5);
+        assert!(db.get_by_slug("fresh").await.unwrap().is_none());
+
+        let report = import(&db, &policy, &feeds, ImportSource::Yourls, input, false)
+            .await
+            .unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(report.imported, 2);
+        assert_eq!(report.conflicts, ["taken", "twice"]);
+        // The first of two rows with the same slug wins, with its click total
+        let twice = db.get_by_slug("twice").await.unwrap().unwrap();
+        assert_eq!(
+            (twice.original_url.as_str(), twice.clicks),
+            ("https://example.com/2", 2)
+        );
+        assert_eq!(db.get_by_slug("fresh").await.unwrap().unwrap().clicks, 4);
+        assert!(db.get_by_slug("bad").await.unwrap().is_none());
+
+        // Importing again conflicts on everything already there
+        let again = import(&db, &policy, &feeds, ImportSource::Yourls, input, false)
+            .await
+            .unwrap();
+        assert_eq!(again.imported, 0);
+        assert_eq!(again.conflicts, ["taken", "twice", "twice", "fresh"]);
+    }
+}
@@ -0,0 +1,196 @@
+use crate::models::UrlRecord;
+
+/// Query parameter carrying the signed token that skips the interstitial.
+pub const CONTINUE_PARAM: &str = "meo_continue";
+
+/// When visitors see a "you are leaving" page before being redirected.
+#[derive(Debug, Clone)]
+pub struct InterstitialConfig {
+    // Links created without signing in
+    pub anonymous: bool,
+    // When non-empty, destinations outside these domains (and their subdomains)
+    pub trusted_domains: Vec<String>,
+    // Seconds before the continue button is enabled
+    pub countdown_secs: u64,
+}
+
+impl InterstitialConfig {
+    pub fn from_env() -> Self {
+        let anonymous = std::env::var("INTERSTITIAL_ANONYMOUS")
+            .map(|v| v == "true" || v == "1")
+            .unwrap_or(false);
+        let trusted_domains = std::env::var("INTERSTITIAL_TRUSTED_DOMAINS")
+            .unwrap_or_default()
+            .split(',')
+            .map(|d| d.trim().trim_start_matches("*.").to_ascii_lowercase())
+            .filter(|d| !d.is_empty())
+            .collect();
+        let countdown_secs = std::env::var("INTERSTITIAL_COUNTDOWN_SECS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(5);
+
+        Self {
+            anonymous,
+            trusted_domains,
+            countdown_secs,
+        }
+    }
+
+    /// Why `record` needs an interstitial before sending the visitor to
+    /// `destination` (the original URL, or the one targeting or rotation picked).
+    pub fn reason(&self, record: &UrlRecord, destination: &str) -> Option<&'static str> {
+        if record.untrusted {
+            return Some("This link has been flagged as untrusted.");
+        }
+        if self.anonymous && record.anonymous {
+            return Some("This link was created anonymously.");
+        }
+        if !self.trusted_domains.is_empty() {
+            let trusted = destination_host(destination).is_some_and(|host| {
+                self.trusted_domains.iter().any(|domain| {
+                    host == *domain
+                        || host
+                            .strip_suffix(domain.as_str())
+                            .is_some_and(|rest| rest.ends_with('.'))
+                })
+            });
+            if !trusted {
+                return Some("This link leads to an external site.");
+            }
+        }
+        None
+    }
+}
+
+pub fn destination_host(url: &str) -> Option<String> {
+    url::Url::parse(url)
+        .ok()?
+        .host_str()
+        .map(|host| host.to_ascii_lowercase())
+}
+
+/// The message signed into continue tokens. Binding the destination shown on
+/// the interstitial means editing the link invalidates tokens handed out for
+/// the old one, and a token can't be used to reach another of its destinations.
+pub fn continue_message(record: &UrlRecord, destination: &str) -> String {
+    format!("continue:{}:{}", record.id, destination)
+}
+
+/// Splits the continue token out of a query string, returning it and the rest
+/// of the query (which may still be forwarded to the destination).
+pub fn take_continue_token(query: Option<&str>) -> (Option<String>, Option<String>) {
+    let Some(query) = query else {
+        return (None, None);
+    };
+    let mut token = None;
+    let rest: Vec<&str> = query
+        .split('&')
+        .filter(|pair| match pair.strip_prefix(CONTINUE_PARAM) {
+            Some(value) if value.starts_with('=') => {
+                token = Some(value[1..].to_string());
+                false
+            }
+            _ => true,
+        })
+        .collect();
+    let rest = rest.join("&");
+    (token, (!rest.is_empty()).then_some(rest))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::signing::Signer;
+
+    fn config(anonymous: bool, trusted_domains: &[&str]) -> InterstitialConfig {
+        InterstitialConfig {
+            anonymous,
+            trusted_domains: trusted_domains.iter().map(|d| d.to_string()).collect(),
+            countdown_secs: 5,
+        }
+    }
+
+    #[test]
+    fn untrusted_and_anonymous_links_are_interrupted() {
+        let flagged = UrlRecord {
+            untrusted: true,
+            ..Default::default()
+        };
+        let anonymous = UrlRecord {
+            anonymous: true,
+            ..Default::default()
+        };
+        let url = "https://example.com/";
+
+        assert!(config(false, &[]).reason(&flagged, url).is_some());
+        assert!(config(false, &[]).reason(&anonymous, url).is_none());
+        assert!(config(true, &[]).reason(&anonymous, url).is_some());
+        assert!(config(true, &[])
+            .reason(&UrlRecord::default(), url)
+            .is_none());
+    }
+
+    #[test]
+    fn trusted_domains_cover_their_subdomains() {
+        let config = config(false, &["example.com"]);
+        let record = UrlRecord::default();
+        for url in [
+            "https://example.com/page",
+            "https://docs.example.com/",
+            "https://a.b.EXAMPLE.com:8443/",
+        ] {
+            assert_eq!(config.reason(&record, url), None, "{}", url);
+        }
+        for url in [
+            "https://notexample.com/",
+            "https://example.com.evil.net/",
+            "https://example.org/",
+            "not a url",
+        ] {
+            assert!(config.reason(&record, url).is_some(), "{}", url);
+        }
+    }
+
+    #[test]
+    fn takes_the_continue_token_out_of_the_query() {
+        assert_eq!(take_continue_token(None), (None, None));
+        assert_eq!(
+            take_continue_token(Some("meo_continue=abc.def")),
+            (Some("abc.def".to_string()), None)
+        );
+        assert_eq!(
+            take_continue_token(Some("a=1&meo_continue=tok&b=2")),
+            (Some("tok".to_string()), Some("a=1&b=2".to_string()))
+        );
+        // Only the exact parameter name counts
+        assert_eq!(
+            take_continue_token(Some("meo_continued=1&meo_continue")),
+            (None, Some("meo_continued=1&meo_continue".to_string()))
+        );
+    }
+
+    #[test]
+    fn continue_tokens_are_bound_to_link_and_destination() {
+        let signer = Signer::new(b"test key".to_vec());
+        let record = UrlRecord {
+            id: 1,
+            ..Default::default()
+        };
+        let token = signer.sign_expiring(&continue_message(&record, "https://a.example/"), 60);
+
+        assert!(signer.verify_expiring(&continue_message(&record, "https://a.example/"), &token));
+        assert!(!signer.verify_expiring(&continue_message(&record, "https://b.example/"), &token));
+        let other = UrlRecord {
+            id: 2,
+            ..Default::default()
+        };
+        assert!(!signer.verify_expiring(&continue_message(&other, "https://a.example/"), &token));
+
+        let expired = signer.sign_expiring(&continue_message(&record, "https://a.example/"), -1);
+        assert!(!signer.verify_expiring(&continue_message(&record, "https://a.example/"), &expired));
+        let forged = Signer::new(b"other key".to_vec())
+            .sign_expiring(&continue_message(&record, "https://a.example/"), 60);
+        assert!(!signer.verify_expiring(&continue_message(&record, "https://a.example/"), &forged));
+    }
+}
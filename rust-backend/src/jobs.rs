@@ -0,0 +1,494 @@
+use chrono::{DateTime, Datelike, Duration as ChronoDuration, DurationRound, Timelike, Utc};
+use rand::Rng;
+use std::time::Duration;
+
+use crate::db::Database;
+
+// How often each replica looks for due jobs
+const POLL_SECS: u64 = 30;
+// A replica that dies mid-run leaves the job claimed for this long
+const LEASE_SECS: i64 = 30 * 60;
+
+/// When a job runs: `every 15m` (also `s`, `h`, `d`) or a five-field cron
+/// expression in UTC, e.g. `30 4 * * 0`.
+#[derive(Debug, Clone)]
+pub enum Schedule {
+    Every(ChronoDuration),
+    Cron(Cron),
+}
+
+impl Schedule {
+    /// `off` disables the job and parses to `None`.
+    pub fn parse(spec: &str) -> Result<Option<Self>, String> {
+        let spec = spec.trim();
+        if spec == "off" {
+            return Ok(None);
+        }
+        if let Some(every) = spec.strip_prefix("every ") {
+            let every = every.trim();
+            let unit_len = every.chars().last().map_or(0, char::len_utf8);
+            let (count, unit) = every.split_at(every.len() - unit_len);
+            let count: i64 = count
+                .parse()
+                .ok()
+                .filter(|&n| n > 0)
+                .ok_or_else(|| format!("invalid interval \"{}\"", every))?;
+            let interval = match unit {
+                "s" => ChronoDuration::seconds(count),
+                "m" => ChronoDuration::minutes(count),
+                "h" => ChronoDuration::hours(count),
+                "d" => ChronoDuration::days(count),
+                _ => return Err(format!("interval \"{}\" must end in s, m, h or d", every)),
+            };
+            return Ok(Some(Self::Every(interval)));
+        }
+        Cron::parse(spec).map(|cron| Some(Self::Cron(cron)))
+    }
+
+    pub fn next_after(&self, time: DateTime<Utc>) -> DateTime<Utc> {
+        match self {
+            Self::Every(interval) => time + *interval,
+            Self::Cron(cron) => cron.next_after(time),
+        }
+    }
+}
+
+/// Minute, hour, day of month, month and day of week, each as a bit set.
+#[derive(Debug, Clone)]
+pub struct Cron {
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    // As in cron, when both days and weekdays are restricted either may match
+    any_day: bool,
+    any_weekday: bool,
+}
+
+impl Cron {
+    fn parse(spec: &str) -> Result<Self, String> {
+        let fields: Vec<&str> = spec.split_whitespace().collect();
+        let [minutes, hours, days, months, weekdays] = fields[..] else {
+            return Err(format!(
+                "\"{}\" is neither \"every <n><unit>\" nor a five-field cron expression",
+                spec
+            ));
+        };
+        let mut weekday_bits = parse_field(weekdays, 0, 7)?;
+        // Both 0 and 7 are Sunday
+        if weekday_bits & (1 << 7) != 0 {
+            weekday_bits |= 1;
+        }
+        Ok(Self {
+            minutes: parse_field(minutes, 0, 59)?,
+            hours: parse_field(hours, 0, 23)?,
+            days: parse_field(days, 1, 31)?,
+            months: parse_field(months, 1, 12)?,
+            weekdays: weekday_bits,
+            any_day: days == "*",
+            any_weekday: weekdays == "*",
+        })
+    }
+
+    fn next_after(&self, time: DateTime<Utc>) -> DateTime<Utc> {
+        let mut next = time
+            .duration_trunc(ChronoDuration::minutes(1))
+            .unwrap_or(time)
+            + ChronoDuration::minutes(1);
+        // Every valid expression matches within a few years
+        let limit = time + ChronoDuration::days(4 * 366);
+        while next < limit {
+            if !self.day_matches(next) {
+                next = (next + ChronoDuration::days(1))
+                    .duration_trunc(ChronoDuration::days(1))
+                    .unwrap_or(next);
+                continue;
+            }
+            if self.hours & (1 << next.hour()) == 0 {
+                next = (next + ChronoDuration::hours(1))
+                    .duration_trunc(ChronoDuration::hours(1))
+                    .unwrap_or(next);
+                continue;
+            }
+            if self.minutes & (1 << next.minute()) == 0 {
+                next += ChronoDuration::minutes(1);
+                continue;
+            }
+            return next;
+        }
+        limit
+    }
+
+    fn day_matches(&self, time: DateTime<Utc>) -> bool {
+        if self.months & (1 << time.month()) == 0 {
+            return false;
+        }
+        let day = self.days & (1 << time.day()) != 0;
+        let weekday = self.weekdays & (1 << time.weekday().num_days_from_sunday()) != 0;
+        match (self.any_day, self.any_weekday) {
+            (false, false) => day || weekday,
+            _ => day && weekday,
+        }
+    }
+}
+
+// `*`, `5`, `1-5`, `*/15`, `0-30/10` and comma-separated lists of those
+fn parse_field(field: &str, min: u32, max: u32) -> Result<u64, String> {
+    let mut bits = 0u64;
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (
+                range,
+                step.parse::<u32>()
+                    .ok()
+                    .filter(|&s| s > 0)
+                    .ok_or_else(|| format!("invalid step in \"{}\"", part))?,
+            ),
+            None => (part, 1),
+        };
+        let (start, end) = if range == "*" {
+            (min, max)
+        } else {
+            let number = |n: &str| {
+                n.parse::<u32>()
+                    .ok()
+                    .filter(|n| (min..=max).contains(n))
+                    .ok_or_else(|| format!("\"{}\" is not between {} and {}", n, min, max))
+            };
+            match range.split_once('-') {
+                Some((start, end)) => (number(start)?, number(end)?),
+                None => {
+                    let n = number(range)?;
+                    (n, if step > 1 { max } else { n })
+                }
+            }
+        };
+        if start > end {
+            return Err(format!("invalid range \"{}\"", range));
+        }
+        for n in (start..=end).step_by(step as usize) {
+            bits |= 1 << n;
+        }
+    }
+    Ok(bits)
+}
+
+/// The maintenance jobs the server runs by itself.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Job {
+    PurgeExpired,
+    RollupClicks,
+    Vacuum,
+}
+
+impl Job {
+    pub const ALL: [Job; 3] = [Job::PurgeExpired, Job::RollupClicks, Job::Vacuum];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::PurgeExpired => "purge_expired",
+            Self::RollupClicks => "rollup_clicks",
+            Self::Vacuum => "vacuum",
+        }
+    }
+
+    fn default_schedule(self) -> &'static str {
+        match self {
+            Self::PurgeExpired => "every 1h",
+            Self::RollupClicks => "15 3 * * *",
+            Self::Vacuum => "30 4 * * 0",
+        }
+    }
+
+    /// Runs the job once, returning a short summary for the job list.
+    async fn run(self, db: &Database) -> Result<String, sqlx::Error> {
+        match self {
+            // Expired links keep showing the "expired" page for a while before
+            // they are archived (or deleted) and their slug becomes free
+            Self::PurgeExpired => {
+                let retention_days = env_days("EXPIRED_RETENTION_DAYS", 30);
+                let archive = std::env::var("EXPIRED_LINK_ACTION").as_deref() != Ok("delete");
+                let cutoff = Utc::now() - ChronoDuration::days(retention_days);
+                let purged = db.purge_expired(cutoff, archive).await?;
+                Ok(format!(
+                    "{} {} expired links",
+                    if archive { "Archived" } else { "Deleted" },
+                    purged
+                ))
+            }
+            // Click events older than the retention period are folded into
+            // per-day, per-country counts
+            Self::RollupClicks => {
+                let retention_days = env_days("CLICK_RETENTION_DAYS", 90);
+                let compacted = db.rollup_clicks(retention_days).await?;
+                Ok(format!("Rolled up {} click events", compacted))
+            }
+            Self::Vacuum => {
+                db.vacuum().await?;
+                Ok("Vacuumed and analyzed the database".to_string())
+            }
+        }
+    }
+}
+
+fn env_days(name: &str, default: i64) -> i64 {
+    std::env::var(name)
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .filter(|&days| days >= 0)
+        .unwrap_or(default)
+}
+
+/// Formats a time the way SQLite's `datetime()` does, so stored times compare
+/// as text.
+pub fn sqlite_datetime(time: DateTime<Utc>) -> String {
+    time.format("%Y-%m-%d %H:%M:%S").to_string()
+}
+
+/// Runs the maintenance jobs on their schedules. Schedules come from
+/// `JOB_<NAME>_SCHEDULE` (e.g. `JOB_VACUUM_SCHEDULE=off`). Each run is claimed
+/// through a lease row in the database, so with several replicas sharing one
+/// database only one of them runs a given job at a time.
+pub struct Scheduler {
+    db: Database,
+    holder: String,
+    jobs: Vec<(Job, String, Schedule)>,
+}
+
+impl Scheduler {
+    pub fn from_env(db: Database) -> Self {
+        let jobs = Job::ALL
+            .into_iter()
+            .filter_map(|job| {
+                let var = format!("JOB_{}_SCHEDULE", job.name().to_ascii_uppercase());
+                let spec =
+                    std::env::var(&var).unwrap_or_else(|_| job.default_schedule().to_string());
+                match Schedule::parse(&spec) {
+                    Ok(schedule) => {
+                        schedule.map(|schedule| (job, spec.trim().to_string(), schedule))
+                    }
+                    Err(e) => panic!("{} is invalid: {}", var, e),
+                }
+            })
+            .collect();
+        let host = std::env::var("HOSTNAME").unwrap_or_else(|_| "server".to_string());
+        let holder = format!(
+            "{}-{}-{:08x}",
+            host,
+            std::process::id(),
+            rand::thread_rng().gen::<u32>()
+        );
+
+        Self { db, holder, jobs }
+    }
+
+    pub fn spawn(self) {
+        tokio::spawn(async move {
+            for (job, spec, schedule) in &self.jobs {
+                let next_run_at = sqlite_datetime(schedule.next_after(Utc::now()));
+                if let Err(e) = self.db.register_job(job.name(), spec, &next_run_at).await {
+                    tracing::error!("Failed to register job {}: {}", job.name(), e);
+                }
+            }
+
+            let mut poll = tokio::time::interval(Duration::from_secs(POLL_SECS));
+            loop {
+                poll.tick().await;
+                for (job, _, schedule) in &self.jobs {
+                    self.run_if_due(*job, schedule).await;
+                }
+            }
+        });
+    }
+
+    async fn run_if_due(&self, job: Job, schedule: &Schedule) {
+        match self
+            .db
+            .claim_job(job.name(), &self.holder, LEASE_SECS)
+            .await
+        {
+            Ok(true) => {}
+            Ok(false) => return,
+            Err(e) => {
+                tracing::error!("Failed to claim job {}: {}", job.name(), e);
+                return;
+            }
+        }
+
+        tracing::info!("Running job {}", job.name());
+        let (status, message) = match job.run(&self.db).await {
+            Ok(summary) => {
+                tracing::info!("Job {} finished: {}", job.name(), summary);
+                ("ok", summary)
+            }
+            Err(e) => {
+                tracing::error!("Job {} failed: {}", job.name(), e);
+                ("failed", e.to_string())
+            }
+        };
+        let next_run_at = sqlite_datetime(schedule.next_after(Utc::now()));
+        if let Err(e) = self
+            .db
+            .finish_job(job.name(), &self.holder, &next_run_at, status, &message)
+            .await
+        {
+            tracing::error!("Failed to record job {}: {}", job.name(), e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::testing::TempDb;
+    use chrono::TimeZone;
+
+    fn at(y: i32, mo: u32, d: u32, h: u32, mi: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(y, mo, d, h, mi, 0).unwrap()
+    }
+
+    fn cron(spec: &str) -> Schedule {
+        let schedule = Schedule::parse(spec).unwrap().unwrap();
+        assert!(matches!(schedule, Schedule::Cron(_)), "{}", spec);
+        schedule
+    }
+
+    #[test]
+    fn parse_intervals() {
+        let Some(Schedule::Every(interval)) = Schedule::parse(" every 15m ").unwrap() else {
+            panic!("expected an interval");
+        };
+        assert_eq!(interval, ChronoDuration::minutes(15));
+        assert!(Schedule::parse("off").unwrap().is_none());
+
+        let schedule = Schedule::parse("every 2d").unwrap().unwrap();
+        assert_eq!(
+            schedule.next_after(at(2026, 10, 18, 12, 0)),
+            at(2026, 10, 20, 12, 0)
+        );
+
+        for invalid in ["every 0m", "every 5", "every 5y", "every -1h", "every m"] {
+            assert!(Schedule::parse(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn parse_cron() {
+        for invalid in [
+            "* * * *",
+            "60 * * * *",
+            "* 24 * * *",
+            "* * 0 * *",
+            "* * * 13 *",
+            "* * * * 8",
+            "*/0 * * * *",
+            "5-1 * * * *",
+            "a * * * *",
+        ] {
+            assert!(Schedule::parse(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn cron_next_run() {
+        // 2026-10-18 is a Sunday
+        let now = Utc.with_ymd_and_hms(2026, 10, 18, 12, 7, 30).unwrap();
+        let cases = [
+            ("* * * * *", at(2026, 10, 18, 12, 8)),
+            ("*/15 * * * *", at(2026, 10, 18, 12, 15)),
+            ("0-30/10 * * * *", at(2026, 10, 18, 12, 10)),
+            ("5,50 * * * *", at(2026, 10, 18, 12, 50)),
+            ("15 3 * * *", at(2026, 10, 19, 3, 15)),
+            ("30 4 * * 0", at(2026, 10, 25, 4, 30)),
+            // 7 is Sunday too
+            ("0 13 * * 7", at(2026, 10, 18, 13, 0)),
+            ("0 0 1 * *", at(2026, 11, 1, 0, 0)),
+            ("0 0 1 1 *", at(2027, 1, 1, 0, 0)),
+            ("0 9 * * 1-5", at(2026, 10, 19, 9, 0)),
+            // With both days and weekdays restricted, either one matches
+            ("0 0 13 * 5", at(2026, 10, 23, 0, 0)),
+            ("0 0 20 * 6", at(2026, 10, 20, 0, 0)),
+            ("0 0 29 2 *", at(2028, 2, 29, 0, 0)),
+        ];
+        for (spec, expected) in cases {
+            assert_eq!(cron(spec).next_after(now), expected, "{}", spec);
+        }
+
+        // The next run is always strictly later, even on a matching minute
+        assert_eq!(
+            cron("0 12 * * *").next_after(at(2026, 10, 18, 12, 0)),
+            at(2026, 10, 19, 12, 0)
+        );
+    }
+
+    #[tokio::test]
+    async fn one_holder_per_lease() {
+        let db = TempDb::new().await;
+        let past = sqlite_datetime(Utc::now() - ChronoDuration::minutes(1));
+        let future = sqlite_datetime(Utc::now() + ChronoDuration::hours(1));
+        db.register_job("vacuum", "every 1h", &past).await.unwrap();
+
+        assert!(db.claim_job("vacuum", "a", LEASE_SECS).await.unwrap());
+        assert!(!db.claim_job("vacuum", "b", LEASE_SECS).await.unwrap());
+        assert!(!db.claim_job("vacuum", "a", LEASE_SECS).await.unwrap());
+
+        // Only the holder can finish the run and release the lease
+        db.finish_job("vacuum", "b", &future, "ok", "done")
+            .await
+            .unwrap();
+        let job = &db.get_jobs().await.unwrap()[0];
+        assert_eq!(job.lease_holder.as_deref(), Some("a"));
+        assert_eq!(job.last_status, None);
+
+        db.finish_job("vacuum", "a", &future, "ok", "done")
+            .await
+            .unwrap();
+        let job = &db.get_jobs().await.unwrap()[0];
+        assert_eq!(job.lease_holder, None);
+        assert_eq!(job.last_status.as_deref(), Some("ok"));
+        assert_eq!(job.next_run_at, future);
+
+        // Not due again until the next run, unless triggered
+        assert!(!db.claim_job("vacuum", "b", LEASE_SECS).await.unwrap());
+        assert!(db.trigger_job("vacuum").await.unwrap());
+        assert!(db.claim_job("vacuum", "b", LEASE_SECS).await.unwrap());
+        assert!(!db.trigger_job("missing").await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn expired_lease_can_be_taken_over() {
+        let db = TempDb::new().await;
+        let past = sqlite_datetime(Utc::now() - ChronoDuration::minutes(1));
+        db.register_job("vacuum", "every 1h", &past).await.unwrap();
+
+        // A holder that died mid-run, with a lease that has run out
+        assert!(db.claim_job("vacuum", "a", 0).await.unwrap());
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+        assert!(db.claim_job("vacuum", "b", LEASE_SECS).await.unwrap());
+
+        // The old holder finishing late doesn't release the new lease
+        let future = sqlite_datetime(Utc::now() + ChronoDuration::hours(1));
+        db.finish_job("vacuum", "a", &future, "ok", "done")
+            .await
+            .unwrap();
+        let job = &db.get_jobs().await.unwrap()[0];
+        assert_eq!(job.lease_holder.as_deref(), Some("b"));
+    }
+
+    #[tokio::test]
+    async fn registering_keeps_schedule_unless_changed() {
+        let db = TempDb::new().await;
+        let soon = sqlite_datetime(Utc::now() + ChronoDuration::minutes(5));
+        let later = sqlite_datetime(Utc::now() + ChronoDuration::hours(5));
+
+        db.register_job("vacuum", "every 1h", &soon).await.unwrap();
+        db.register_job("vacuum", "every 1h", &later).await.unwrap();
+        assert_eq!(db.get_jobs().await.unwrap()[0].next_run_at, soon);
+
+        db.register_job("vacuum", "every 5h", &later).await.unwrap();
+        let job = &db.get_jobs().await.unwrap()[0];
+        assert_eq!(job.schedule, "every 5h");
+        assert_eq!(job.next_run_at, later);
+    }
+}
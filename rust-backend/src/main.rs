@@ -1,28 +1,86 @@
 use axum::{
-    routing::{delete, get, patch, post},
+    extract::DefaultBodyLimit,
+    http::StatusCode,
+    routing::{delete, get, patch, post, put},
     Router,
 };
-use std::{net::SocketAddr, sync::Arc};
+use std::{net::SocketAddr, sync::Arc, time::Duration};
 use tower_http::{
     cors::{Any, CorsLayer},
     services::ServeDir,
 };
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
+mod accounts;
+mod auth;
+mod auth_provider;
+mod cli;
+mod client_ip;
 mod db;
+mod destination;
+mod domain_policy;
+mod error;
+mod expiry;
+mod export;
+mod fetch;
+mod file_watch;
+mod geoip;
 mod handlers;
+mod health;
+mod import;
+mod interstitial;
+mod jobs;
+mod metadata;
 mod models;
+mod pages;
+mod password;
+mod rate_limit;
 mod session;
+mod signing;
+mod threat_feed;
+mod user_agent;
 
+use auth_provider::AuthProviders;
 use db::Database;
+use domain_policy::DomainPolicy;
+use expiry::ExpiryPolicy;
+use fetch::FetchConfig;
+use geoip::GeoIp;
+use health::HealthConfig;
+use interstitial::InterstitialConfig;
+use jobs::Scheduler;
+use rate_limit::RateLimiter;
+use signing::Signer;
+use threat_feed::ThreatFeeds;
 
 #[derive(Clone)]
 pub struct AppState {
     pub db: Database,
     pub base_url: String,
-    pub discord_client_id: String,
-    pub discord_client_secret: String,
-    pub discord_redirect_uri: String,
+    pub auth_providers: Arc<AuthProviders>,
+    pub fetch: FetchConfig,
+    pub fetch_link_metadata: bool,
+    pub signer: Signer,
+    pub trust_proxy: bool,
+    pub password_limiter: Arc<RateLimiter>,
+    pub login_limiter: Arc<RateLimiter>,
+    pub pending_page: Option<String>,
+    pub fallback: FallbackConfig,
+    pub default_redirect_status: StatusCode,
+    pub permanent_redirect_max_age: u64,
+    pub geoip: Arc<GeoIp>,
+    pub interstitial: InterstitialConfig,
+    pub domain_policy: Arc<DomainPolicy>,
+    pub threat_feeds: Arc<ThreatFeeds>,
+    pub expiry: ExpiryPolicy,
+}
+
+/// Instance-wide destination for links that can't be followed, used when the
+/// link has no fallback of its own.
+#[derive(Clone)]
+pub struct FallbackConfig {
+    pub url: Option<String>,
+    pub page: Option<String>,
 }
 
 #[tokio::main]
@@ -30,9 +88,9 @@ async fn main() {
     // Load env
     dotenvy::dotenv().ok();
 
-    // Initialize tracing
+    // Initialize tracing (stderr, so CLI exports can be piped from stdout)
     tracing_subscriber::registry()
-        .with(tracing_subscriber::fmt::layer())
+        .with(tracing_subscriber::fmt::layer().with_writer(std::io::stderr))
         .init();
 
     // Config from env
@@ -43,19 +101,82 @@ async fn main() {
 
     let db_path = std::env::var("DB_PATH").unwrap_or_else(|_| "data/urls.sqlite".to_string());
     let base_url = std::env::var("BASE_URL").unwrap_or_else(|_| "http://localhost:3006".to_string());
-    let discord_client_id = std::env::var("DISCORD_CLIENT_ID").unwrap_or_default();
-    let discord_client_secret = std::env::var("DISCORD_CLIENT_SECRET").unwrap_or_default();
-    let discord_redirect_uri = std::env::var("DISCORD_REDIRECT_URI").unwrap_or_default();
+    let auth_providers = Arc::new(AuthProviders::from_env(&base_url));
+    let fetch = FetchConfig::from_env();
+    let fetch_link_metadata = std::env::var("FETCH_LINK_METADATA")
+        .map(|v| v == "true" || v == "1")
+        .unwrap_or(false);
+    let trust_proxy = std::env::var("TRUST_PROXY")
+        .map(|v| v == "true" || v == "1")
+        .unwrap_or(false);
+
+    // Optional HTML file replacing the built-in "not available yet" page
+    let pending_page = std::env::var("PENDING_PAGE").ok().map(|path| {
+        std::fs::read_to_string(&path).unwrap_or_else(|e| panic!("Failed to read PENDING_PAGE {}: {}", path, e))
+    });
+
+    let fallback = FallbackConfig {
+        url: std::env::var("FALLBACK_URL").ok().filter(|v| !v.is_empty()),
+        page: std::env::var("FALLBACK_PAGE").ok().map(|path| {
+            std::fs::read_to_string(&path).unwrap_or_else(|e| panic!("Failed to read FALLBACK_PAGE {}: {}", path, e))
+        }),
+    };
+
+    let default_redirect_status = match std::env::var("DEFAULT_REDIRECT_STATUS").as_deref() {
+        Ok("301") => StatusCode::MOVED_PERMANENTLY,
+        Ok("302") => StatusCode::FOUND,
+        Ok("308") => StatusCode::PERMANENT_REDIRECT,
+        Ok("307") | Err(_) => StatusCode::TEMPORARY_REDIRECT,
+        Ok(_) => panic!("DEFAULT_REDIRECT_STATUS must be 301, 302, 307 or 308"),
+    };
+    let permanent_redirect_max_age: u64 = std::env::var("PERMANENT_REDIRECT_MAX_AGE")
+        .unwrap_or_else(|_| "86400".to_string())
+        .parse()
+        .expect("PERMANENT_REDIRECT_MAX_AGE must be a number of seconds");
 
     // Initialize database
     let db = Database::new(&db_path).await.expect("Failed to connect to database");
 
+    // One-off CLI commands run against the same database instead of starting the server
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if !args.is_empty() {
+        if let Err(message) = cli::run(db, &args).await {
+            eprintln!("{}", message);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    let geoip = Arc::new(GeoIp::from_env());
+    geoip.clone().spawn_reloader();
+    let domain_policy = Arc::new(DomainPolicy::from_env());
+    domain_policy.clone().spawn_reloader();
+    let threat_feeds = Arc::new(ThreatFeeds::from_env());
+    threat_feeds.clone().spawn_screener(db.clone());
+    health::spawn_checker(db.clone(), fetch.clone(), HealthConfig::from_env());
+    Scheduler::from_env(db.clone()).spawn();
+
     let state = Arc::new(AppState {
         db,
         base_url,
-        discord_client_id,
-        discord_client_secret,
-        discord_redirect_uri,
+        auth_providers,
+        fetch,
+        fetch_link_metadata,
+        signer: Signer::from_env(),
+        trust_proxy,
+        // 5 wrong passwords per visitor and link every 15 minutes
+        password_limiter: Arc::new(RateLimiter::new(5, Duration::from_secs(15 * 60))),
+        // 10 failed sign-ins per visitor and username every 15 minutes
+        login_limiter: Arc::new(RateLimiter::new(10, Duration::from_secs(15 * 60))),
+        pending_page,
+        fallback,
+        default_redirect_status,
+        permanent_redirect_max_age,
+        geoip,
+        interstitial: InterstitialConfig::from_env(),
+        domain_policy,
+        threat_feeds,
+        expiry: ExpiryPolicy::from_env(),
     });
 
     // Build router
@@ -66,21 +187,89 @@ async fn main() {
         .route("/api/admin/urls", get(handlers::admin::list_urls))
         .route("/api/admin/urls/:id", delete(handlers::admin::delete_url))
         .route("/api/admin/urls/:id", patch(handlers::admin::update_url))
+        .route("/api/admin/urls/:id/tags", put(handlers::tags::set_url_tags))
+        .route("/api/admin/urls/:id/folder", put(handlers::folders::set_url_folder))
+        .route(
+            "/api/admin/urls/:id/device-rules",
+            get(handlers::targeting::get_device_rules).put(handlers::targeting::set_device_rules),
+        )
+        .route(
+            "/api/admin/urls/:id/geo-rules",
+            get(handlers::targeting::get_geo_rules).put(handlers::targeting::set_geo_rules),
+        )
+        .route(
+            "/api/admin/urls/:id/rotation",
+            get(handlers::targeting::get_rotation).put(handlers::targeting::set_rotation),
+        )
+        .route("/api/admin/urls/:id/countries", get(handlers::admin::get_country_stats))
+        .route("/api/admin/urls/:id/check", post(handlers::admin::check_url_health))
+        .route(
+            "/api/admin/urls/:id/variants",
+            get(handlers::variants::list_variants).post(handlers::variants::create_variant),
+        )
+        .route("/api/admin/tags", get(handlers::tags::list_tags).post(handlers::tags::create_tag))
+        .route("/api/admin/tags/:id", patch(handlers::tags::rename_tag).delete(handlers::tags::delete_tag))
+        .route("/api/admin/folders", get(handlers::folders::list_folders).post(handlers::folders::create_folder))
+        .route(
+            "/api/admin/folders/:id",
+            patch(handlers::folders::rename_folder).delete(handlers::folders::delete_folder),
+        )
         .route("/api/admin/me", get(handlers::admin::get_me))
+        .route(
+            "/api/admin/domain-policy",
+            get(handlers::domain_policy::list_entries)
+                .post(handlers::domain_policy::add_entry)
+                .delete(handlers::domain_policy::remove_entry),
+        )
+        .route("/api/admin/domain-policy/reload", post(handlers::domain_policy::reload))
+        .route("/api/admin/threat-feeds", get(handlers::threat_feeds::list_feeds))
+        .route("/api/admin/threat-feeds/scan", post(handlers::threat_feeds::scan))
+        .route("/api/admin/jobs", get(handlers::jobs::list_jobs))
+        .route("/api/admin/jobs/:name/run", post(handlers::jobs::run_job))
+        .route(
+            "/api/admin/accounts",
+            get(handlers::accounts::list_accounts).post(handlers::accounts::create_account),
+        )
+        .route(
+            "/api/admin/accounts/:id",
+            patch(handlers::accounts::update_account).delete(handlers::accounts::delete_account),
+        )
+        .route("/api/admin/accounts/:id/reset", post(handlers::accounts::reset_account))
+        .route("/api/admin/invites", post(handlers::accounts::create_invite))
+        .route("/api/admin/export/:kind", get(handlers::export::export))
+        .route(
+            "/api/admin/import",
+            post(handlers::import::import_urls).layer(DefaultBodyLimit::max(50 * 1024 * 1024)),
+        )
         // Auth routes
-        .route("/auth/discord", get(handlers::auth::discord_redirect))
-        .route("/auth/discord/callback", get(handlers::auth::discord_callback))
+        .route("/api/auth/providers", get(handlers::auth::list_providers))
+        .route("/auth/:provider", get(handlers::auth::login))
+        .route("/auth/:provider/callback", get(handlers::auth::callback))
         .route("/auth/logout", get(handlers::auth::logout))
+        // Built-in accounts
+        .route("/api/accounts/login", post(handlers::accounts::login))
+        .route("/api/accounts/register", post(handlers::accounts::register))
+        .route("/api/accounts/reset", post(handlers::accounts::reset_password))
+        .route("/api/accounts/totp/setup", post(handlers::accounts::setup_totp))
+        .route("/api/accounts/totp/enable", post(handlers::accounts::enable_totp))
+        .route("/api/accounts/totp/disable", post(handlers::accounts::disable_totp))
         // Redirect route
-        .route("/:slug", get(handlers::redirect::handle_redirect))
+        .route(
+            "/:slug",
+            get(handlers::redirect::handle_redirect).post(handlers::redirect::unlock),
+        )
+        .route("/:slug/*path", get(handlers::redirect::handle_redirect_with_path))
         // Static files fallback
         .fallback_service(ServeDir::new("dist").fallback(ServeDir::new("dist").append_index_html_on_directories(true)))
         .layer(CorsLayer::new().allow_origin(Any).allow_methods(Any).allow_headers(Any))
+        .layer(axum::middleware::from_fn(error::request_id_layer))
         .with_state(state);
 
     let addr = SocketAddr::from(([0, 0, 0, 0], port));
     tracing::info!("🦀 Server running at http://{}", addr);
 
     let listener = tokio::net::TcpListener::bind(addr).await.unwrap();
-    axum::serve(listener, app).await.unwrap();
+    axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>())
+        .await
+        .unwrap();
 }
@@ -0,0 +1,114 @@
+use regex::Regex;
+use reqwest::Method;
+use std::sync::OnceLock;
+
+use crate::{
+    db::Database,
+    fetch::{fetch, FetchConfig},
+};
+
+// Stored values are cut to this many characters
+const MAX_TITLE_LENGTH: usize = 300;
+const MAX_DESCRIPTION_LENGTH: usize = 1000;
+
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct PageMetadata {
+    pub title: Option<String>,
+    pub description: Option<String>,
+}
+
+/// Pulls the `<title>` (falling back to `og:title`) and the OpenGraph
+/// description (falling back to the `description` meta tag) out of an HTML page.
+pub fn extract(html: &str) -> PageMetadata {
+    static TITLE: OnceLock<Regex> = OnceLock::new();
+    static META: OnceLock<Regex> = OnceLock::new();
+    static ATTR: OnceLock<Regex> = OnceLock::new();
+
+    let title_re = TITLE.get_or_init(|| Regex::new(r"(?is)<title[^>]*>(.*?)</title>").unwrap());
+    let meta_re = META.get_or_init(|| Regex::new(r"(?is)<meta\s[^>]*>").unwrap());
+    let attr_re = ATTR
+        .get_or_init(|| Regex::new(r#"(?is)([a-z:-]+)\s*=\s*(?:"([^"]*)"|'([^']*)')"#).unwrap());
+
+    let meta = |wanted: &str| {
+        meta_re.find_iter(html).find_map(|tag| {
+            let mut key = None;
+            let mut content = None;
+            for attr in attr_re.captures_iter(tag.as_str()) {
+                let value = attr.get(2).or(attr.get(3)).map_or("", |m| m.as_str());
+                match attr[1].to_ascii_lowercase().as_str() {
+                    "property" | "name" => key = Some(value.to_ascii_lowercase()),
+                    "content" => content = Some(value.to_string()),
+                    _ => {}
+                }
+            }
+            (key.as_deref() == Some(wanted))
+                .then_some(content)
+                .flatten()
+        })
+    };
+
+    let title = title_re
+        .captures(html)
+        .map(|c| c[1].to_string())
+        .or_else(|| meta("og:title"));
+    let description = meta("og:description").or_else(|| meta("description"));
+
+    PageMetadata {
+        title: title.and_then(|t| clean(&t, MAX_TITLE_LENGTH)),
+        description: description.and_then(|d| clean(&d, MAX_DESCRIPTION_LENGTH)),
+    }
+}
+
+/// Fetches the destination's title and description in the background and stores
+/// them on the link, unless they were already set.
+pub fn spawn_fetch(db: Database, config: FetchConfig, id: i64, url: String) {
+    tokio::spawn(async move {
+        let page = match fetch(&config, Method::GET, &url).await {
+            Ok(page) => page,
+            Err(e) => {
+                tracing::warn!("Metadata fetch for {} failed: {}", url, e);
+                return;
+            }
+        };
+
+        let is_html = page
+            .content_type
+            .as_deref()
+            .is_some_and(|ct| ct.to_ascii_lowercase().contains("text/html"));
+        if !(200..300).contains(&page.status) || !is_html {
+            return;
+        }
+
+        let metadata = extract(&String::from_utf8_lossy(&page.body));
+        if metadata == PageMetadata::default() {
+            return;
+        }
+        if let Err(e) = db
+            .set_fetched_metadata(
+                id,
+                metadata.title.as_deref(),
+                metadata.description.as_deref(),
+            )
+            .await
+        {
+            tracing::error!("Failed to store metadata for link {}: {}", id, e);
+        }
+    });
+}
+
+// Decodes the common entities, collapses whitespace and truncates
+fn clean(value: &str, max_length: usize) -> Option<String> {
+    let decoded = value
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&#x27;", "'")
+        .replace("&nbsp;", " ")
+        .replace("&amp;", "&");
+    let collapsed = decoded.split_whitespace().collect::<Vec<_>>().join(" ");
+    if collapsed.is_empty() {
+        return None;
+    }
+    Some(collapsed.chars().take(max_length).collect())
+}
@@ -1,20 +1,234 @@
-use serde::{Deserialize, Serialize};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Deserializer, Serialize};
 
-#[derive(Debug, Clone, Serialize, Deserialize, sqlx::FromRow)]
+use crate::auth::Role;
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, sqlx::FromRow)]
 pub struct UrlRecord {
     pub id: i64,
     pub slug: String,
     pub original_url: String,
     pub created_at: String,
     pub clicks: i64,
-    pub expires_at: Option<String>,
+    // Stored as UTC; see `expiry::format_utc`
+    pub expires_at: Option<DateTime<Utc>>,
+    pub folder_id: Option<i64>,
+    pub title: Option<String>,
+    pub description: Option<String>,
+    pub notes: Option<String>,
+    #[serde(skip)]
+    pub password_hash: Option<String>,
+    pub password_protected: bool,
+    pub max_clicks: Option<i64>,
+    // Stored as UTC like `expires_at`
+    pub activates_at: Option<DateTime<Utc>>,
+    // Where visitors go before `activates_at`; a notice page is shown when unset
+    pub pending_url: Option<String>,
+    pub disabled: bool,
+    pub disabled_reason: Option<String>,
+    // Where visitors go when the link is disabled, expired, used up or not live yet
+    pub fallback_url: Option<String>,
+    // HTML shown in the same cases when there is no `fallback_url`
+    pub fallback_page: Option<String>,
+    // 301, 302, 307 or 308; the instance default when unset
+    pub redirect_type: Option<i64>,
+    // "merge" or "override" to forward the visitor's query string; dropped when unset
+    pub query_passthrough: Option<String>,
+    // Append any path after the slug to the destination
+    pub path_passthrough: bool,
+    // Added to the destination's query at redirect time
+    pub utm_source: Option<String>,
+    pub utm_medium: Option<String>,
+    pub utm_campaign: Option<String>,
+    pub utm_term: Option<String>,
+    pub utm_content: Option<String>,
+    // The link this one is a UTM variant of
+    pub parent_id: Option<i64>,
+    // Keep returning visitors on the rotation destination they got first
+    pub sticky_rotation: bool,
+    // Username of the signed-in user who created the link
+    pub created_by: Option<String>,
+    // Session user id of the user who manages the link besides admins; unset
+    // for anonymous, imported and older links, which only admins manage
+    pub owner_id: Option<String>,
+    // Created without signing in. Older and imported links without a creator
+    // aren't known to be anonymous.
+    pub anonymous: bool,
+    // Visitors are warned before being redirected
+    pub untrusted: bool,
+    // Why the link was flagged, e.g. the threat feed its destination is listed in
+    pub untrusted_reason: Option<String>,
+    // Result of the last health check: of the first broken destination, or the
+    // link's own when all of them work. The status is unset when the request
+    // itself failed, with the reason in `health_error`
+    pub health_status: Option<i64>,
+    pub health_error: Option<String>,
+    pub health_latency_ms: Option<i64>,
+    // Every URL requested, one per line, ending with the one that answered
+    // (just the destination when the request failed)
+    pub health_chain: Option<String>,
+    pub health_checked_at: Option<String>,
+    // Failed checks in a row, used to back off
+    pub health_failures: i64,
+    // Comma-separated tag names
+    pub tags: Option<String>,
+}
+
+/// A link about to be inserted. Optional settings default to unset.
+#[derive(Debug, Clone, Default)]
+pub struct NewUrl {
+    pub slug: String,
+    pub original_url: String,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub title: Option<String>,
+    pub description: Option<String>,
+    pub notes: Option<String>,
+    pub password_hash: Option<String>,
+    pub max_clicks: Option<i64>,
+    pub activates_at: Option<DateTime<Utc>>,
+    pub pending_url: Option<String>,
+    pub fallback_url: Option<String>,
+    pub fallback_page: Option<String>,
+    pub redirect_type: Option<i64>,
+    pub query_passthrough: Option<String>,
+    pub path_passthrough: bool,
+    pub utm: UtmParams,
+    pub parent_id: Option<i64>,
+    pub created_by: Option<String>,
+    pub owner_id: Option<String>,
+    pub anonymous: bool,
+    pub untrusted: bool,
+    pub untrusted_reason: Option<String>,
+}
+
+/// Campaign parameters kept apart from the destination so they can't be mistyped into it.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct UtmParams {
+    #[serde(alias = "utm_source")]
+    pub source: Option<String>,
+    #[serde(alias = "utm_medium")]
+    pub medium: Option<String>,
+    #[serde(alias = "utm_campaign")]
+    pub campaign: Option<String>,
+    #[serde(alias = "utm_term")]
+    pub term: Option<String>,
+    #[serde(alias = "utm_content")]
+    pub content: Option<String>,
+}
+
+/// Sends visitors whose user agent matches to another destination. Rules are
+/// checked in order; a rule needs a platform, a browser or both.
+#[derive(Debug, Clone, Serialize, Deserialize, sqlx::FromRow)]
+pub struct DeviceRule {
+    pub platform: Option<String>,
+    pub browser: Option<String>,
+    pub url: String,
+}
+
+/// Sends visitors from a country (ISO 3166-1 alpha-2 code) to another destination.
+#[derive(Debug, Clone, Serialize, Deserialize, sqlx::FromRow)]
+pub struct GeoRule {
+    pub country: String,
+    pub url: String,
+}
+
+/// One of several destinations a link rotates between, picked per click in
+/// proportion to its weight. A weight of 0 pauses it.
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+pub struct RotationDestination {
+    pub id: i64,
+    pub url: String,
+    pub weight: i64,
+    pub clicks: i64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Rotation {
+    pub sticky: bool,
+    pub destinations: Vec<RotationDestination>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, sqlx::FromRow)]
+pub struct ClickEvent {
+    pub id: i64,
+    pub url_id: i64,
+    pub slug: String,
+    pub clicked_at: String,
+    pub referrer: Option<String>,
+    pub user_agent: Option<String>,
+    pub country: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+pub struct CountryStats {
+    // Unknown when no GeoIP database is configured or the address isn't in it
+    pub country: Option<String>,
+    pub clicks: i64,
+}
+
+/// A scheduled maintenance job and its last run.
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+pub struct JobRun {
+    pub name: String,
+    pub schedule: String,
+    pub next_run_at: String,
+    // The replica running the job right now, if any
+    pub lease_holder: Option<String>,
+    pub lease_until: Option<String>,
+    pub last_started_at: Option<String>,
+    pub last_finished_at: Option<String>,
+    // "ok" or "failed"
+    pub last_status: Option<String>,
+    pub last_message: Option<String>,
 }
 
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+pub struct TagStats {
+    pub id: i64,
+    pub name: String,
+    pub links: i64,
+    pub clicks: i64,
+}
+
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+pub struct FolderStats {
+    pub id: i64,
+    pub name: String,
+    pub created_at: String,
+    pub links: i64,
+    pub clicks: i64,
+}
+
+/// The signed-in user as stored in the session token.
 #[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct DiscordUser {
+pub struct SessionUser {
     pub id: String,
     pub username: String,
     pub avatar: Option<String>,
+    pub role: Role,
+}
+
+/// A built-in account, for instances without an external login provider.
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+pub struct Account {
+    pub id: i64,
+    pub username: String,
+    #[serde(skip)]
+    pub password_hash: String,
+    pub role: Role,
+    // Base32; set by TOTP setup and only checked once `totp_enabled`
+    #[serde(skip)]
+    pub totp_secret: Option<String>,
+    pub totp_enabled: bool,
+    // The last time step a code was accepted for, so codes can't be replayed
+    #[serde(skip)]
+    pub totp_last_step: Option<i64>,
+    pub disabled: bool,
+    // Unix time in milliseconds; sessions issued up to then are rejected
+    #[serde(skip)]
+    pub sessions_valid_after: i64,
+    pub created_by: Option<String>,
+    pub created_at: String,
 }
 
 // Request/Response DTOs
@@ -26,6 +240,29 @@ pub struct CreateUrlRequest {
     pub custom_slug: Option<String>,
     #[serde(rename = "expiresAt")]
     pub expires_at: Option<String>,
+    pub title: Option<String>,
+    pub description: Option<String>,
+    pub notes: Option<String>,
+    pub password: Option<String>,
+    #[serde(rename = "maxClicks")]
+    pub max_clicks: Option<i64>,
+    // Shorthand for `maxClicks: 1`
+    #[serde(rename = "oneTime", default)]
+    pub one_time: bool,
+    #[serde(rename = "activatesAt")]
+    pub activates_at: Option<String>,
+    #[serde(rename = "pendingUrl")]
+    pub pending_url: Option<String>,
+    #[serde(rename = "fallbackUrl")]
+    pub fallback_url: Option<String>,
+    #[serde(rename = "redirectType")]
+    pub redirect_type: Option<i64>,
+    #[serde(rename = "queryPassthrough")]
+    pub query_passthrough: Option<String>,
+    #[serde(rename = "pathPassthrough", default)]
+    pub path_passthrough: bool,
+    #[serde(default)]
+    pub utm: UtmParams,
 }
 
 #[derive(Debug, Serialize)]
@@ -34,18 +271,165 @@ pub struct CreateUrlResponse {
     pub short_url: String,
     pub slug: String,
     pub original_url: String,
-    pub expires_at: Option<String>,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub max_clicks: Option<i64>,
+    pub activates_at: Option<DateTime<Utc>>,
 }
 
+/// The body of every API error; see `error::AppError`.
 #[derive(Debug, Serialize)]
-#[allow(dead_code)]
 pub struct ErrorResponse {
+    // Human-readable message
     pub error: String,
+    // Stable identifier for clients to match on, e.g. "slug_taken"
+    pub code: &'static str,
+    // Same as the X-Request-Id response header, for finding the server log entry
+    pub request_id: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct UrlFilter {
+    pub q: Option<String>,
+    pub created_after: Option<String>,
+    pub created_before: Option<String>,
+    pub tag: Option<String>,
+    pub folder: Option<String>,
+    // Only links whose last health check failed
+    #[serde(default)]
+    pub broken: bool,
+    // Set by the handlers for users who aren't admins, never from the query
+    #[serde(skip)]
+    pub owner_id: Option<String>,
 }
 
 #[derive(Debug, Deserialize)]
+pub struct ExportQuery {
+    pub format: Option<String>,
+    #[serde(flatten)]
+    pub filter: UrlFilter,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ImportQuery {
+    pub format: String,
+    #[serde(default)]
+    pub dry_run: bool,
+}
+
+// Partial update: a missing field is left alone, an explicit `null` clears it
+#[derive(Debug, Default, Deserialize)]
 pub struct UpdateUrlRequest {
-    pub expires_at: Option<String>,
+    #[serde(default, deserialize_with = "nullable")]
+    pub expires_at: Option<Option<String>>,
+    #[serde(default, deserialize_with = "nullable")]
+    pub title: Option<Option<String>>,
+    #[serde(default, deserialize_with = "nullable")]
+    pub description: Option<Option<String>>,
+    #[serde(default, deserialize_with = "nullable")]
+    pub notes: Option<Option<String>>,
+    // Hashed by the handler and stored separately; `null` removes the password
+    #[serde(default, deserialize_with = "nullable")]
+    pub password: Option<Option<String>>,
+    #[serde(default, deserialize_with = "nullable")]
+    pub max_clicks: Option<Option<i64>>,
+    #[serde(default, deserialize_with = "nullable")]
+    pub activates_at: Option<Option<String>>,
+    #[serde(default, deserialize_with = "nullable")]
+    pub pending_url: Option<Option<String>>,
+    // This, `disabled_reason`, `fallback_page` and `untrusted` need the admin role
+    pub disabled: Option<bool>,
+    #[serde(default, deserialize_with = "nullable")]
+    pub disabled_reason: Option<Option<String>>,
+    #[serde(default, deserialize_with = "nullable")]
+    pub fallback_url: Option<Option<String>>,
+    // Served as HTML from our domain
+    #[serde(default, deserialize_with = "nullable")]
+    pub fallback_page: Option<Option<String>>,
+    #[serde(default, deserialize_with = "nullable")]
+    pub redirect_type: Option<Option<i64>>,
+    #[serde(default, deserialize_with = "nullable")]
+    pub query_passthrough: Option<Option<String>>,
+    pub path_passthrough: Option<bool>,
+    #[serde(default, deserialize_with = "nullable")]
+    pub utm_source: Option<Option<String>>,
+    #[serde(default, deserialize_with = "nullable")]
+    pub utm_medium: Option<Option<String>>,
+    #[serde(default, deserialize_with = "nullable")]
+    pub utm_campaign: Option<Option<String>>,
+    #[serde(default, deserialize_with = "nullable")]
+    pub utm_term: Option<Option<String>>,
+    #[serde(default, deserialize_with = "nullable")]
+    pub utm_content: Option<Option<String>>,
+    pub untrusted: Option<bool>,
+}
+
+fn nullable<'de, T, D>(deserializer: D) -> Result<Option<Option<T>>, D::Error>
+where
+    T: Deserialize<'de>,
+    D: Deserializer<'de>,
+{
+    Option::<T>::deserialize(deserializer).map(Some)
+}
+
+#[derive(Debug, Deserialize)]
+pub struct NameRequest {
+    pub name: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SetTagsRequest {
+    pub tags: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SetDeviceRulesRequest {
+    pub rules: Vec<DeviceRule>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SetGeoRulesRequest {
+    pub rules: Vec<GeoRule>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RotationEntry {
+    pub url: String,
+    pub weight: i64,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SetRotationRequest {
+    pub destinations: Vec<RotationEntry>,
+    #[serde(default)]
+    pub sticky: bool,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SetFolderRequest {
+    pub folder_id: Option<i64>,
+}
+
+/// A UTM variant of an existing link; everything else is copied from the parent.
+#[derive(Debug, Deserialize)]
+pub struct CreateVariantRequest {
+    pub slug: Option<String>,
+    #[serde(flatten)]
+    pub utm: UtmParams,
+}
+
+/// A link with its UTM variants, for comparing them side by side.
+#[derive(Debug, Serialize)]
+pub struct VariantGroup {
+    pub parent: UrlRecord,
+    pub variants: Vec<UrlRecord>,
+    // Clicks on the parent and all of its variants
+    pub total_clicks: i64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct IdResponse {
+    pub success: bool,
+    pub id: i64,
 }
 
 #[derive(Debug, Serialize)]
@@ -55,15 +439,109 @@ pub struct SuccessResponse {
 
 #[derive(Debug, Serialize)]
 pub struct MeResponse {
-    pub user: DiscordUser,
+    pub user: SessionUser,
 }
 
 #[derive(Debug, Deserialize)]
-pub struct DiscordTokenResponse {
-    pub access_token: String,
+pub struct UnlockForm {
+    pub password: String,
+    // Path and query that followed the slug on the original request
+    #[serde(default)]
+    pub next: String,
 }
 
 #[derive(Debug, Deserialize)]
 pub struct CallbackQuery {
     pub code: Option<String>,
+    pub state: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RegisterRequest {
+    pub invite: String,
+    pub username: String,
+    pub password: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AccountLoginRequest {
+    pub username: String,
+    pub password: String,
+    // A TOTP or recovery code, for accounts with a second factor
+    pub code: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct AccountLoginResponse {
+    pub user: SessionUser,
+    // The session token, for API clients that can't keep cookies
+    pub token: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ResetPasswordRequest {
+    pub token: String,
+    pub password: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TotpCodeRequest {
+    pub code: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DisableTotpRequest {
+    pub password: String,
+    // A TOTP or recovery code; not needed to cancel a setup that wasn't confirmed
+    pub code: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TotpSetupResponse {
+    pub secret: String,
+    pub otpauth_url: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RecoveryCodesResponse {
+    pub recovery_codes: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CreateAccountRequest {
+    pub username: String,
+    pub password: String,
+    #[serde(default = "default_role")]
+    pub role: Role,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UpdateAccountRequest {
+    pub role: Option<Role>,
+    pub disabled: Option<bool>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct AdminResetRequest {
+    // Also turn off the second factor, for users who lost their authenticator
+    #[serde(default)]
+    pub reset_totp: bool,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct InviteRequest {
+    #[serde(default = "default_role")]
+    pub role: Role,
+}
+
+fn default_role() -> Role {
+    Role::User
+}
+
+/// A single-use invite or password reset token. Only its hash is stored, so
+/// it is shown once.
+#[derive(Debug, Serialize)]
+pub struct AccountTokenResponse {
+    pub token: String,
+    pub expires_at: String,
 }
@@ -0,0 +1,244 @@
+// Small server-rendered pages for visitors following short links
+
+use crate::{interstitial::destination_host, models::UrlRecord};
+
+/// Escapes text for use in HTML content and attribute values.
+pub fn escape(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            _ => out.push(c),
+        }
+    }
+    out
+}
+
+// Wraps already-escaped body markup in a minimal standalone document
+fn layout(title: &str, body: &str) -> String {
+    format!(
+        r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+<meta charset="utf-8">
+<meta name="viewport" content="width=device-width, initial-scale=1">
+<meta name="robots" content="noindex">
+<title>{title}</title>
+<style>
+body {{ font-family: system-ui, sans-serif; background: #f5f5f7; color: #1d1d1f; display: flex; min-height: 100vh; align-items: center; justify-content: center; margin: 0; }}
+main {{ background: #fff; padding: 2rem; border-radius: 12px; box-shadow: 0 2px 12px rgba(0,0,0,.08); max-width: 28rem; width: 100%; }}
+input, button, .button {{ font: inherit; padding: .6rem .8rem; border-radius: 8px; }}
+input {{ border: 1px solid #ccc; width: 100%; box-sizing: border-box; margin-bottom: .8rem; }}
+button, .button {{ border: 0; background: #1d1d1f; color: #fff; cursor: pointer; text-decoration: none; display: inline-block; }}
+.error {{ color: #c00; }}
+.muted {{ color: #666; word-break: break-all; }}
+dl {{ display: grid; grid-template-columns: auto 1fr; gap: .4rem 1rem; }}
+dt {{ color: #666; }}
+dd {{ margin: 0; word-break: break-all; }}
+</style>
+</head>
+<body>
+<main>
+{body}
+</main>
+</body>
+</html>
+"#,
+        title = escape(title),
+        body = body,
+    )
+}
+
+/// `next` is the path and query that followed the slug, restored after unlocking.
+pub fn password_form(slug: &str, next: &str, error: Option<&str>) -> String {
+    let error = error
+        .map(|e| format!(r#"<p class="error">{}</p>"#, escape(e)))
+        .unwrap_or_default();
+    layout(
+        "Password required",
+        &format!(
+            r#"<h1>Password required</h1>
+<p>This link is protected. Enter the password to continue.</p>
+{error}
+<form method="post" action="/{slug}">
+<input type="hidden" name="next" value="{next}">
+<input type="password" name="password" autofocus required>
+<button type="submit">Continue</button>
+</form>"#,
+            error = error,
+            slug = escape(&urlencoding::encode(slug)),
+            next = escape(next),
+        ),
+    )
+}
+
+/// Shown before a scheduled link goes live. `template` replaces the built-in page;
+/// `{activates_at}` in it is substituted.
+pub fn not_yet_active(template: Option<&str>, activates_at: &str) -> String {
+    if let Some(template) = template {
+        return template.replace("{activates_at}", &escape(activates_at));
+    }
+    layout(
+        "Not available yet",
+        &format!(
+            r#"<h1>Not available yet</h1>
+<p>This link becomes available at <time datetime="{at}">{at}</time>.</p>"#,
+            at = escape(activates_at),
+        ),
+    )
+}
+
+/// Renders a user-supplied fallback page, substituting `{slug}` and `{reason}`.
+pub fn fallback(template: &str, slug: &str, reason: &str) -> String {
+    template
+        .replace("{slug}", &escape(slug))
+        .replace("{reason}", &escape(reason))
+}
+
+/// Shows where a link goes without following it. The destination of a
+/// password-protected link stays hidden, and links that can't be followed say why.
+pub fn preview(record: &UrlRecord, unavailable: Option<&str>) -> String {
+    let link = format!("/{}", urlencoding::encode(&record.slug));
+    let mut details = String::new();
+    let mut row = |label: &str, value: &str| {
+        details.push_str(&format!(
+            "<dt>{}</dt><dd>{}</dd>\n",
+            escape(label),
+            escape(value)
+        ));
+    };
+
+    if record.password_protected {
+        row("Destination", "Hidden until the password is entered");
+    } else {
+        if let Some(title) = &record.title {
+            row("Title", title);
+        }
+        row("Destination", &record.original_url);
+    }
+    row("Created", &record.created_at);
+    let owner = match &record.created_by {
+        Some(username) => username.as_str(),
+        None if record.anonymous => "Anonymous",
+        None => "Unknown",
+    };
+    row("Owner", owner);
+    row("Clicks", &record.clicks.to_string());
+
+    let action = match unavailable {
+        Some(reason) => format!(r#"<p class="error">{}</p>"#, escape(reason)),
+        None => format!(
+            r#"<a class="button" href="{}" rel="nofollow noreferrer">Continue</a>"#,
+            escape(&link)
+        ),
+    };
+
+    layout(
+        "Link preview",
+        &format!(
+            r#"<h1>Link preview</h1>
+<p class="muted">{link}</p>
+<dl>
+{details}</dl>
+{action}"#,
+            link = escape(&link),
+            details = details,
+            action = action,
+        ),
+    )
+}
+
+/// Warns visitors before they leave for `destination`. The continue button
+/// unlocks after `countdown_secs`; without JavaScript it is usable right away.
+pub fn interstitial(
+    destination: &str,
+    continue_url: &str,
+    reason: &str,
+    countdown_secs: u64,
+) -> String {
+    let host = destination_host(destination).unwrap_or_else(|| destination.to_string());
+    layout(
+        "Leaving meoShortURL",
+        &format!(
+            r#"<h1>You are leaving meoShortURL</h1>
+<p>{reason}</p>
+<p>This link goes to <strong>{host}</strong>:</p>
+<p class="muted">{destination}</p>
+<a class="button" id="continue" href="{continue_url}" rel="nofollow noreferrer">Continue to {host}</a>
+<script>
+(function () {{
+  var button = document.getElementById("continue");
+  var href = button.getAttribute("href");
+  var label = button.textContent;
+  var remaining = {countdown};
+  function tick() {{
+    if (remaining <= 0) {{
+      button.setAttribute("href", href);
+      button.style.opacity = "";
+      button.textContent = label;
+      return;
+    }}
+    button.removeAttribute("href");
+    button.style.opacity = ".5";
+    button.textContent = label + " (" + remaining + ")";
+    remaining -= 1;
+    setTimeout(tick, 1000);
+  }}
+  tick();
+}})();
+</script>"#,
+            reason = escape(reason),
+            host = escape(&host),
+            destination = escape(destination),
+            continue_url = escape(continue_url),
+            countdown = countdown_secs,
+        ),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn link() -> UrlRecord {
+        UrlRecord {
+            slug: "docs".to_string(),
+            original_url: "https://secret.example/path?a=1&b=2".to_string(),
+            title: Some("Quarterly <plan>".to_string()),
+            created_by: Some("alice".to_string()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn preview_shows_where_a_link_goes() {
+        let page = preview(&link(), None);
+        assert!(page.contains("https://secret.example/path?a=1&amp;b=2"));
+        assert!(page.contains("Quarterly &lt;plan&gt;"));
+        assert!(page.contains("alice"));
+        assert!(page.contains(r#"href="/docs""#));
+    }
+
+    #[test]
+    fn preview_hides_password_protected_destinations() {
+        let record = UrlRecord {
+            password_protected: true,
+            ..link()
+        };
+        let page = preview(&record, None);
+        assert!(!page.contains("secret.example"));
+        assert!(!page.contains("Quarterly"));
+        assert!(page.contains("Hidden until the password is entered"));
+    }
+
+    #[test]
+    fn preview_of_unavailable_links_has_no_continue_button() {
+        let page = preview(&link(), Some("This link has expired."));
+        assert!(page.contains("This link has expired."));
+        assert!(!page.contains("Continue</a>"));
+    }
+}
@@ -0,0 +1,51 @@
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+// Expired entries are swept once the map grows past this
+const PRUNE_THRESHOLD: usize = 10_000;
+
+/// Counts failures per key within a fixed window. In-memory, so limits are per process.
+pub struct RateLimiter {
+    max_failures: u32,
+    window: Duration,
+    entries: Mutex<HashMap<String, (u32, Instant)>>,
+}
+
+impl RateLimiter {
+    pub fn new(max_failures: u32, window: Duration) -> Self {
+        Self {
+            max_failures,
+            window,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Whether `key` has used up its failures for the current window.
+    pub fn is_limited(&self, key: &str) -> bool {
+        let entries = self.entries.lock().unwrap();
+        entries.get(key).is_some_and(|(count, started)| {
+            started.elapsed() < self.window && *count >= self.max_failures
+        })
+    }
+
+    pub fn record_failure(&self, key: &str) {
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() > PRUNE_THRESHOLD {
+            entries.retain(|_, (_, started)| started.elapsed() < self.window);
+        }
+        let entry = entries
+            .entry(key.to_string())
+            .or_insert((0, Instant::now()));
+        if entry.1.elapsed() >= self.window {
+            *entry = (0, Instant::now());
+        }
+        entry.0 += 1;
+    }
+
+    pub fn reset(&self, key: &str) {
+        self.entries.lock().unwrap().remove(key);
+    }
+}
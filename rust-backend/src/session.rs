@@ -1,27 +1,91 @@
-use crate::models::DiscordUser;
-use base64::{engine::general_purpose::STANDARD, Engine};
+use crate::{models::SessionUser, signing::Signer};
+use axum::http::{header, HeaderMap};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use serde::{Deserialize, Serialize};
 
 pub const SESSION_COOKIE: &str = "meo_session";
+pub const SESSION_TTL_SECS: i64 = 60 * 60 * 24 * 7;
 
-pub fn encode_session(user: &DiscordUser) -> String {
-    let json = serde_json::to_string(user).unwrap_or_default();
-    STANDARD.encode(json.as_bytes())
+// The token payload: the user plus when the token was issued, in milliseconds
+#[derive(Serialize)]
+struct Claims<'a> {
+    #[serde(flatten)]
+    user: &'a SessionUser,
+    iat: i64,
 }
 
-pub fn decode_session(cookie: &str) -> Option<DiscordUser> {
-    let bytes = STANDARD.decode(cookie).ok()?;
+#[derive(Deserialize)]
+struct IssuedAt {
+    iat: i64,
+}
+
+/// Session tokens are `<base64 JSON>.<expiry>.<signature>`, so the user and
+/// role in them can't be altered and they stop working after a week. The same
+/// token is accepted as a cookie or as a bearer token.
+pub fn encode_session(user: &SessionUser, signer: &Signer) -> String {
+    let claims = Claims {
+        user,
+        iat: chrono::Utc::now().timestamp_millis(),
+    };
+    let json = serde_json::to_string(&claims).unwrap_or_default();
+    let payload = URL_SAFE_NO_PAD.encode(json.as_bytes());
+    let signature = signer.sign_expiring(&session_message(&payload), SESSION_TTL_SECS);
+    format!("{}.{}", payload, signature)
+}
+
+pub fn decode_session(token: &str, signer: &Signer) -> Option<SessionUser> {
+    let (payload, signature) = token.split_once('.')?;
+    if !signer.verify_expiring(&session_message(payload), signature) {
+        return None;
+    }
+    let bytes = URL_SAFE_NO_PAD.decode(payload).ok()?;
     let json = String::from_utf8(bytes).ok()?;
     serde_json::from_str(&json).ok()
 }
 
-pub fn extract_session_from_cookie(cookie_header: &str) -> Option<DiscordUser> {
-    for cookie in cookie_header.split("; ") {
-        let mut parts = cookie.splitn(2, '=');
-        if let (Some(key), Some(value)) = (parts.next(), parts.next()) {
-            if key == SESSION_COOKIE {
-                return decode_session(value);
-            }
-        }
-    }
-    None
+// Keeps session signatures distinct from other values signed with the same key
+fn session_message(payload: &str) -> String {
+    format!("session|{}", payload)
+}
+
+/// When a session token was issued, as Unix time in milliseconds.
+pub fn session_issued_at(token: &str) -> Option<i64> {
+    let (payload, _) = token.split_once('.')?;
+    let payload = URL_SAFE_NO_PAD.decode(payload).ok()?;
+    serde_json::from_slice::<IssuedAt>(&payload)
+        .ok()
+        .map(|claims| claims.iat)
+}
+
+/// The session token from an `Authorization: Bearer` header, or else the
+/// session cookie.
+pub fn session_token(headers: &HeaderMap) -> Option<String> {
+    let bearer = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.trim().split_once(' '))
+        .filter(|(scheme, _)| scheme.eq_ignore_ascii_case("bearer"))
+        .map(|(_, token)| token.trim().to_string());
+    bearer.or_else(|| cookie_value(headers, SESSION_COOKIE))
+}
+
+/// Looks up a cookie by name across all `Cookie` headers. Pairs are separated
+/// by `;` with optional whitespace, and values may be wrapped in double quotes
+/// (RFC 6265). The first cookie with the name wins.
+pub fn cookie_value(headers: &HeaderMap, name: &str) -> Option<String> {
+    headers
+        .get_all(header::COOKIE)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(';'))
+        .filter_map(|pair| pair.split_once('='))
+        .find(|(key, _)| key.trim() == name)
+        .map(|(_, value)| {
+            let value = value.trim();
+            value
+                .strip_prefix('"')
+                .and_then(|v| v.strip_suffix('"'))
+                .unwrap_or(value)
+                .to_string()
+        })
 }
@@ -0,0 +1,69 @@
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use sha2::Sha256;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Signs short server-issued values (cookies, links) with HMAC-SHA256.
+#[derive(Clone)]
+pub struct Signer {
+    key: Vec<u8>,
+}
+
+impl Signer {
+    /// Uses `SIGNING_SECRET`, or a random key when unset. A random key means
+    /// signed values stop validating after a restart.
+    pub fn from_env() -> Self {
+        match std::env::var("SIGNING_SECRET") {
+            Ok(secret) if !secret.is_empty() => Self::new(secret.into_bytes()),
+            _ => {
+                tracing::warn!("SIGNING_SECRET not set, using a random key for this run");
+                let mut key = vec![0u8; 32];
+                rand::thread_rng().fill_bytes(&mut key);
+                Self::new(key)
+            }
+        }
+    }
+
+    pub fn new(key: Vec<u8>) -> Self {
+        Self { key }
+    }
+
+    pub fn sign(&self, message: &str) -> String {
+        let mut mac = HmacSha256::new_from_slice(&self.key).expect("HMAC accepts any key length");
+        mac.update(message.as_bytes());
+        URL_SAFE_NO_PAD.encode(mac.finalize().into_bytes())
+    }
+
+    pub fn verify(&self, message: &str, signature: &str) -> bool {
+        let Ok(signature) = URL_SAFE_NO_PAD.decode(signature) else {
+            return false;
+        };
+        let mut mac = HmacSha256::new_from_slice(&self.key).expect("HMAC accepts any key length");
+        mac.update(message.as_bytes());
+        mac.verify_slice(&signature).is_ok()
+    }
+
+    /// Signs `message` with an expiry, producing `<unix expiry>.<signature>`.
+    pub fn sign_expiring(&self, message: &str, ttl_secs: i64) -> String {
+        let expires = chrono::Utc::now().timestamp() + ttl_secs;
+        format!(
+            "{}.{}",
+            expires,
+            self.sign(&format!("{}|{}", message, expires))
+        )
+    }
+
+    /// Checks a token produced by `sign_expiring` for the same `message`.
+    pub fn verify_expiring(&self, message: &str, token: &str) -> bool {
+        let Some((expires, signature)) = token.split_once('.') else {
+            return false;
+        };
+        let Ok(expires_at) = expires.parse::<i64>() else {
+            return false;
+        };
+        expires_at > chrono::Utc::now().timestamp()
+            && self.verify(&format!("{}|{}", message, expires), signature)
+    }
+}
@@ -0,0 +1,593 @@
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashSet,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, SystemTime},
+};
+
+use crate::db::Database;
+
+/// What happens to existing links whose destination shows up in a feed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ThreatAction {
+    // Take the link offline
+    Disable,
+    // Keep it working behind the untrusted-link interstitial
+    Flag,
+}
+
+/// A destination found in a feed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ThreatMatch {
+    pub feed: String,
+    pub entry: String,
+}
+
+impl ThreatMatch {
+    pub fn message(&self) -> String {
+        format!("Listed in threat feed {} ({})", self.feed, self.entry)
+    }
+}
+
+/// Entry counts of a loaded feed, for the admin API.
+#[derive(Debug, Clone, Serialize)]
+pub struct FeedSummary {
+    pub name: String,
+    pub urls: usize,
+    pub hosts: usize,
+    pub hashes: usize,
+}
+
+#[derive(Default)]
+struct Feed {
+    name: String,
+    // Destinations keyed by `url_key`, so the scheme doesn't matter
+    urls: HashSet<String>,
+    // Domains whose every page is listed, including subdomains
+    hosts: HashSet<String>,
+    // SHA-256 hashes of Safe Browsing style "host/path" expressions
+    hashes: HashSet<[u8; 32]>,
+}
+
+impl Feed {
+    // Files ending in `.hashes` or `.sha256` hold one hex SHA-256 hash per line.
+    // Shorter hash prefixes are skipped: with no full-hash lookup to confirm
+    // them, a 4-byte prefix would take down unrelated links.
+    // Anything else is read as CSV (URLhaus and PhishTank dumps) or plain text,
+    // taking the first http(s) URL of each row, or a bare domain / hosts-file
+    // entry when the row has a single field.
+    fn load(path: &Path) -> Result<Self, String> {
+        let name = path
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let content =
+            std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        let mut feed = Feed {
+            name,
+            ..Default::default()
+        };
+
+        let extension = path.extension().and_then(|e| e.to_str()).unwrap_or("");
+        if extension == "hashes" || extension == "sha256" {
+            let mut prefixes = 0;
+            for (i, line) in content.lines().enumerate() {
+                let line = line.trim();
+                if line.is_empty() || line.starts_with('#') {
+                    continue;
+                }
+                let bytes = decode_hex(line)
+                    .filter(|bytes| (4..=32).contains(&bytes.len()))
+                    .ok_or_else(|| {
+                        format!("{} line {}: expected a hex hash", path.display(), i + 1)
+                    })?;
+                match <[u8; 32]>::try_from(bytes) {
+                    Ok(hash) => {
+                        feed.hashes.insert(hash);
+                    }
+                    Err(_) => prefixes += 1,
+                }
+            }
+            if prefixes > 0 {
+                tracing::warn!(
+                    "{}: skipped {} hash prefixes, only full SHA-256 hashes are used",
+                    path.display(),
+                    prefixes
+                );
+            }
+            return Ok(feed);
+        }
+
+        let mut reader = csv::ReaderBuilder::new()
+            .has_headers(false)
+            .flexible(true)
+            .comment(Some(b'#'))
+            .from_reader(content.as_bytes());
+        for record in reader.records() {
+            let Ok(record) = record else {
+                continue;
+            };
+            let url = record.iter().map(str::trim).find(|field| {
+                let lower = field.to_ascii_lowercase();
+                lower.starts_with("http://") || lower.starts_with("https://")
+            });
+            if let Some(key) = url.and_then(url_key) {
+                feed.urls.insert(key);
+            } else if record.len() == 1 {
+                let host = record[0].split_whitespace().last().unwrap_or("");
+                let host = host.trim_end_matches('.').to_ascii_lowercase();
+                if is_domain(&host) && host != "localhost" {
+                    feed.hosts.insert(host);
+                }
+            }
+        }
+        Ok(feed)
+    }
+
+    fn check(&self, key: &str, host: &str, expressions: &[String]) -> Option<ThreatMatch> {
+        let found = |entry: String| {
+            Some(ThreatMatch {
+                feed: self.name.clone(),
+                entry,
+            })
+        };
+        if self.urls.contains(key) {
+            return found(key.to_string());
+        }
+        if let Some(domain) = parent_domains(host).find(|d| self.hosts.contains(*d)) {
+            return found(domain.to_string());
+        }
+        for expression in expressions {
+            let digest: [u8; 32] = Sha256::digest(expression.as_bytes()).into();
+            if self.hashes.contains(&digest) {
+                return found(format!("hash of {}", expression));
+            }
+        }
+        None
+    }
+
+    fn summary(&self) -> FeedSummary {
+        FeedSummary {
+            name: self.name.clone(),
+            urls: self.urls.len(),
+            hosts: self.hosts.len(),
+            hashes: self.hashes.len(),
+        }
+    }
+}
+
+/// Malicious-URL lists synced to a local directory (`THREAT_FEED_DIR`, default
+/// `data/threat_feeds`). Nothing is looked up over the network: feeds are
+/// re-read when a file in the directory is added, removed or modified.
+pub struct ThreatFeeds {
+    dir: PathBuf,
+    pub action: ThreatAction,
+    feeds: RwLock<Vec<Feed>>,
+    // File names and modification times the loaded feeds were read from
+    loaded: Mutex<Option<Vec<(PathBuf, SystemTime)>>>,
+}
+
+impl ThreatFeeds {
+    pub fn from_env() -> Self {
+        let dir =
+            std::env::var("THREAT_FEED_DIR").unwrap_or_else(|_| "data/threat_feeds".to_string());
+        let action = match std::env::var("THREAT_FEED_ACTION").as_deref() {
+            Ok("flag") => ThreatAction::Flag,
+            Ok("disable") | Err(_) => ThreatAction::Disable,
+            Ok(_) => panic!("THREAT_FEED_ACTION must be disable or flag"),
+        };
+        Self::new(PathBuf::from(dir), action)
+    }
+
+    /// The feeds in `dir`; a missing directory means no feeds.
+    pub fn new(dir: PathBuf, action: ThreatAction) -> Self {
+        let feeds = Self {
+            dir,
+            action,
+            feeds: RwLock::new(Vec::new()),
+            loaded: Mutex::new(None),
+        };
+        feeds.reload_if_changed();
+        feeds
+    }
+
+    /// The first feed listing `url`, if any. URLs that don't parse can't be
+    /// listed and always pass.
+    pub fn check(&self, url: &str) -> Option<ThreatMatch> {
+        let key = url_key(url)?;
+        let host = url::Url::parse(url).ok()?.host_str()?.to_ascii_lowercase();
+        let expressions = hash_expressions(url);
+        self.feeds
+            .read()
+            .unwrap()
+            .iter()
+            .find_map(|feed| feed.check(&key, &host, &expressions))
+    }
+
+    pub fn summaries(&self) -> Vec<FeedSummary> {
+        self.feeds
+            .read()
+            .unwrap()
+            .iter()
+            .map(Feed::summary)
+            .collect()
+    }
+
+    /// Re-reads the directory if anything in it changed. Returns whether the
+    /// feeds were reloaded; a feed that fails to load keeps the previous set.
+    pub fn reload_if_changed(&self) -> bool {
+        let files = match self.list_files() {
+            Ok(files) => files,
+            Err(e) if e.kind() == ErrorKind::NotFound => Vec::new(),
+            Err(e) => {
+                tracing::warn!("Threat feeds in {} unavailable: {}", self.dir.display(), e);
+                return false;
+            }
+        };
+        let mut loaded = self.loaded.lock().unwrap();
+        if loaded.as_ref() == Some(&files) {
+            return false;
+        }
+
+        let feeds: Result<Vec<Feed>, String> =
+            files.iter().map(|(path, _)| Feed::load(path)).collect();
+        match feeds {
+            Ok(feeds) => {
+                let entries: usize = feeds
+                    .iter()
+                    .map(Feed::summary)
+                    .map(|f| f.urls + f.hosts + f.hashes)
+                    .sum();
+                tracing::info!(
+                    "Loaded {} threat feeds ({} entries) from {}",
+                    feeds.len(),
+                    entries,
+                    self.dir.display()
+                );
+                *self.feeds.write().unwrap() = feeds;
+                *loaded = Some(files);
+                true
+            }
+            Err(e) => {
+                tracing::error!("Failed to load threat feeds: {}", e);
+                false
+            }
+        }
+    }
+
+    /// Checks every enabled link against the feeds and disables or flags the
+    /// ones that match. Returns how many links were affected.
+    ///
+    /// Links re-enabled or unflagged by an admin are caught again as long as
+    /// their destination is still listed.
+    pub async fn screen_links(&self, db: &Database) -> Result<usize, sqlx::Error> {
+        let links = db
+            .get_screenable_links(self.action == ThreatAction::Flag)
+            .await?;
+        let mut matched = 0;
+        for (id, slug, destinations) in links {
+            let Some(threat) = destinations.iter().find_map(|url| self.check(url)) else {
+                continue;
+            };
+            let reason = threat.message();
+            match self.action {
+                ThreatAction::Disable => db.disable_url(id, &reason).await?,
+                ThreatAction::Flag => db.flag_untrusted(id, &reason).await?,
+            }
+            tracing::warn!("Link {}: {}", slug, reason);
+            matched += 1;
+        }
+        Ok(matched)
+    }
+
+    /// Reloads changed feeds and screens all links every `THREAT_FEED_SCAN_SECS`
+    /// (default 900), starting right away.
+    pub fn spawn_screener(self: Arc<Self>, db: Database) {
+        let secs = std::env::var("THREAT_FEED_SCAN_SECS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .filter(|&secs| secs > 0)
+            .unwrap_or(900);
+
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(Duration::from_secs(secs));
+            loop {
+                interval.tick().await;
+                let feeds = self.clone();
+                let _ = tokio::task::spawn_blocking(move || feeds.reload_if_changed()).await;
+                if let Err(e) = self.screen_links(&db).await {
+                    tracing::error!("Threat feed screening failed: {}", e);
+                }
+            }
+        });
+    }
+
+    fn list_files(&self) -> std::io::Result<Vec<(PathBuf, SystemTime)>> {
+        let mut files = Vec::new();
+        for entry in std::fs::read_dir(&self.dir)? {
+            let entry = entry?;
+            let metadata = entry.metadata()?;
+            let hidden = entry.file_name().to_string_lossy().starts_with('.');
+            if metadata.is_file() && !hidden {
+                files.push((entry.path(), metadata.modified()?));
+            }
+        }
+        files.sort();
+        Ok(files)
+    }
+}
+
+// Host (with any non-default port), path and query, ignoring scheme and fragment
+fn url_key(url: &str) -> Option<String> {
+    let url = url::Url::parse(url).ok()?;
+    let mut key = url.host_str()?.to_ascii_lowercase();
+    if let Some(port) = url.port() {
+        key.push_str(&format!(":{}", port));
+    }
+    key.push_str(url.path());
+    if let Some(query) = url.query() {
+        key.push('?');
+        key.push_str(query);
+    }
+    Some(key)
+}
+
+// The host and each domain it belongs to, e.g. a.b.example.com, b.example.com,
+// example.com and com
+fn parent_domains(host: &str) -> impl Iterator<Item = &str> {
+    std::iter::successors(Some(host), |h| h.split_once('.').map(|(_, rest)| rest))
+}
+
+// Simplified Safe Browsing expressions: up to five host suffixes combined with
+// the full path and query, the path alone and up to four leading directories
+fn hash_expressions(url: &str) -> Vec<String> {
+    let Ok(url) = url::Url::parse(url) else {
+        return Vec::new();
+    };
+    let Some(host) = url.host_str().map(str::to_ascii_lowercase) else {
+        return Vec::new();
+    };
+
+    let mut hosts = vec![host.clone()];
+    if url.domain().is_some() {
+        let labels: Vec<&str> = host.split('.').collect();
+        let first_suffix = labels.len().saturating_sub(5).max(1);
+        for start in first_suffix..labels.len().saturating_sub(1) {
+            hosts.push(labels[start..].join("."));
+        }
+    }
+
+    let path = url.path();
+    let mut paths = Vec::new();
+    if let Some(query) = url.query() {
+        paths.push(format!("{}?{}", path, query));
+    }
+    paths.push(path.to_string());
+    let mut prefix = String::from("/");
+    for segment in path.split('/').filter(|s| !s.is_empty()).take(4) {
+        if !paths.contains(&prefix) {
+            paths.push(prefix.clone());
+        }
+        prefix.push_str(segment);
+        prefix.push('/');
+    }
+    if !paths.contains(&prefix) && prefix.len() < path.len() {
+        paths.push(prefix);
+    }
+
+    hosts
+        .iter()
+        .flat_map(|host| paths.iter().map(move |path| format!("{}{}", host, path)))
+        .collect()
+}
+
+fn is_domain(host: &str) -> bool {
+    host.contains('.')
+        && host.split('.').all(|label| {
+            !label.is_empty()
+                && label
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        })
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        db::testing::TempDb,
+        models::{NewUrl, RotationEntry},
+    };
+    use rand::Rng;
+
+    // A feed directory in the temp dir, removed when dropped
+    struct FeedDir(PathBuf);
+
+    impl FeedDir {
+        fn new(files: &[(&str, &str)]) -> Self {
+            let dir = std::env::temp_dir().join(format!(
+                "meoshorturl-feeds-{:016x}",
+                rand::thread_rng().gen::<u64>()
+            ));
+            std::fs::create_dir(&dir).unwrap();
+            for (name, content) in files {
+                std::fs::write(dir.join(name), content).unwrap();
+            }
+            Self(dir)
+        }
+
+        fn feeds(&self, action: ThreatAction) -> ThreatFeeds {
+            ThreatFeeds::new(self.0.clone(), action)
+        }
+    }
+
+    impl Drop for FeedDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn sha256_hex(expression: &str) -> String {
+        Sha256::digest(expression.as_bytes())
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect()
+    }
+
+    #[test]
+    fn canonical_url_keys() {
+        assert_eq!(
+            url_key("HTTPS://Evil.Example.com/Path?q=1#top").as_deref(),
+            Some("evil.example.com/Path?q=1")
+        );
+        assert_eq!(
+            url_key("http://evil.example.com:80/").as_deref(),
+            Some("evil.example.com/")
+        );
+        assert_eq!(
+            url_key("http://evil.example.com:8080/a").as_deref(),
+            Some("evil.example.com:8080/a")
+        );
+        assert_eq!(url_key("mailto:someone@example.com"), None);
+    }
+
+    #[test]
+    fn reads_url_and_domain_lists() {
+        let dir = FeedDir::new(&[
+            (
+                "urlhaus.csv",
+                "# id,dateadded,url,url_status\n\
+                 \"1\",\"2026-10-01\",\"http://bad.example.net/payload.exe\",\"online\"\n",
+            ),
+            (
+                "hosts.txt",
+                "# comment\n0.0.0.0 tracker.example.org\nphish.example.com.\nlocalhost\nnot-a-domain\n",
+            ),
+        ]);
+        let feeds = dir.feeds(ThreatAction::Disable);
+        let summaries = feeds.summaries();
+        assert_eq!(summaries.iter().map(|f| f.urls).sum::<usize>(), 1);
+        assert_eq!(summaries.iter().map(|f| f.hosts).sum::<usize>(), 2);
+
+        // The scheme doesn't matter, but the path does
+        let found = feeds.check("https://BAD.example.net/payload.exe").unwrap();
+        assert_eq!(found.feed, "urlhaus");
+        assert!(feeds.check("http://bad.example.net/other").is_none());
+
+        // Listed domains cover their subdomains, but not their parents
+        assert_eq!(
+            feeds
+                .check("https://a.tracker.example.org/x")
+                .unwrap()
+                .entry,
+            "tracker.example.org"
+        );
+        assert!(feeds.check("https://phish.example.com/").is_some());
+        assert!(feeds.check("https://example.com/").is_none());
+        assert!(feeds.check("http://localhost/").is_none());
+    }
+
+    #[test]
+    fn matches_full_hashes_only() {
+        let full = sha256_hex("evil.example.com/login/");
+        let prefix = &sha256_hex("example.org/")[..8];
+        let dir = FeedDir::new(&[("gsb.sha256", &format!("{}\n{}\n", full, prefix))]);
+        let feeds = dir.feeds(ThreatAction::Disable);
+        assert_eq!(feeds.summaries()[0].hashes, 1);
+
+        // Any path under the listed directory, on the host or a subdomain
+        assert!(feeds.check("https://evil.example.com/login/").is_some());
+        assert!(feeds
+            .check("https://www.evil.example.com/login/form?a=1")
+            .is_some());
+        assert!(feeds.check("https://evil.example.com/").is_none());
+        // The skipped prefix matches nothing
+        assert!(feeds.check("https://example.org/").is_none());
+
+        // A broken feed keeps the others from loading, rather than half a set
+        let broken = FeedDir::new(&[
+            ("bad.hashes", "not hex\n"),
+            ("good.txt", "evil.example.com\n"),
+        ]);
+        let feeds = broken.feeds(ThreatAction::Disable);
+        assert!(feeds.summaries().is_empty());
+    }
+
+    #[test]
+    fn hash_expressions_follow_safe_browsing() {
+        let expressions = hash_expressions("http://a.b.c.d.e.f.g/1/2.html?param=1");
+        for expected in [
+            "a.b.c.d.e.f.g/1/2.html?param=1",
+            "a.b.c.d.e.f.g/1/2.html",
+            "a.b.c.d.e.f.g/",
+            "a.b.c.d.e.f.g/1/",
+            "c.d.e.f.g/1/2.html?param=1",
+            "f.g/",
+        ] {
+            assert!(expressions.contains(&expected.to_string()), "{}", expected);
+        }
+        assert!(!expressions.iter().any(|e| e.starts_with("g/")));
+        assert!(!expressions.iter().any(|e| e.starts_with("b.c.d.e.f.g/")));
+    }
+
+    async fn link(db: &TempDb, slug: &str, url: &str) -> i64 {
+        db.insert_url(&NewUrl {
+            slug: slug.to_string(),
+            original_url: url.to_string(),
+            ..Default::default()
+        })
+        .await
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn screening_disables_or_flags_listed_links() {
+        let dir = FeedDir::new(&[("bad.txt", "evil.example.com\n")]);
+        let db = TempDb::new().await;
+        link(&db, "good", "https://example.com/").await;
+        link(&db, "bad", "https://evil.example.com/").await;
+        // Only a rotation destination is listed
+        let rotated = link(&db, "rotated", "https://example.com/").await;
+        let destinations = [
+            RotationEntry {
+                url: "https://example.com/a".to_string(),
+                weight: 1,
+            },
+            RotationEntry {
+                url: "https://evil.example.com/b".to_string(),
+                weight: 1,
+            },
+        ];
+        db.set_rotation(rotated, &destinations, false)
+            .await
+            .unwrap();
+
+        let feeds = dir.feeds(ThreatAction::Flag);
+        assert_eq!(feeds.screen_links(&db).await.unwrap(), 2);
+        let bad = db.get_by_slug("bad").await.unwrap().unwrap();
+        assert!(bad.untrusted && !bad.disabled);
+        assert!(bad.untrusted_reason.unwrap().contains("evil.example.com"));
+        assert!(!db.get_by_slug("good").await.unwrap().unwrap().untrusted);
+        // Flagged links aren't flagged again
+        assert_eq!(feeds.screen_links(&db).await.unwrap(), 0);
+
+        let feeds = dir.feeds(ThreatAction::Disable);
+        assert_eq!(feeds.screen_links(&db).await.unwrap(), 2);
+        let rotated = db.get_by_slug("rotated").await.unwrap().unwrap();
+        assert!(rotated.disabled);
+        assert!(!db.get_by_slug("good").await.unwrap().unwrap().disabled);
+    }
+}
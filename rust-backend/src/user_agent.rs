@@ -0,0 +1,233 @@
+// Just enough user agent parsing to route visitors; anything unrecognised is `Other`
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Platform {
+    Ios,
+    Android,
+    Windows,
+    Macos,
+    Linux,
+    Other,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Browser {
+    Chrome,
+    Firefox,
+    Safari,
+    Edge,
+    Opera,
+    Samsung,
+    Other,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Client {
+    pub platform: Platform,
+    pub browser: Browser,
+    pub mobile: bool,
+}
+
+impl Client {
+    pub fn parse(user_agent: &str) -> Self {
+        let has = |needle: &str| user_agent.contains(needle);
+
+        // Order matters: iOS and Android agents also mention macOS and Linux
+        let platform = if has("iPhone") || has("iPad") || has("iPod") {
+            Platform::Ios
+        } else if has("Android") {
+            Platform::Android
+        } else if has("Windows") {
+            Platform::Windows
+        } else if has("Macintosh") || has("Mac OS X") {
+            Platform::Macos
+        } else if has("Linux") || has("CrOS") || has("X11") {
+            Platform::Linux
+        } else {
+            Platform::Other
+        };
+
+        // Most browsers also claim to be Chrome and Safari, so check the specific ones first
+        let browser = if has("SamsungBrowser") {
+            Browser::Samsung
+        } else if has("Edg/") || has("EdgA/") || has("EdgiOS/") {
+            Browser::Edge
+        } else if has("OPR/") || has("Opera") {
+            Browser::Opera
+        } else if has("Firefox/") || has("FxiOS/") {
+            Browser::Firefox
+        } else if has("Chrome/") || has("CriOS/") || has("Chromium/") {
+            Browser::Chrome
+        } else if has("Safari/") {
+            Browser::Safari
+        } else {
+            Browser::Other
+        };
+
+        let mobile = matches!(platform, Platform::Ios | Platform::Android) || has("Mobile");
+
+        Self {
+            platform,
+            browser,
+            mobile,
+        }
+    }
+
+    /// Whether the client matches a rule's platform: an OS name, `mobile` or `desktop`.
+    pub fn matches_platform(&self, platform: &str) -> bool {
+        match platform {
+            "ios" => self.platform == Platform::Ios,
+            "android" => self.platform == Platform::Android,
+            "windows" => self.platform == Platform::Windows,
+            "macos" => self.platform == Platform::Macos,
+            "linux" => self.platform == Platform::Linux,
+            "mobile" => self.mobile,
+            "desktop" => {
+                !self.mobile
+                    && matches!(
+                        self.platform,
+                        Platform::Windows | Platform::Macos | Platform::Linux
+                    )
+            }
+            _ => false,
+        }
+    }
+
+    pub fn matches_browser(&self, browser: &str) -> bool {
+        match browser {
+            "chrome" => self.browser == Browser::Chrome,
+            "firefox" => self.browser == Browser::Firefox,
+            "safari" => self.browser == Browser::Safari,
+            "edge" => self.browser == Browser::Edge,
+            "opera" => self.browser == Browser::Opera,
+            "samsung" => self.browser == Browser::Samsung,
+            _ => false,
+        }
+    }
+}
+
+pub const PLATFORMS: [&str; 7] = [
+    "ios", "android", "windows", "macos", "linux", "mobile", "desktop",
+];
+pub const BROWSERS: [&str; 6] = ["chrome", "firefox", "safari", "edge", "opera", "samsung"];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn classifies_real_user_agents() {
+        let cases = [
+            (
+                "Mozilla/5.0 (iPhone; CPU iPhone OS 17_4 like Mac OS X) AppleWebKit/605.1.15 (KHTML, like Gecko) Version/17.4 Mobile/15E148 Safari/604.1",
+                Platform::Ios,
+                Browser::Safari,
+                true,
+            ),
+            (
+                "Mozilla/5.0 (iPad; CPU OS 16_6 like Mac OS X) AppleWebKit/605.1.15 (KHTML, like Gecko) CriOS/120.0.6099.119 Mobile/15E148 Safari/604.1",
+                Platform::Ios,
+                Browser::Chrome,
+                true,
+            ),
+            (
+                "Mozilla/5.0 (Linux; Android 14; Pixel 8) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/124.0.0.0 Mobile Safari/537.36",
+                Platform::Android,
+                Browser::Chrome,
+                true,
+            ),
+            (
+                "Mozilla/5.0 (Linux; Android 13; SM-S918B) AppleWebKit/537.36 (KHTML, like Gecko) SamsungBrowser/24.0 Chrome/117.0.0.0 Mobile Safari/537.36",
+                Platform::Android,
+                Browser::Samsung,
+                true,
+            ),
+            (
+                "Mozilla/5.0 (Android 14; Mobile; rv:125.0) Gecko/125.0 Firefox/125.0",
+                Platform::Android,
+                Browser::Firefox,
+                true,
+            ),
+            (
+                "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/124.0.0.0 Safari/537.36 Edg/124.0.2478.51",
+                Platform::Windows,
+                Browser::Edge,
+                false,
+            ),
+            (
+                "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/124.0.0.0 Safari/537.36 OPR/109.0.0.0",
+                Platform::Windows,
+                Browser::Opera,
+                false,
+            ),
+            (
+                "Mozilla/5.0 (Macintosh; Intel Mac OS X 10_15_7) AppleWebKit/605.1.15 (KHTML, like Gecko) Version/17.4 Safari/605.1.15",
+                Platform::Macos,
+                Browser::Safari,
+                false,
+            ),
+            (
+                "Mozilla/5.0 (X11; Ubuntu; Linux x86_64; rv:125.0) Gecko/20100101 Firefox/125.0",
+                Platform::Linux,
+                Browser::Firefox,
+                false,
+            ),
+            (
+                "Mozilla/5.0 (X11; CrOS x86_64 14541.0.0) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/124.0.0.0 Safari/537.36",
+                Platform::Linux,
+                Browser::Chrome,
+                false,
+            ),
+            (
+                "Mozilla/5.0 (compatible; Googlebot/2.1; +http://www.google.com/bot.html)",
+                Platform::Other,
+                Browser::Other,
+                false,
+            ),
+            (
+                "Mozilla/5.0 (compatible; bingbot/2.0; +http://www.bing.com/bingbot.htm)",
+                Platform::Other,
+                Browser::Other,
+                false,
+            ),
+            ("curl/8.5.0", Platform::Other, Browser::Other, false),
+            ("", Platform::Other, Browser::Other, false),
+        ];
+        for (user_agent, platform, browser, mobile) in cases {
+            assert_eq!(
+                Client::parse(user_agent),
+                Client {
+                    platform,
+                    browser,
+                    mobile
+                },
+                "{}",
+                user_agent
+            );
+        }
+    }
+
+    #[test]
+    fn matches_rule_names() {
+        let iphone = Client::parse(
+            "Mozilla/5.0 (iPhone; CPU iPhone OS 17_4 like Mac OS X) Mobile/15E148 Safari/604.1",
+        );
+        assert!(iphone.matches_platform("ios") && iphone.matches_platform("mobile"));
+        assert!(!iphone.matches_platform("desktop") && !iphone.matches_platform("macos"));
+        assert!(iphone.matches_browser("safari") && !iphone.matches_browser("chrome"));
+
+        let windows = Client::parse(
+            "Mozilla/5.0 (Windows NT 10.0; Win64; x64; rv:125.0) Gecko/20100101 Firefox/125.0",
+        );
+        assert!(windows.matches_platform("desktop") && !windows.matches_platform("mobile"));
+        assert!(windows.matches_browser("firefox"));
+
+        // Bots are neither desktop nor mobile, so only a catch-all reaches them
+        let bot = Client::parse(
+            "Mozilla/5.0 (compatible; Googlebot/2.1; +http://www.google.com/bot.html)",
+        );
+        assert!(PLATFORMS.iter().all(|p| !bot.matches_platform(p)));
+        assert!(BROWSERS.iter().all(|b| !bot.matches_browser(b)));
+        assert!(!bot.matches_platform("unknown"));
+    }
+}